@@ -80,7 +80,7 @@ dfx deploy icrc1_ledger_canister --argument '(
 cd ../..
 
 echo "🚀 7. Deploy remaining canisters locally..."
-dfx deploy dao_backend
+dfx deploy dao_backend --argument '(null)'
 dfx deploy ecoproof-icp-backend
 
 echo "✅ All canisters deployed locally. Done!"
@@ -77,7 +77,7 @@ if [ "$DEPLOY_NETWORK" = "local" ]; then
   dfx deploy ecoproof-icp-backend
 
   echo "🚀 Deploying dao_backend locally..."
-  dfx deploy dao_backend
+  dfx deploy dao_backend --argument '(null)'
 fi
 
 # 🌐 Playground: only deploy dao_backend
@@ -86,7 +86,7 @@ if [ "$DEPLOY_NETWORK" = "playground" ]; then
   dfx build dao_backend
 
   echo "🚀 Deploying dao_backend to Playground..."
-  dfx deploy dao_backend --playground
+  dfx deploy dao_backend --playground --argument '(null)'
 
   echo "🌍 Playground URL:"
   echo "https://$(dfx canister id dao_backend --playground).icp0.io"
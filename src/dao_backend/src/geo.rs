@@ -0,0 +1,414 @@
+//! Geohash cells for the spatial indexes.
+//!
+//! Points are indexed under their cell at `MAX_PRECISION`; areas are indexed
+//! under a handful of coarser cells covering their bounding box. Two cells
+//! overlap exactly when one is a prefix of the other.
+//!
+//! Also holds the polygon boundaries of challenges.
+
+use std::borrow::Cow;
+
+use candid::CandidType;
+use ic_stable_structures::storable::{Bound, Storable};
+use serde::{Deserialize, Serialize};
+
+const BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Precision of point cells, roughly 1.2 km by 0.6 km.
+pub const MAX_PRECISION: usize = 6;
+
+/// Upper bound on the number of cells an area is covered with.
+pub const MAX_COVER_CELLS: usize = 32;
+
+const METERS_PER_DEGREE_LAT: f64 = 111_320.0;
+
+/// Geohash of a point.
+pub fn encode(lat: f64, lon: f64, precision: usize) -> String {
+    let (mut lat_lo, mut lat_hi) = (-90.0, 90.0);
+    let (mut lon_lo, mut lon_hi) = (-180.0, 180.0);
+    let mut hash = String::with_capacity(precision);
+    let mut even_bit = true;
+    let (mut bits, mut ch) = (0, 0usize);
+
+    while hash.len() < precision {
+        let (lo, hi, value) = if even_bit {
+            (&mut lon_lo, &mut lon_hi, lon)
+        } else {
+            (&mut lat_lo, &mut lat_hi, lat)
+        };
+        let mid = (*lo + *hi) / 2.0;
+        ch <<= 1;
+        if value >= mid {
+            ch |= 1;
+            *lo = mid;
+        } else {
+            *hi = mid;
+        }
+        even_bit = !even_bit;
+        bits += 1;
+        if bits == 5 {
+            hash.push(BASE32[ch] as char);
+            bits = 0;
+            ch = 0;
+        }
+    }
+    hash
+}
+
+/// Latitude and longitude span, in degrees, of a cell of `precision` characters.
+fn cell_span(precision: usize) -> (f64, f64) {
+    let bits = 5 * precision as i32;
+    let lon_bits = (bits + 1) / 2;
+    let lat_bits = bits / 2;
+    (180.0 / 2f64.powi(lat_bits), 360.0 / 2f64.powi(lon_bits))
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BoundingBox {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+impl BoundingBox {
+    /// Boxes covering `[min_lon, max_lon]`; a box with `min_lon > max_lon`
+    /// crosses the antimeridian and is split in two.
+    pub fn split(min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> Vec<BoundingBox> {
+        let (min_lat, max_lat) = (min_lat.max(-90.0), max_lat.min(90.0));
+        if min_lon <= max_lon {
+            vec![BoundingBox { min_lat, min_lon, max_lat, max_lon }]
+        } else {
+            vec![
+                BoundingBox { min_lat, min_lon, max_lat, max_lon: 180.0 },
+                BoundingBox { min_lat, min_lon: -180.0, max_lat, max_lon },
+            ]
+        }
+    }
+
+    /// Boxes enclosing the circle of `radius_m` around a point.
+    pub fn around(lat: f64, lon: f64, radius_m: f64) -> Vec<BoundingBox> {
+        let radius_m = if radius_m.is_nan() { 0.0 } else { radius_m.max(0.0) };
+        let d_lat = radius_m / METERS_PER_DEGREE_LAT;
+        let (min_lat, max_lat) = (lat - d_lat, lat + d_lat);
+        let widest_cos = min_lat.abs().max(max_lat.abs()).min(90.0).to_radians().cos();
+        if min_lat <= -90.0 || max_lat >= 90.0 || widest_cos <= f64::EPSILON {
+            return Self::split(min_lat, -180.0, max_lat, 180.0);
+        }
+        let d_lon = d_lat / widest_cos;
+        if d_lon >= 180.0 {
+            return Self::split(min_lat, -180.0, max_lat, 180.0);
+        }
+        let wrap = |l: f64| if l < -180.0 { l + 360.0 } else if l > 180.0 { l - 360.0 } else { l };
+        Self::split(min_lat, wrap(lon - d_lon), max_lat, wrap(lon + d_lon))
+    }
+
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        self.min_lat <= lat && lat <= self.max_lat && self.min_lon <= lon && lon <= self.max_lon
+    }
+
+    /// Index range of the grid cells of `span` degrees between `min` and `max`.
+    fn cell_range(min: f64, max: f64, origin: f64, span: f64, count: usize) -> (usize, usize) {
+        let index = |v: f64| (((v - origin) / span).floor().max(0.0) as usize).min(count - 1);
+        (index(min), index(max))
+    }
+
+    fn cells(&self, precision: usize) -> (usize, usize, usize, usize) {
+        let (lat_span, lon_span) = cell_span(precision);
+        let lat_count = (180.0 / lat_span).round() as usize;
+        let lon_count = (360.0 / lon_span).round() as usize;
+        let (lat_from, lat_to) = Self::cell_range(self.min_lat, self.max_lat, -90.0, lat_span, lat_count);
+        let (lon_from, lon_to) = Self::cell_range(self.min_lon, self.max_lon, -180.0, lon_span, lon_count);
+        (lat_from, lat_to, lon_from, lon_to)
+    }
+
+    fn cell_count(&self, precision: usize) -> usize {
+        let (lat_from, lat_to, lon_from, lon_to) = self.cells(precision);
+        (lat_to + 1).saturating_sub(lat_from) * (lon_to + 1).saturating_sub(lon_from)
+    }
+}
+
+/// The cells of the finest precision that covers `boxes` with at most
+/// `MAX_COVER_CELLS` cells.
+pub fn cover(boxes: &[BoundingBox]) -> Vec<String> {
+    let precision = (1..=MAX_PRECISION)
+        .rev()
+        .find(|&p| boxes.iter().map(|b| b.cell_count(p)).sum::<usize>() <= MAX_COVER_CELLS)
+        .unwrap_or(1);
+
+    let (lat_span, lon_span) = cell_span(precision);
+    let mut cells = Vec::new();
+    for bbox in boxes {
+        let (lat_from, lat_to, lon_from, lon_to) = bbox.cells(precision);
+        for lat_index in lat_from..=lat_to {
+            for lon_index in lon_from..=lon_to {
+                let lat = -90.0 + (lat_index as f64 + 0.5) * lat_span;
+                let lon = -180.0 + (lon_index as f64 + 0.5) * lon_span;
+                cells.push(encode(lat, lon, precision));
+            }
+        }
+    }
+    cells.sort();
+    cells.dedup();
+    cells
+}
+
+// -------- Polygon boundaries --------
+
+/// `[longitude, latitude]`, in GeoJSON order.
+pub type Position = [f64; 2];
+
+/// A GeoJSON `MultiPolygon`: polygons of rings, each polygon's first ring is
+/// its outer boundary and the others are holes. A `Polygon` is stored as a
+/// single-element multipolygon.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Boundary {
+    pub polygons: Vec<Vec<Vec<Position>>>,
+}
+
+impl Storable for Boundary {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("Boundary serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("Boundary deserialization failed")
+    }
+}
+
+impl Boundary {
+    /// Parses a GeoJSON `Polygon` or `MultiPolygon` geometry, or a `Feature`
+    /// holding one, with at most `max_vertices` positions in total.
+    pub fn from_geojson(geojson: &str, max_vertices: u32) -> Result<Boundary, String> {
+        let value: serde_json::Value = serde_json::from_str(geojson).map_err(|e| format!("Invalid JSON: {}", e))?;
+        let geometry = match value.get("type").and_then(|t| t.as_str()) {
+            Some("Feature") => value.get("geometry").ok_or("Feature has no geometry.")?,
+            _ => &value,
+        };
+        let coordinates = geometry.get("coordinates").cloned().ok_or("Geometry has no coordinates.")?;
+        let polygons: Vec<Vec<Vec<Position>>> = match geometry.get("type").and_then(|t| t.as_str()) {
+            Some("Polygon") => vec![serde_json::from_value(coordinates).map_err(|e| format!("Invalid Polygon: {}", e))?],
+            Some("MultiPolygon") => serde_json::from_value(coordinates).map_err(|e| format!("Invalid MultiPolygon: {}", e))?,
+            Some(other) => return Err(format!("Unsupported geometry type {}.", other)),
+            None => return Err("Geometry has no type.".to_string()),
+        };
+
+        let boundary = Boundary { polygons };
+        boundary.check(max_vertices)?;
+        Ok(boundary)
+    }
+
+    fn check(&self, max_vertices: u32) -> Result<(), String> {
+        if self.polygons.is_empty() {
+            return Err("Boundary has no polygons.".to_string());
+        }
+        let vertices: usize = self.polygons.iter().flatten().map(|ring| ring.len()).sum();
+        if vertices > max_vertices as usize {
+            return Err(format!("Boundary has {} positions, at most {} are allowed.", vertices, max_vertices));
+        }
+        for ring in self.polygons.iter().flatten() {
+            if ring.len() < 4 {
+                return Err("Rings need at least four positions.".to_string());
+            }
+            if ring.first() != ring.last() {
+                return Err("Rings must be closed.".to_string());
+            }
+            let valid = |&[lon, lat]: &Position| (-180.0..=180.0).contains(&lon) && (-90.0..=90.0).contains(&lat);
+            if !ring.iter().all(valid) {
+                return Err("Positions must be [longitude, latitude] within range.".to_string());
+            }
+        }
+        if self.polygons.iter().any(|polygon| polygon.is_empty()) {
+            return Err("Polygons need an outer ring.".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether the point lies inside an outer ring and outside its holes.
+    /// Rings are treated as planar in longitude and latitude.
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        self.polygons.iter().any(|polygon| {
+            let mut rings = polygon.iter();
+            rings.next().is_some_and(|outer| ring_contains(outer, lat, lon))
+                && !rings.any(|hole| ring_contains(hole, lat, lon))
+        })
+    }
+
+    pub fn bounding_box(&self) -> BoundingBox {
+        let mut bbox = BoundingBox { min_lat: 90.0, min_lon: 180.0, max_lat: -90.0, max_lon: -180.0 };
+        for &[lon, lat] in self.polygons.iter().flatten().flatten() {
+            bbox.min_lat = bbox.min_lat.min(lat);
+            bbox.max_lat = bbox.max_lat.max(lat);
+            bbox.min_lon = bbox.min_lon.min(lon);
+            bbox.max_lon = bbox.max_lon.max(lon);
+        }
+        bbox
+    }
+}
+
+/// Ray casting; points on an edge may fall either way.
+fn ring_contains(ring: &[Position], lat: f64, lon: f64) -> bool {
+    let mut inside = false;
+    for edge in ring.windows(2) {
+        let ([lon1, lat1], [lon2, lat2]) = (edge[0], edge[1]);
+        if (lat1 > lat) != (lat2 > lat) && lon < lon1 + (lat - lat1) * (lon2 - lon1) / (lat2 - lat1) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn covered(cells: &[String], lat: f64, lon: f64) -> bool {
+        let point = encode(lat, lon, MAX_PRECISION);
+        cells.iter().any(|cell| point.starts_with(cell.as_str()))
+    }
+
+    #[test]
+    fn encodes_known_vectors() {
+        assert_eq!(encode(57.64911, 10.40744, 11), "u4pruydqqvj");
+        assert_eq!(encode(42.6, -5.6, 5), "ezs42");
+        assert_eq!(encode(-25.382708, -49.265506, 6), "6gkzwg");
+        assert_eq!(encode(0.0, 0.0, 1), "s");
+        assert_eq!(encode(-90.0, -180.0, 3), "000");
+    }
+
+    #[test]
+    fn coarser_cells_are_prefixes() {
+        let fine = encode(52.52, 13.405, MAX_PRECISION);
+        for precision in 1..MAX_PRECISION {
+            assert!(fine.starts_with(&encode(52.52, 13.405, precision)));
+        }
+    }
+
+    #[test]
+    fn cover_contains_the_box() {
+        let boxes = BoundingBox::around(52.52, 13.405, 5_000.0);
+        let cells = cover(&boxes);
+        assert!(!cells.is_empty() && cells.len() <= MAX_COVER_CELLS);
+        assert!(covered(&cells, 52.52, 13.405));
+        assert!(covered(&cells, 52.56, 13.46));
+        assert!(covered(&cells, 52.48, 13.35));
+        assert!(!covered(&cells, 48.85, 2.35));
+    }
+
+    #[test]
+    fn around_splits_at_the_antimeridian() {
+        let boxes = BoundingBox::around(0.0, 179.999, 1_000.0);
+        assert_eq!(boxes.len(), 2);
+        assert_eq!(boxes[0].max_lon, 180.0);
+        assert_eq!(boxes[1].min_lon, -180.0);
+        assert!(boxes.iter().any(|b| b.contains(0.0, 179.9995)));
+        assert!(boxes.iter().any(|b| b.contains(0.0, -179.995)));
+        assert!(!boxes.iter().any(|b| b.contains(0.0, 0.0)));
+
+        let cells = cover(&boxes);
+        assert!(covered(&cells, 0.0, 179.9995));
+        assert!(covered(&cells, 0.0, -179.995));
+    }
+
+    #[test]
+    fn around_spans_all_longitudes_at_the_poles() {
+        for lat in [89.99, -89.99] {
+            let boxes = BoundingBox::around(lat, 10.0, 5_000.0);
+            assert_eq!(boxes.len(), 1);
+            assert_eq!((boxes[0].min_lon, boxes[0].max_lon), (-180.0, 180.0));
+            assert!(boxes[0].min_lat >= -90.0 && boxes[0].max_lat <= 90.0);
+            assert!(boxes[0].contains(lat, -170.0));
+        }
+    }
+
+    #[test]
+    fn around_treats_bad_radius_as_zero() {
+        for radius in [f64::NAN, -5.0] {
+            let boxes = BoundingBox::around(10.0, 20.0, radius);
+            assert_eq!(boxes.len(), 1);
+            assert!(boxes[0].contains(10.0, 20.0));
+            assert!(!boxes[0].contains(10.001, 20.0));
+        }
+    }
+
+    const SQUARE_WITH_HOLE: &str = r#"{
+        "type": "Polygon",
+        "coordinates": [
+            [[0, 0], [10, 0], [10, 10], [0, 10], [0, 0]],
+            [[4, 4], [6, 4], [6, 6], [4, 6], [4, 4]]
+        ]
+    }"#;
+
+    #[test]
+    fn ring_contains_uses_lon_lat_order() {
+        let triangle = [[0.0, 0.0], [10.0, 0.0], [0.0, 5.0], [0.0, 0.0]];
+        assert!(ring_contains(&triangle, 1.0, 2.0));
+        assert!(!ring_contains(&triangle, 2.0, 9.0));
+        assert!(!ring_contains(&triangle, -1.0, 2.0));
+    }
+
+    #[test]
+    fn polygon_with_hole() {
+        let boundary = Boundary::from_geojson(SQUARE_WITH_HOLE, 100).unwrap();
+        assert_eq!(boundary.polygons.len(), 1);
+        assert!(boundary.contains(2.0, 2.0));
+        assert!(boundary.contains(8.0, 5.0));
+        assert!(!boundary.contains(5.0, 5.0));
+        assert!(!boundary.contains(5.0, 11.0));
+
+        let bbox = boundary.bounding_box();
+        assert_eq!((bbox.min_lat, bbox.min_lon, bbox.max_lat, bbox.max_lon), (0.0, 0.0, 10.0, 10.0));
+    }
+
+    #[test]
+    fn multipolygon_in_a_feature() {
+        let geojson = r#"{
+            "type": "Feature",
+            "properties": {},
+            "geometry": {
+                "type": "MultiPolygon",
+                "coordinates": [
+                    [[[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]],
+                    [[[20, 40], [21, 40], [21, 41], [20, 41], [20, 40]]]
+                ]
+            }
+        }"#;
+        let boundary = Boundary::from_geojson(geojson, 100).unwrap();
+        assert_eq!(boundary.polygons.len(), 2);
+        assert!(boundary.contains(0.5, 0.5));
+        assert!(boundary.contains(40.5, 20.5));
+        assert!(!boundary.contains(20.5, 40.5));
+    }
+
+    #[test]
+    fn rejects_malformed_geojson() {
+        let err = |geojson: &str| Boundary::from_geojson(geojson, 100).unwrap_err();
+        assert!(err("{ not json").starts_with("Invalid JSON"));
+        assert_eq!(err(r#"{"type": "Polygon"}"#), "Geometry has no coordinates.");
+        assert_eq!(err(r#"{"coordinates": []}"#), "Geometry has no type.");
+        assert_eq!(err(r#"{"type": "Point", "coordinates": [1, 2]}"#), "Unsupported geometry type Point.");
+        assert_eq!(err(r#"{"type": "Feature"}"#), "Feature has no geometry.");
+        assert!(err(r#"{"type": "Polygon", "coordinates": [[1, 2]]}"#).starts_with("Invalid Polygon"));
+        assert_eq!(err(r#"{"type": "MultiPolygon", "coordinates": []}"#), "Boundary has no polygons.");
+        assert_eq!(err(r#"{"type": "MultiPolygon", "coordinates": [[]]}"#), "Polygons need an outer ring.");
+    }
+
+    #[test]
+    fn rejects_invalid_rings() {
+        let err = |coordinates: &str| {
+            Boundary::from_geojson(&format!(r#"{{"type": "Polygon", "coordinates": {}}}"#, coordinates), 100).unwrap_err()
+        };
+        assert_eq!(err("[[[0, 0], [10, 0], [10, 10], [0, 10]]]"), "Rings must be closed.");
+        assert_eq!(err("[[[0, 0], [10, 0], [0, 0]]]"), "Rings need at least four positions.");
+        assert_eq!(
+            err("[[[0, 0], [190, 0], [10, 10], [0, 0]]]"),
+            "Positions must be [longitude, latitude] within range."
+        );
+        assert_eq!(
+            Boundary::from_geojson(SQUARE_WITH_HOLE, 9).unwrap_err(),
+            "Boundary has 10 positions, at most 9 are allowed."
+        );
+    }
+}
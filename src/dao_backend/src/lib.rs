@@ -1,24 +1,71 @@
+#[cfg(not(test))]
 use ic_cdk::api::time;
-use ic_cdk_macros::{update, query, init, pre_upgrade, post_upgrade};
+use ic_cdk_macros::{update, query, init, post_upgrade};
 use candid::{CandidType, Nat};
 use candid::Principal;
 use candid::candid_method;
 use ic_stable_structures::{
     StableBTreeMap,
+    StableCell,
     DefaultMemoryImpl,
     storable::{Storable, Bound},
     memory_manager::{MemoryManager, MemoryId, VirtualMemory},
 };
 use std::borrow::Cow;
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use ic_cdk::call; 
-use std::cell::RefCell;  
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+use ic_cdk_timers::TimerId;
+
+mod geo;
+mod schema;
+mod telegram;
+mod validation;
+
+use validation::{FieldError, ValidationIssue, ValidationLimits, Validator};
+
+/// Fixed clock for unit tests, which run outside a canister.
+#[cfg(test)]
+fn time() -> u64 {
+    1_700_000_000_000_000_000
+}
 
 // -------- Type Definitions --------
 
 type UserId = String;
-const MAX_CHALLENGE_BYTES: u32 = 512;
+const MAX_CHALLENGE_BYTES: u32 = 1024;
+const MAX_USER_ID_BYTES: u32 = 64;
+const MAX_CITY_KEY_BYTES: u32 = 128;
+const REPUTATION_INITIAL_SCORE: u32 = 100;
+const REPUTATION_MIN_SCORE: u32 = 10;
+const REPUTATION_MAX_SCORE: u32 = 1_000;
+const REPUTATION_CORRECT_GAIN: u32 = 5;
+const REPUTATION_INCORRECT_LOSS: u32 = 10;
+/// How long a Telegram `initData` payload is accepted after its `auth_date`.
+const INIT_DATA_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+const MAX_INTEGRITY_ISSUES: usize = 100;
+/// Skipped posts listed in a payout run report; the rest are only counted.
+const MAX_REPORTED_SKIPS: usize = 100;
+/// Entries of each discrepancy list in a reconciliation report.
+const MAX_REPORTED_DISCREPANCIES: usize = 100;
+const MAX_PAGE_SIZE: u32 = 100;
+/// Entries a filtered page visits at most, to stay within the query
+/// instruction limit.
+const MAX_PAGE_SCAN: usize = 2_000;
+const MAX_SPATIAL_RESULTS: usize = 500;
+const HOUR_NS: u64 = 3_600 * 1_000_000_000;
+const DAY_NS: u64 = 24 * HOUR_NS;
+/// Longest voting window a config may set.
+const MAX_VOTING_WINDOW_SECS: u64 = 30 * 24 * 60 * 60;
+/// Longest duplicate window; every submission inside it is kept per user.
+const MAX_DUPLICATE_WINDOW_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Nanoseconds in `secs`, saturating for values from arguments and settings.
+const fn secs_to_ns(secs: u64) -> u64 {
+    secs.saturating_mul(1_000_000_000)
+}
 
 // -------- Structs --------
 
@@ -41,6 +88,80 @@ struct UserSubmission {
     rewarded: bool,
     status: PostStatus,
     expiration_timestamp: u64,
+    /// Set when the post leaves `OPEN`; explains the outcome to clients.
+    #[serde(default)]
+    finalization: Option<FinalizationRecord>,
+    /// Earlier submission of the same user this one nearly duplicates, when
+    /// near-duplicates are accepted rather than rejected.
+    #[serde(default)]
+    duplicate_of: Option<u64>,
+    /// Challenge the post was submitted to. Membership is decided by this
+    /// link, not by the post's location; posts made before the link was
+    /// recorded were linked by the schema v4 migration.
+    #[serde(default)]
+    challenge_id: Option<u64>,
+    /// Reward credited to the user's balance when the post was accepted.
+    #[serde(default)]
+    reward_credit: u64,
+    /// Part of `reward_credit` already transferred through claims.
+    #[serde(default)]
+    claimed: u64,
+    /// Ledger block of the transfer that paid the post.
+    #[serde(default)]
+    payout_block_index: Option<u64>,
+}
+
+/// Vote counts of a post. The weighted sums weight each vote by the voter's
+/// reputation score and decide the approval threshold; the plain counts
+/// decide quorum and minimum approvals.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct VoteTally {
+    valid: u32,
+    invalid: u32,
+    #[serde(default)]
+    weighted_valid: u64,
+    #[serde(default)]
+    weighted_invalid: u64,
+}
+
+impl VoteTally {
+    fn of(votes: &[Vote]) -> Self {
+        let mut tally = VoteTally::default();
+        for vote in votes {
+            let weight = reputation_of(&vote.user).score as u64;
+            if vote.vote_value {
+                tally.valid += 1;
+                tally.weighted_valid += weight;
+            } else {
+                tally.invalid += 1;
+                tally.weighted_invalid += weight;
+            }
+        }
+        tally
+    }
+
+    fn total(&self) -> u32 {
+        self.valid + self.invalid
+    }
+}
+
+/// The tally of a finalized post and the rule it was judged by.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct FinalizationRecord {
+    tally: VoteTally,
+    quorum: u32,
+    min_approvals: u32,
+    approval_threshold_bps: u32,
+    finalized_at: u64,
+    /// The votes accepted the post but its challenge already had
+    /// `max_accepted` accepted posts, so it ended as `EXPIRED`.
+    #[serde(default)]
+    capacity_reached: bool,
+    /// Voters who sided with the outcome and were credited `voter_reward` each.
+    #[serde(default)]
+    rewarded_voters: u32,
+    #[serde(default)]
+    voter_reward: u64,
 }
 
 impl ic_stable_structures::Storable for UserSubmission {
@@ -64,6 +185,32 @@ impl ic_stable_structures::Storable for UserSubmission {
     }
 }
 
+/// Stored state of a challenge. An `Active` challenge past its end time is
+/// reported as `Closed` until the finalizer records it.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+enum ChallengeState {
+    /// Editable and not yet open for submissions.
+    Draft,
+    /// Accepts submissions between its start and end time.
+    Active,
+    /// Ended; its posts may still be voted on.
+    Closed,
+    /// Closed with every post finalized.
+    Settled,
+    Cancelled,
+}
+
+/// Optional parts of `WeatherData` a challenge can make mandatory. Plain
+/// submissions always require all of them.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum WeatherField {
+    City,
+    Weather,
+    Photo,
+}
+
+const ALL_WEATHER_FIELDS: [WeatherField; 3] = [WeatherField::City, WeatherField::Weather, WeatherField::Photo];
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 struct Challenge {
     id: u64,
@@ -71,8 +218,84 @@ struct Challenge {
     latitude: f64,
     longitude: f64,
     radius_m: f64,
+    /// End of the submission window.
     expiration: u64,
     picture_url: String,
+    /// The geofence is the polygon stored in `CHALLENGE_BOUNDARIES`; the
+    /// circle then just encloses it.
+    #[serde(default)]
+    has_boundary: bool,
+    /// Anonymous for challenges created before creators were recorded.
+    #[serde(default = "Principal::anonymous")]
+    creator: Principal,
+    /// Start of the submission window.
+    #[serde(default)]
+    start_time: u64,
+    /// Posts that can be accepted; later ones end as `EXPIRED`.
+    #[serde(default)]
+    max_accepted: Option<u32>,
+    #[serde(default = "all_weather_fields")]
+    required_fields: Vec<WeatherField>,
+    #[serde(default = "active_state")]
+    state: ChallengeState,
+}
+
+fn all_weather_fields() -> Vec<WeatherField> {
+    ALL_WEATHER_FIELDS.to_vec()
+}
+
+/// Challenges created before the lifecycle existed were live at once.
+fn active_state() -> ChallengeState {
+    ChallengeState::Active
+}
+
+/// Fields of a new challenge.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct ChallengeInput {
+    title: String,
+    latitude: f64,
+    longitude: f64,
+    radius_m: f64,
+    /// A GeoJSON `Polygon`, `MultiPolygon` or `Feature` holding one; replaces
+    /// the circle as the geofence.
+    boundary_geojson: Option<String>,
+    picture_url: String,
+    /// Defaults to the time of publication.
+    start_time: Option<u64>,
+    end_time: u64,
+    max_accepted: Option<u32>,
+    /// Defaults to every field.
+    required_fields: Option<Vec<WeatherField>>,
+    /// Create as `Active` instead of `Draft`.
+    publish: bool,
+}
+
+/// Changes to a challenge; `None` keeps the current value. The start time
+/// and required fields can only change while the challenge is a draft.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct ChallengeUpdate {
+    title: Option<String>,
+    picture_url: Option<String>,
+    start_time: Option<u64>,
+    end_time: Option<u64>,
+    max_accepted: Option<Option<u32>>,
+    required_fields: Option<Vec<WeatherField>>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct ChallengeStats {
+    challenge_id: u64,
+    state: ChallengeState,
+    submissions: u64,
+    participants: u64,
+    open: u64,
+    /// `PENDING` and `PAID` posts.
+    accepted: u64,
+    paid: u64,
+    expired: u64,
+    no_quorum: u64,
+    /// Accepted posts still possible, when the challenge is capped.
+    remaining_capacity: Option<u32>,
 }
 
 impl Storable for Challenge {
@@ -156,1162 +379,5171 @@ impl ic_stable_structures::Storable for User {
     }
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-struct VoteList(std::vec::Vec<Vote>);
+/// Key of the `VOTES` map. Ordered by submission first so all votes on a
+/// submission form one contiguous range.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct VoteKey {
+    data_id: u64,
+    user: UserId,
+}
 
-impl ic_stable_structures::Storable for VoteList {
-    const BOUND: Bound = Bound::Unbounded;
+impl Storable for VoteKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 8 + MAX_USER_ID_BYTES,
+        is_fixed_size: false,
+    };
 
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_cbor::to_vec(&self.0).unwrap())
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.data_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.user.as_bytes());
+        Cow::Owned(bytes)
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        match serde_cbor::from_slice::<Vec<Vote>>(&bytes) {
-            Ok(votes) => VoteList(votes),
-            Err(e) => {
-                ic_cdk::println!("ERROR: Failed to deserialize VoteList: {:?}", e);
-                VoteList(vec![])
-            }
+        let (data_id, user) = bytes.split_at(8);
+        VoteKey {
+            data_id: u64::from_be_bytes(data_id.try_into().expect("VoteKey data_id is 8 bytes")),
+            user: String::from_utf8(user.to_vec()).expect("VoteKey user is not valid UTF-8"),
         }
     }
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-struct UserSubmissionSummary {
+/// Key of the per-user maps `USER_VOTES`, `SUBMISSIONS_BY_USER` and
+/// `BALANCE_HISTORY`; in the latter `data_id` is the entry id.
+/// Ordered by user first so all entries of a user form one contiguous range.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct UserDataKey {
+    user: UserId,
     data_id: u64,
-    city: String,
-    status: PostStatus,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-struct SubmissionInfo {
-    data_id: u64,
-    user_id: UserId,
-    username: Option<String>,
-    first_name: Option<String>,
-    last_name: Option<String>,
-    profile_picture_url: Option<String>,
-    latitude: f64,
-    longitude: f64,
-    city: String,
-    temperature: f64,
-    weather: String,
-    timestamp: u64,
-    submission_photo_url: String,
-    rewarded: bool,
-    status: PostStatus,
-}
+impl Storable for UserDataKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_USER_ID_BYTES + 8,
+        is_fixed_size: false,
+    };
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-struct ExpirationInfo {
-    data_id: u64,
-    expiration_timestamp: u64,
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.user.as_bytes().to_vec();
+        bytes.extend_from_slice(&self.data_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (user, data_id) = bytes.split_at(bytes.len() - 8);
+        UserDataKey {
+            user: String::from_utf8(user.to_vec()).expect("UserDataKey user is not valid UTF-8"),
+            data_id: u64::from_be_bytes(data_id.try_into().expect("UserDataKey data_id is 8 bytes")),
+        }
+    }
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
-struct TokenBalance {
-    balance: u64,
+/// Key of the `SUBMISSIONS_BY_CITY` index; `city` is normalized and
+/// truncated to `MAX_CITY_KEY_BYTES`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct CityKey {
+    city: String,
+    data_id: u64,
 }
 
-impl ic_stable_structures::Storable for TokenBalance {
-    const BOUND: Bound = Bound::Unbounded;
+impl Storable for CityKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_CITY_KEY_BYTES + 8,
+        is_fixed_size: false,
+    };
 
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_cbor::to_vec(self).unwrap())
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.city.as_bytes().to_vec();
+        bytes.extend_from_slice(&self.data_id.to_be_bytes());
+        Cow::Owned(bytes)
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_cbor::from_slice(&bytes).unwrap()
+        let (city, data_id) = bytes.split_at(bytes.len() - 8);
+        CityKey {
+            city: String::from_utf8(city.to_vec()).expect("CityKey city is not valid UTF-8"),
+            data_id: u64::from_be_bytes(data_id.try_into().expect("CityKey data_id is 8 bytes")),
+        }
     }
 }
 
-// -------- Enums --------
-
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
-enum PostStatus {
-    OPEN,
-    PENDING,
-    PAID,
-    EXPIRED,
+/// Key of the spatial indexes: a geohash cell and the id of a record in it.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct GeoKey {
+    cell: String,
+    id: u64,
 }
 
-#[derive(CandidType, Serialize, Deserialize)]
-enum RewardResponse {
-    Ok(String),
-    Err(String),
-}
+impl Storable for GeoKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: geo::MAX_PRECISION as u32 + 8,
+        is_fixed_size: false,
+    };
 
-// -------- Ledger Transfer Types --------
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.cell.as_bytes().to_vec();
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
 
-#[derive(CandidType, Deserialize)]
-pub struct TransferArg {
-    pub to: Account,
-    pub fee: Option<Nat>,
-    pub memo: Option<Vec<u8>>,
-    pub from_subaccount: Option<Vec<u8>>,
-    pub created_at_time: Option<u64>,
-    pub amount: Nat,
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (cell, id) = bytes.split_at(bytes.len() - 8);
+        GeoKey {
+            cell: String::from_utf8(cell.to_vec()).expect("GeoKey cell is not valid UTF-8"),
+            id: u64::from_be_bytes(id.try_into().expect("GeoKey id is 8 bytes")),
+        }
+    }
 }
 
-// -------- Ledger Account / TransferResult / TransferError --------
+/// Half-open interval `[start, end)` of timestamps in nanoseconds.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct TimeRange {
+    start: u64,
+    end: u64,
+}
 
-#[derive(CandidType, Deserialize)]
-pub struct Account {
-    pub owner: Principal,
-    pub subaccount: Option<Vec<u8>>,
+/// Key of the `SUBMISSIONS_BY_STATUS` index.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct StatusKey {
+    status: u8,
+    data_id: u64,
 }
 
-#[derive(CandidType, Deserialize, Debug)]
-pub enum TransferResult {
-    Ok(Nat),
-    Err(TransferError),
+impl StatusKey {
+    fn new(status: &PostStatus, data_id: u64) -> Self {
+        let status = match status {
+            PostStatus::OPEN => 0,
+            PostStatus::PENDING => 1,
+            PostStatus::PAID => 2,
+            PostStatus::EXPIRED => 3,
+            PostStatus::NoQuorum => 4,
+        };
+        StatusKey { status, data_id }
+    }
 }
 
-#[derive(CandidType, Deserialize, Debug)]
-pub enum TransferError {
-    GenericError { message: String, error_code: Nat },
-    TemporarilyUnavailable,
-    Duplicate { duplicate_of: Nat },
-    BadFee { expected_fee: Nat },
-    CreatedInFuture { ledger_time: u64 },
-    TooOld,
-    InsufficientFunds { balance: Nat },
+impl Storable for StatusKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 9,
+        is_fixed_size: true,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![self.status];
+        bytes.extend_from_slice(&self.data_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StatusKey {
+            status: bytes[0],
+            data_id: u64::from_be_bytes(bytes[1..].try_into().expect("StatusKey data_id is 8 bytes")),
+        }
+    }
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-struct SubmissionLocationInfo {
+/// Key of the `SUBMISSIONS_BY_CHALLENGE` index.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ChallengeKey {
+    challenge_id: u64,
     data_id: u64,
-    latitude: f64,
-    longitude: f64,
-    status: PostStatus,
 }
 
-// -------- Storage (StableBTreeMap + MemoryManager) --------
-thread_local! {
-    static MEMORY_MANAGER: std::cell::RefCell<MemoryManager<DefaultMemoryImpl>> =
-        std::cell::RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+impl Storable for ChallengeKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 16,
+        is_fixed_size: true,
+    };
 
-    static SUBMISSIONS: std::cell::RefCell<StableBTreeMap<u64, UserSubmission, VirtualMemory<DefaultMemoryImpl>>> = 
-        std::cell::RefCell::new({
-            let memory = MEMORY_MANAGER.with(|m| {
-                let memory_id = MemoryId::new(0);
-                let mem = m.borrow().get(memory_id);
-                ic_cdk::println!("DEBUG: Allocated memory region {:?}", memory_id);
-                mem
-            });
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.challenge_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.data_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
 
-            StableBTreeMap::init(memory) 
-        });
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (challenge_id, data_id) = bytes.split_at(8);
+        ChallengeKey {
+            challenge_id: u64::from_be_bytes(challenge_id.try_into().expect("ChallengeKey challenge_id is 8 bytes")),
+            data_id: u64::from_be_bytes(data_id.try_into().expect("ChallengeKey data_id is 8 bytes")),
+        }
+    }
+}
 
-    static USERS: std::cell::RefCell<StableBTreeMap<UserId, User, VirtualMemory<DefaultMemoryImpl>>> =
-        std::cell::RefCell::new({
-            let memory = MEMORY_MANAGER.with(|m| {
-                let memory_id = MemoryId::new(1);
-                m.borrow().get(memory_id)
-            });
-            StableBTreeMap::init(memory)
-        });
+/// Highest ids handed out so far. New ids are always `last + 1`, so ids are
+/// never reused even if records are removed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct IdCounters {
+    last_submission_id: u64,
+    last_challenge_id: u64,
+    #[serde(default)]
+    last_balance_entry_id: u64,
+    #[serde(default)]
+    last_claim_id: u64,
+}
 
-    static VOTES: std::cell::RefCell<HashMap<u64, Vec<Vote>>> =
-        std::cell::RefCell::new(HashMap::new());
+impl Storable for IdCounters {
+    const BOUND: Bound = Bound::Unbounded;
 
-    static CHALLENGES: RefCell<StableBTreeMap<u64, Challenge, VirtualMemory<DefaultMemoryImpl>>> = 
-        RefCell::new({
-            let memory = MEMORY_MANAGER.with(|m| {
-                m.borrow().get(MemoryId::new(2))
-            });
-            StableBTreeMap::init(memory)
-        });
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("IdCounters serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("IdCounters deserialization failed")
+    }
 }
 
-// -------- Upgrade Hooks --------
-#[pre_upgrade]
-fn pre_upgrade() {
-    let submission_backup: Vec<(u64, UserSubmission)> = SUBMISSIONS.with(|s| {
-        s.borrow().iter().map(|(k, v)| (k, v.clone())).collect()
-    });
+/// Reward and voting policy, set through init/upgrade arguments or `update_config`.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct DaoConfig {
+    ledger_canister_id: Principal,
+    /// Reward pool of a decided post, in the ledger's smallest unit: the
+    /// submitter of an accepted post gets it less the voters' share, except
+    /// in sponsored challenges, whose escrow pays the whole pool.
+    reward_amount: u64,
+    /// Fee passed to `icrc1_transfer`; `None` lets the ledger charge its default fee.
+    transfer_fee: Option<u64>,
+    /// Take the transfer fee out of `reward_amount` instead of paying it on top.
+    fee_included_in_reward: bool,
+    voting_window_secs: u64,
+    challenge_voting_window_secs: u64,
+    /// Minimum number of votes for a post to be decided at all; below it the
+    /// post ends as `NoQuorum`.
+    quorum: u32,
+    /// Minimum number of valid votes for a post to be accepted.
+    #[serde(default)]
+    min_approvals: u32,
+    /// Share of valid votes, in basis points, that must be exceeded for a post to be accepted.
+    approval_threshold_bps: u32,
+    /// Share of `reward_amount`, in basis points, split among the voters who
+    /// sided with the outcome of a decided post. The submitter of an
+    /// accepted post gets the rest.
+    #[serde(default)]
+    voter_reward_bps: u32,
+}
 
-    let user_backup: Vec<(UserId, User)> = USERS.with(|u| {
-        u.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect()
-    });    
+impl Default for DaoConfig {
+    fn default() -> Self {
+        DaoConfig {
+            ledger_canister_id: Principal::from_text("br5f7-7uaaa-aaaaa-qaaca-cai")
+                .expect("Invalid default ledger canister ID"),
+            reward_amount: 10_000,
+            transfer_fee: None,
+            fee_included_in_reward: false,
+            voting_window_secs: 900,
+            challenge_voting_window_secs: 300,
+            quorum: 1,
+            min_approvals: 1,
+            approval_threshold_bps: 5_000,
+            voter_reward_bps: 0,
+        }
+    }
+}
 
-    ic_cdk::storage::stable_save((submission_backup, user_backup))
-        .expect("Failed to save state before upgrade");
+impl DaoConfig {
+    /// Part of the reward pool of a decided post that goes to its voters.
+    fn voter_pool(&self) -> u64 {
+        (self.reward_amount as u128 * self.voter_reward_bps as u128 / 10_000) as u64
+    }
+
+    /// Part of the reward pool that goes to the submitter of an accepted post.
+    fn submitter_reward(&self) -> u64 {
+        self.reward_amount - self.voter_pool()
+    }
 }
 
-#[post_upgrade]
-fn post_upgrade() {
-    if let Ok((submission_backup, user_backup)) =
-        ic_cdk::storage::stable_restore::<(Vec<(u64, UserSubmission)>, Vec<(UserId, User)>)>()
-    {
-        SUBMISSIONS.with(|s| {
-            let mut s = s.borrow_mut();
-            for (k, mut v) in submission_backup {
-                v.data_id = k;
-                s.insert(k, v);
-            }
-        });
+impl Storable for DaoConfig {
+    const BOUND: Bound = Bound::Unbounded;
 
-        USERS.with(|u| {
-            let mut u = u.borrow_mut();
-            for (k, v) in user_backup {
-                u.insert(k, v);
-            }
-        });
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("DaoConfig serialization failed"))
+    }
 
-        ic_cdk::println!("INFO: Successfully restored submissions and users after upgrade.");
-    } else {
-        ic_cdk::println!("WARNING: Failed to restore submissions or users after upgrade.");
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("DaoConfig deserialization failed")
     }
 }
 
-// -------- User functions --------
-#[update]
-#[candid_method(update)]
-fn create_tg_user(telegram_id: String, first_name: String, last_name: String, username: String, language_code: String, is_bot: bool, profile_picture_url: String) -> String {
-    USERS.with(|users_map| {
-        let mut users = users_map.borrow_mut();
-        let user_id = telegram_id.clone();
-
-        if let Some(user) = users.get(&user_id) {
-            let mut updated_user = user;
-            ic_cdk::println!("👤 Existing user found: {}. Updating info.", user_id);
-            updated_user.first_name = Some(first_name);
-            updated_user.last_name = Some(last_name);
-            updated_user.username = Some(username);
-            updated_user.language_code = Some(language_code);
-            updated_user.is_bot = is_bot;
-            updated_user.profile_picture_url = Some(profile_picture_url);
-            users.insert(user_id.clone(), updated_user);
-            format!("Updated user: {}", user_id)
-        } else {
-            let new_user = User {
-                user_id: user_id.clone(),
-                balance: 0,
-                first_name: Some(first_name),
-                last_name: Some(last_name),
-                username: Some(username),
-                language_code: Some(language_code),
-                is_bot,
-                profile_picture_url: Some(profile_picture_url),
-                wallet_address: None,
-                role: Role::User,
-            };
-            users.insert(user_id.clone(), new_user);
-            ic_cdk::println!("Created new user: {}", user_id);
-            format!("Created new user: {}", user_id)
-        }
-    })
+/// Controls the timer that finalizes expired posts.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct FinalizerSettings {
+    enabled: bool,
+    interval_secs: u64,
+    /// Maximum number of posts finalized per run.
+    batch_size: u32,
+    /// Pay out posts that finalize as `PENDING` in the same run.
+    auto_payout: bool,
 }
 
-#[query]
-#[candid_method(query)]
-fn get_tg_user(user_id: String) -> Result<User, String> {
-    USERS.with(|users| {
-        let users = users.borrow();
-        match users.get(&user_id) {
-            Some(user) => Ok(user.clone()),
-            None => Err(format!("User {} not found.", user_id)),
+impl Default for FinalizerSettings {
+    fn default() -> Self {
+        FinalizerSettings {
+            enabled: true,
+            interval_secs: 60,
+            batch_size: 50,
+            auto_payout: false,
         }
-    })
+    }
+}
+
+impl Storable for FinalizerSettings {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("FinalizerSettings serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("FinalizerSettings deserialization failed")
+    }
+}
+
+/// Limits of the batch payout job for `PENDING` posts.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct PayoutRunSettings {
+    enabled: bool,
+    interval_secs: u64,
+    /// Posts paid per run at most.
+    max_payouts_per_run: u32,
+    /// Ledger calls awaited at the same time.
+    max_concurrency: u32,
+    /// Cycles a run may spend before it stops.
+    max_cycles_per_run: u64,
+    /// No new payouts start below this canister cycle balance.
+    min_cycles_balance: u64,
+}
+
+impl Default for PayoutRunSettings {
+    fn default() -> Self {
+        PayoutRunSettings {
+            enabled: false,
+            interval_secs: 300,
+            max_payouts_per_run: 100,
+            max_concurrency: 10,
+            max_cycles_per_run: 50_000_000_000,
+            min_cycles_balance: 500_000_000_000,
+        }
+    }
+}
+
+impl Storable for PayoutRunSettings {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("PayoutRunSettings serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("PayoutRunSettings deserialization failed")
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct PaidSubmission {
+    data_id: u64,
+    block_index: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct SubmissionIssue {
+    data_id: u64,
+    reason: String,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct PayoutRunReport {
+    started_at: u64,
+    finished_at: u64,
+    paid: Vec<PaidSubmission>,
+    failed: Vec<SubmissionIssue>,
+    /// Posts not attempted, e.g. without a wallet or left for the next run.
+    /// Only the first `MAX_REPORTED_SKIPS` are listed.
+    skipped: Vec<SubmissionIssue>,
+    #[serde(default)]
+    skipped_count: u64,
+    cycles_spent: u64,
+    /// Why the run ended before visiting every `PENDING` post.
+    stopped_early: Option<String>,
+    /// Set when `skipped` was cut at `MAX_REPORTED_SKIPS` entries.
+    #[serde(default)]
+    truncated: bool,
+}
+
+impl PayoutRunReport {
+    fn skip(&mut self, data_id: u64, reason: String) {
+        self.skipped_count += 1;
+        if self.skipped.len() < MAX_REPORTED_SKIPS {
+            self.skipped.push(SubmissionIssue { data_id, reason });
+        } else {
+            self.truncated = true;
+        }
+    }
+}
+
+impl Storable for PayoutRunReport {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("PayoutRunReport serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("PayoutRunReport deserialization failed")
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct FinalizerReport {
+    pending: Vec<u64>,
+    expired: Vec<u64>,
+    no_quorum: Vec<u64>,
+    paid: Vec<u64>,
+    payout_errors: Vec<(u64, DaoError)>,
+    /// Challenges whose end time passed.
+    closed_challenges: Vec<u64>,
+}
+
+/// How often a user's votes agreed with the final outcome of a post. The
+/// score is the weight of the user's votes.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct Reputation {
+    user_id: UserId,
+    score: u32,
+    correct_votes: u32,
+    incorrect_votes: u32,
+    /// Total credited for votes that agreed with the outcome.
+    #[serde(default)]
+    voter_rewards: u64,
+}
+
+impl Reputation {
+    fn new(user_id: UserId) -> Self {
+        Reputation {
+            user_id,
+            score: REPUTATION_INITIAL_SCORE,
+            correct_votes: 0,
+            incorrect_votes: 0,
+            voter_rewards: 0,
+        }
+    }
+}
+
+impl Storable for Reputation {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("Reputation serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("Reputation deserialization failed")
+    }
+}
+
+/// Per-user quotas and near-duplicate detection. A zero quota or window
+/// disables that check.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct RateLimits {
+    submissions_per_hour: u32,
+    submissions_per_day: u32,
+    votes_per_hour: u32,
+    /// Submissions of the same user closer than this, within
+    /// `duplicate_window_secs`, are near-duplicates.
+    duplicate_radius_m: f64,
+    duplicate_window_secs: u64,
+    /// Reject near-duplicates instead of storing them with `duplicate_of` set.
+    reject_duplicates: bool,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            submissions_per_hour: 5,
+            submissions_per_day: 20,
+            votes_per_hour: 60,
+            duplicate_radius_m: 100.0,
+            duplicate_window_secs: 3_600,
+            reject_duplicates: true,
+        }
+    }
+}
+
+impl Storable for RateLimits {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("RateLimits serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("RateLimits deserialization failed")
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct RecentSubmission {
+    data_id: u64,
+    timestamp: u64,
+    latitude: f64,
+    longitude: f64,
+}
+
+/// Recent actions of a user, oldest first. Entries older than the longest
+/// window that still needs them are pruned on every write.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct UserActivity {
+    submissions: Vec<RecentSubmission>,
+    vote_times: Vec<u64>,
+}
+
+impl Storable for UserActivity {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("UserActivity serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("UserActivity deserialization failed")
+    }
+}
+
+/// Outcome of the consistency check run on install and after every upgrade.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct IntegrityReport {
+    schema_version: u32,
+    checked_at: u64,
+    /// Migrations applied by the upgrade, oldest first.
+    migrations: Vec<schema::MigrationStep>,
+    submissions: u64,
+    users: u64,
+    challenges: u64,
+    votes: u64,
+    payouts: u64,
+    /// Inconsistencies found; empty when the state is coherent.
+    issues: Vec<String>,
+}
+
+impl Storable for IntegrityReport {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("IntegrityReport serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("IntegrityReport deserialization failed")
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct UserSubmissionSummary {
+    data_id: u64,
+    city: String,
+    status: PostStatus,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct SubmissionInfo {
+    data_id: u64,
+    user_id: UserId,
+    username: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    profile_picture_url: Option<String>,
+    latitude: f64,
+    longitude: f64,
+    city: String,
+    temperature: f64,
+    weather: String,
+    timestamp: u64,
+    submission_photo_url: String,
+    rewarded: bool,
+    status: PostStatus,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct ExpirationInfo {
+    data_id: u64,
+    expiration_timestamp: u64,
+}
+
+// Pages of list queries. `next_cursor` is the `start_after` of the next page
+// and is `None` on the last page.
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct SubmissionPage {
+    items: Vec<UserSubmission>,
+    next_cursor: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct UserPage {
+    items: Vec<User>,
+    next_cursor: Option<UserId>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct ChallengePage {
+    items: Vec<Challenge>,
+    next_cursor: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct ExpirationPage {
+    items: Vec<ExpirationInfo>,
+    next_cursor: Option<u64>,
+}
+
+/// Submissions of one page grouped by city. A city can appear on several
+/// pages.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct CityMapPage {
+    items: Vec<(String, Vec<SubmissionInfo>)>,
+    next_cursor: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+struct TokenBalance {
+    balance: u64,
+}
+
+impl ic_stable_structures::Storable for TokenBalance {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).unwrap()
+    }
+}
+
+// -------- Enums --------
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+enum PostStatus {
+    OPEN,
+    PENDING,
+    PAID,
+    EXPIRED,
+    /// Voting closed without enough participation to decide.
+    NoQuorum,
+}
+
+/// Error returned by every fallible endpoint.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+enum DaoError {
+    NotFound(String),
+    Unauthorized(String),
+    InvalidInput(String),
+    /// The record is not in a state that allows the operation.
+    InvalidState(String),
+    AlreadyVoted,
+    /// Authors cannot vote on their own submissions.
+    SelfVote,
+    /// The post is no longer `OPEN` or its voting window has ended.
+    VotingClosed,
+    AlreadyRewarded,
+    LedgerError { message: String },
+    /// A per-user quota is used up; retry after the given delay.
+    RateLimited { retry_after_secs: u64 },
+    /// The user submitted nearly the same observation recently.
+    DuplicateSubmission { data_id: u64 },
+    /// Every field that failed validation.
+    Validation(Vec<FieldError>),
+}
+
+// -------- Ledger Transfer Types --------
+
+#[derive(CandidType, Deserialize)]
+pub struct TransferArg {
+    pub to: Account,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+    pub amount: Nat,
+}
+
+// -------- Ledger Account / TransferResult / TransferError --------
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TransferResult {
+    Ok(Nat),
+    Err(TransferError),
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TransferError {
+    GenericError { message: String, error_code: Nat },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    BadFee { expected_fee: Nat },
+    CreatedInFuture { ledger_time: u64 },
+    TooOld,
+    InsufficientFunds { balance: Nat },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TransferFromResult {
+    Ok(Nat),
+    Err(TransferFromError),
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetTransactionsRequest {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct LedgerMint {
+    pub to: Account,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct LedgerTransfer {
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+    pub spender: Option<Account>,
+}
+
+/// A ledger block; burns and approvals are not read.
+#[derive(CandidType, Deserialize, Debug)]
+pub struct LedgerTransaction {
+    pub kind: String,
+    pub mint: Option<LedgerMint>,
+    pub transfer: Option<LedgerTransfer>,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct TransactionRange {
+    pub transactions: Vec<LedgerTransaction>,
+}
+
+candid::define_function!(pub QueryArchiveFn : (GetTransactionsRequest) -> (TransactionRange) query);
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct ArchivedTransactions {
+    pub start: Nat,
+    pub length: Nat,
+    pub callback: QueryArchiveFn,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct GetTransactionsResponse {
+    pub log_length: Nat,
+    pub first_index: Nat,
+    pub transactions: Vec<LedgerTransaction>,
+    pub archived_transactions: Vec<ArchivedTransactions>,
+}
+
+// -------- Payout Types --------
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+enum PayoutState {
+    Scheduled,
+    InFlight,
+    Paid { block_index: u64 },
+    Failed { reason: String },
+}
+
+/// A reward transfer, persisted before the ledger is called. Retries resend
+/// exactly the same transfer so the ledger deduplicates them.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct Payout {
+    data_id: u64,
+    to: Account,
+    amount: u64,
+    fee: Option<u64>,
+    memo: Vec<u8>,
+    /// Set on the first attempt and kept while an earlier attempt may have
+    /// reached the ledger; cleared only after a definite ledger rejection.
+    created_at_time: Option<u64>,
+    state: PayoutState,
+    attempts: u32,
+    /// Challenge whose escrow subaccount the payout is sent from.
+    #[serde(default)]
+    escrow_challenge_id: Option<u64>,
+    /// Ledger the payout was scheduled on. Retries go to the same ledger even
+    /// if the config changed, so it can deduplicate them.
+    #[serde(default)]
+    ledger_canister_id: Option<Principal>,
+    /// Credited reward taken out of the user's balance for this payout.
+    /// Given back with the escrow reservation when the ledger definitely did
+    /// not execute the payout, and taken again on the next attempt.
+    #[serde(default)]
+    balance_debit: u64,
+}
+
+impl Storable for Payout {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("Payout serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("Payout deserialization failed")
+    }
+}
+
+// -------- Balance Types --------
+
+/// Why a user's internal balance changed.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+enum BalanceChange {
+    /// Credit: reward of an accepted post.
+    SubmissionReward { data_id: u64 },
+    /// Debit: the remaining reward of a post sent to the linked wallet.
+    Payout { data_id: u64 },
+    /// Debit: a transfer requested through `claim_rewards`.
+    Claim { claim_id: u64 },
+    /// Credit: a claim the ledger did not execute, given back.
+    ClaimReverted { claim_id: u64 },
+    /// Credit: share of a post's voter pool for a vote that matched the outcome.
+    VoterReward { data_id: u64 },
+    /// Credit: the balance taken for a payout the ledger did not execute.
+    PayoutReverted { data_id: u64 },
+    /// Debit: the remaining reward of a post marked as rewarded by hand.
+    MarkedRewarded { data_id: u64 },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct BalanceEntry {
+    id: u64,
+    user: UserId,
+    change: BalanceChange,
+    amount: u64,
+    balance_after: u64,
+    at: u64,
+}
+
+impl Storable for BalanceEntry {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("BalanceEntry serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("BalanceEntry deserialization failed")
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct BalanceHistoryPage {
+    items: Vec<BalanceEntry>,
+    next_cursor: Option<u64>,
+}
+
+/// A transfer out of a user's balance. `amount` is what the balance was
+/// debited; the ledger fee comes out of it when fees are included in rewards.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct RewardClaim {
+    id: u64,
+    user: UserId,
+    to: Account,
+    amount: u64,
+    transfer_amount: u64,
+    fee: Option<u64>,
+    memo: Vec<u8>,
+    created_at: u64,
+    state: PayoutState,
+}
+
+impl Storable for RewardClaim {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("RewardClaim serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("RewardClaim deserialization failed")
+    }
+}
+
+// -------- Escrow Types --------
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct EscrowTransfer {
+    amount: u64,
+    block_index: u64,
+    at: u64,
+}
+
+/// Sponsor budget of a challenge, held in the challenge's subaccount of this
+/// canister. Reserved and paid amounts include the fees of the transfers out.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct ChallengeEscrow {
+    challenge_id: u64,
+    sponsor: Account,
+    /// Ledger fee of a transfer out of the subaccount, read when the escrow
+    /// was first funded.
+    transfer_fee: u64,
+    deposited: u64,
+    /// Cost of payouts scheduled but not paid yet.
+    reserved: u64,
+    paid: u64,
+    deposits: Vec<EscrowTransfer>,
+    refund: Option<EscrowTransfer>,
+}
+
+impl ChallengeEscrow {
+    /// Budget left for new payouts; nothing once refunded.
+    fn available(&self) -> u64 {
+        if self.refund.is_some() {
+            return 0;
+        }
+        self.deposited.saturating_sub(self.reserved).saturating_sub(self.paid)
+    }
+
+    /// Amount a payout takes out of the subaccount.
+    fn payout_cost(&self, amount: u64, fee: Option<u64>) -> u64 {
+        amount.saturating_add(fee.unwrap_or(self.transfer_fee))
+    }
+}
+
+impl Storable for ChallengeEscrow {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("ChallengeEscrow serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("ChallengeEscrow deserialization failed")
+    }
+}
+
+// -------- Reconciliation Types --------
+
+/// What a transfer out of this canister paid for, read from its memo.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+enum PaymentRef {
+    Reward { data_id: u64 },
+    Claim { claim_id: u64 },
+}
+
+/// A transfer with one of our memos found on the ledger.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct LedgerPayment {
+    block_index: u64,
+    payment: PaymentRef,
+    to: Account,
+    amount: u64,
+    timestamp: u64,
+}
+
+impl Storable for LedgerPayment {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("LedgerPayment serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("LedgerPayment deserialization failed")
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct Discrepancy {
+    payment: PaymentRef,
+    block_index: Option<u64>,
+    /// Amount recorded by this canister.
+    expected_amount: Option<u64>,
+    /// Amount found on the ledger.
+    ledger_amount: Option<u64>,
+    reason: String,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct ReconciliationReport {
+    generated_at: u64,
+    /// Blocks below this index have been read.
+    scanned_to: u64,
+    log_length: u64,
+    /// Ledger payments that agree with the local records.
+    matched: u64,
+    /// Local payments in blocks not read yet.
+    unverified: u64,
+    /// On the ledger, but not recorded as paid here.
+    orphaned_payments: Vec<Discrepancy>,
+    /// Recorded as paid here, but no matching ledger transfer.
+    missing_payments: Vec<Discrepancy>,
+    amount_mismatches: Vec<Discrepancy>,
+    /// Set when a list was cut at `MAX_REPORTED_DISCREPANCIES` entries.
+    truncated: bool,
+}
+
+impl Storable for ReconciliationReport {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("ReconciliationReport serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("ReconciliationReport deserialization failed")
+    }
+}
+
+/// Records a reconciliation check walks, in order.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+enum ReconciliationStage {
+    LedgerPayments,
+    Submissions,
+    Claims,
+}
+
+/// A check spread over several `run_reconciliation` calls.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct ReconciliationCheck {
+    stage: ReconciliationStage,
+    /// Last key checked in `stage`.
+    checked_to: Option<u64>,
+    /// Discrepancies found so far; `scanned_to` is fixed when the check starts.
+    report: ReconciliationReport,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct ReconciliationProgress {
+    /// Blocks below this index have been read.
+    scanned_to: u64,
+    log_length: u64,
+    /// `None` until the next call starts a check.
+    check: Option<ReconciliationCheck>,
+}
+
+impl Storable for ReconciliationProgress {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("ReconciliationProgress serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("ReconciliationProgress deserialization failed")
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct SubmissionLocationInfo {
+    data_id: u64,
+    latitude: f64,
+    longitude: f64,
+    status: PostStatus,
+}
+
+/// `auth_date` and signature of a Telegram `initData`.
+type InitDataKey = (u64, [u8; 32]);
+
+// -------- Storage (StableBTreeMap + MemoryManager) --------
+
+// Memories whose values have a versioned layout, see `schema`.
+const SUBMISSIONS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const USERS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const CHALLENGES_MEMORY_ID: MemoryId = MemoryId::new(2);
+
+thread_local! {
+    static MEMORY_MANAGER: std::cell::RefCell<MemoryManager<DefaultMemoryImpl>> =
+        std::cell::RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static SUBMISSIONS: std::cell::RefCell<StableBTreeMap<u64, UserSubmission, VirtualMemory<DefaultMemoryImpl>>> = 
+        std::cell::RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                let memory_id = SUBMISSIONS_MEMORY_ID;
+                let mem = m.borrow().get(memory_id);
+                ic_cdk::println!("DEBUG: Allocated memory region {:?}", memory_id);
+                mem
+            });
+
+            StableBTreeMap::init(memory) 
+        });
+
+    static USERS: std::cell::RefCell<StableBTreeMap<UserId, User, VirtualMemory<DefaultMemoryImpl>>> =
+        std::cell::RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(USERS_MEMORY_ID)
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    static VOTES: RefCell<StableBTreeMap<VoteKey, Vote, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(3))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    static USER_VOTES: RefCell<StableBTreeMap<UserDataKey, (), VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(4))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    static TRUSTED_RELAYERS: RefCell<StableBTreeMap<Principal, (), VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(5))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    static PRINCIPAL_USERS: RefCell<StableBTreeMap<Principal, UserId, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(6))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    static TELEGRAM_BOT_TOKEN: RefCell<StableCell<String, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(7))
+            });
+            StableCell::init(memory, String::new()).expect("Failed to init bot token cell")
+        });
+
+    static ID_COUNTERS: RefCell<StableCell<IdCounters, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(8))
+            });
+            StableCell::init(memory, IdCounters::default()).expect("Failed to init id counters cell")
+        });
+
+    static FINALIZER_SETTINGS: RefCell<StableCell<FinalizerSettings, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(9))
+            });
+            StableCell::init(memory, FinalizerSettings::default()).expect("Failed to init finalizer settings cell")
+        });
+
+    static CONFIG: RefCell<StableCell<DaoConfig, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(10))
+            });
+            StableCell::init(memory, DaoConfig::default()).expect("Failed to init config cell")
+        });
+
+    static PAYOUTS: RefCell<StableBTreeMap<u64, Payout, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(11))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    static REPUTATIONS: RefCell<StableBTreeMap<UserId, Reputation, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(12))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    static SCHEMA_VERSION: RefCell<StableCell<u32, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(13))
+            });
+            StableCell::init(memory, 0).expect("Failed to init schema version cell")
+        });
+
+    static INTEGRITY_REPORT: RefCell<StableCell<IntegrityReport, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(14))
+            });
+            StableCell::init(memory, IntegrityReport::default()).expect("Failed to init integrity report cell")
+        });
+
+    static VALIDATION_LIMITS: RefCell<StableCell<ValidationLimits, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(15))
+            });
+            StableCell::init(memory, ValidationLimits::default()).expect("Failed to init validation limits cell")
+        });
+
+    static RATE_LIMITS: RefCell<StableCell<RateLimits, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(16))
+            });
+            StableCell::init(memory, RateLimits::default()).expect("Failed to init rate limits cell")
+        });
+
+    static USER_ACTIVITY: RefCell<StableBTreeMap<UserId, UserActivity, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(17))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    static SUBMISSIONS_BY_USER: RefCell<StableBTreeMap<UserDataKey, (), VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(18))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    static SUBMISSIONS_BY_CITY: RefCell<StableBTreeMap<CityKey, (), VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(19))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    static SUBMISSIONS_BY_STATUS: RefCell<StableBTreeMap<StatusKey, (), VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(20))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    /// Submissions linked to a challenge.
+    static SUBMISSIONS_BY_CHALLENGE: RefCell<StableBTreeMap<ChallengeKey, (), VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(24))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    static CHALLENGE_ESCROWS: RefCell<StableBTreeMap<u64, ChallengeEscrow, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(25))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    /// Credits and debits of user balances, by user and entry id.
+    static BALANCE_HISTORY: RefCell<StableBTreeMap<UserDataKey, BalanceEntry, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(26))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    static CLAIMS: RefCell<StableBTreeMap<u64, RewardClaim, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(27))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    static PAYOUT_RUN_SETTINGS: RefCell<StableCell<PayoutRunSettings, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(28))
+            });
+            StableCell::init(memory, PayoutRunSettings::default()).expect("Failed to init payout run settings cell")
+        });
+
+    static LAST_PAYOUT_RUN: RefCell<StableCell<PayoutRunReport, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(29))
+            });
+            StableCell::init(memory, PayoutRunReport::default()).expect("Failed to init payout run report cell")
+        });
+
+    /// Transfers with our memos, by ledger block index.
+    static LEDGER_PAYMENTS: RefCell<StableBTreeMap<u64, LedgerPayment, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(30))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    static RECONCILIATION_REPORT: RefCell<StableCell<ReconciliationReport, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(31))
+            });
+            StableCell::init(memory, ReconciliationReport::default()).expect("Failed to init reconciliation report cell")
+        });
+
+    /// `initData` signatures already used, by `auth_date`, with the principal
+    /// they were bound to. Kept until the `initData` expires.
+    static CONSUMED_INIT_DATA: RefCell<StableBTreeMap<InitDataKey, Principal, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(32))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    /// Ledger read position and the local check in progress.
+    static RECONCILIATION_PROGRESS: RefCell<StableCell<ReconciliationProgress, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(33))
+            });
+            StableCell::init(memory, ReconciliationProgress::default()).expect("Failed to init reconciliation progress cell")
+        });
+
+    /// Polygon geofences, kept apart from the bounded `CHALLENGES` map.
+    static CHALLENGE_BOUNDARIES: RefCell<StableBTreeMap<u64, geo::Boundary, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(23))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    /// Challenges under the cells covering their geofence.
+    static CHALLENGE_CELLS: RefCell<StableBTreeMap<GeoKey, (), VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(21))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    /// Submissions under the cell of their location.
+    static SUBMISSION_CELLS: RefCell<StableBTreeMap<GeoKey, (), VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(MemoryId::new(22))
+            });
+            StableBTreeMap::init(memory)
+        });
+
+    /// Payouts whose ledger call is currently awaited. An `InFlight` payout
+    /// missing from this set was left behind by a trap or an upgrade.
+    static PAYOUTS_IN_PROGRESS: RefCell<std::collections::BTreeSet<u64>> = const { RefCell::new(std::collections::BTreeSet::new()) };
+
+    /// Challenges whose escrow is being funded or refunded.
+    static ESCROWS_IN_PROGRESS: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+
+    static FINALIZER_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+
+    static FINALIZER_RUNNING: Cell<bool> = const { Cell::new(false) };
+
+    static PAYOUT_RUN_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+
+    static PAYOUT_RUN_RUNNING: Cell<bool> = const { Cell::new(false) };
+
+    static RECONCILING: Cell<bool> = const { Cell::new(false) };
+
+    static CHALLENGES: RefCell<StableBTreeMap<u64, Challenge, VirtualMemory<DefaultMemoryImpl>>> = 
+        RefCell::new({
+            let memory = MEMORY_MANAGER.with(|m| {
+                m.borrow().get(CHALLENGES_MEMORY_ID)
+            });
+            StableBTreeMap::init(memory)
+        });
+}
+
+// -------- Upgrade Hooks --------
+// All state lives in stable structures behind the memory manager, so there is
+// no pre_upgrade hook. Record layouts are versioned; `post_upgrade` migrates
+// them to the layout of this build (see `schema`) and stores an integrity
+// report of the result.
+
+/// Magic bytes the `MemoryManager` keeps at offset 0 of stable memory.
+const MEMORY_MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+#[post_upgrade]
+fn post_upgrade(config: Option<DaoConfig>) {
+    let legacy_snapshot = take_legacy_snapshot();
+
+    // The typed maps must not be opened before their records are migrated.
+    let stored_version = SCHEMA_VERSION.with(|v| *v.borrow().get());
+    let migrations = schema::migrate(stored_version);
+    set_schema_version(schema::CURRENT_SCHEMA_VERSION);
+
+    if let Some((submission_backup, user_backup)) = legacy_snapshot {
+        for (k, v) in submission_backup {
+            store_submission(&v.into_current(k));
+        }
+
+        USERS.with(|u| {
+            let mut u = u.borrow_mut();
+            for (k, v) in user_backup {
+                u.insert(k, User::from(v));
+            }
+        });
+
+        ic_cdk::println!("INFO: Migrated legacy stable_save snapshot of submissions and users.");
+    }
+
+    sync_id_counters();
+    if let Some(config) = config {
+        store_config(config).unwrap_or_else(|e| ic_cdk::trap(&format!("{:?}", e)));
+    }
+
+    let report = check_integrity(migrations);
+    if report.issues.is_empty() {
+        ic_cdk::println!("INFO: Upgrade integrity check passed.");
+    } else {
+        ic_cdk::println!("WARNING: Upgrade integrity check found {} issue(s): {:?}", report.issues.len(), report.issues);
+    }
+    store_integrity_report(report);
+
+    schedule_finalizer();
+    schedule_payout_runner();
+}
+
+/// One-time migration from deployments whose `pre_upgrade` wrote submissions
+/// and users with `stable_save`. That snapshot overwrites the memory manager
+/// header, so it is only present when the magic bytes are missing. Votes were
+/// never part of it and cannot be recovered from such a deployment.
+///
+/// Must run before any stable structure is touched.
+fn take_legacy_snapshot() -> Option<schema::LegacySnapshot> {
+    if ic_cdk::api::stable::stable_size() == 0 {
+        return None;
+    }
+
+    let mut magic = [0u8; 3];
+    ic_cdk::api::stable::stable_read(0, &mut magic);
+    if &magic == MEMORY_MANAGER_MAGIC {
+        return None;
+    }
+
+    match ic_cdk::storage::stable_restore::<schema::LegacySnapshot>() {
+        Ok(snapshot) => Some(snapshot),
+        Err(e) => {
+            ic_cdk::println!("WARNING: Failed to restore legacy snapshot after upgrade: {}", e);
+            None
+        }
+    }
+}
+
+fn set_schema_version(version: u32) {
+    SCHEMA_VERSION.with(|v| v.borrow_mut().set(version))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to store schema version: {:?}", e)));
+}
+
+fn store_integrity_report(report: IntegrityReport) {
+    INTEGRITY_REPORT.with(|r| r.borrow_mut().set(report))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to store integrity report: {:?}", e)));
+}
+
+/// Cross-checks the stable maps against each other. Only the first
+/// `MAX_INTEGRITY_ISSUES` problems are listed.
+fn check_integrity(migrations: Vec<schema::MigrationStep>) -> IntegrityReport {
+    let mut issues = Vec::new();
+    let mut report_issue = |issue: String| {
+        if issues.len() < MAX_INTEGRITY_ISSUES {
+            issues.push(issue);
+        }
+    };
+
+    let (submissions, max_submission_id) = SUBMISSIONS.with(|s| {
+        let s = s.borrow();
+        for (key, sub) in s.iter() {
+            if sub.data_id != key {
+                report_issue(format!("Submission {} is stored under key {}.", sub.data_id, key));
+            }
+            if !USERS.with(|u| u.borrow().contains_key(&sub.user)) {
+                report_issue(format!("Submission {} belongs to unknown user {}.", key, sub.user));
+            }
+            let indexed = (!is_indexable_user(&sub.user)
+                    || SUBMISSIONS_BY_USER.with(|i| i.borrow().contains_key(&UserDataKey { user: sub.user.clone(), data_id: key })))
+                && SUBMISSIONS_BY_CITY.with(|i| i.borrow().contains_key(&city_key(&sub.data.city, key)))
+                && SUBMISSIONS_BY_STATUS.with(|i| i.borrow().contains_key(&StatusKey::new(&sub.status, key)))
+                && sub.challenge_id.is_none_or(|challenge_id| {
+                    SUBMISSIONS_BY_CHALLENGE.with(|i| i.borrow().contains_key(&ChallengeKey { challenge_id, data_id: key }))
+                });
+            if !indexed {
+                report_issue(format!("Submission {} is missing from a secondary index.", key));
+            }
+            if let Some(challenge_id) = sub.challenge_id {
+                if !CHALLENGES.with(|c| c.borrow().contains_key(&challenge_id)) {
+                    report_issue(format!("Submission {} is linked to unknown challenge {}.", key, challenge_id));
+                }
+            }
+        }
+        (s.len(), s.last_key_value().map_or(0, |(k, _)| k))
+    });
+
+    // A submission has at most one entry per index, so larger indexes hold
+    // stale entries.
+    let index_sizes = [
+        ("user", SUBMISSIONS_BY_USER.with(|i| i.borrow().len())),
+        ("city", SUBMISSIONS_BY_CITY.with(|i| i.borrow().len())),
+        ("status", SUBMISSIONS_BY_STATUS.with(|i| i.borrow().len())),
+        ("location", SUBMISSION_CELLS.with(|i| i.borrow().len())),
+        ("challenge", SUBMISSIONS_BY_CHALLENGE.with(|i| i.borrow().len())),
+    ];
+    for (index, len) in index_sizes {
+        if len > submissions {
+            report_issue(format!("The {} index has {} entries for {} submissions.", index, len, submissions));
+        }
+    }
+
+    let (challenges, max_challenge_id) = CHALLENGES.with(|c| {
+        let c = c.borrow();
+        for (key, challenge) in c.iter() {
+            if challenge.id != key {
+                report_issue(format!("Challenge {} is stored under key {}.", challenge.id, key));
+            }
+            if challenge.has_boundary && !CHALLENGE_BOUNDARIES.with(|b| b.borrow().contains_key(&key)) {
+                report_issue(format!("Challenge {} has no stored boundary.", key));
+            }
+        }
+        (c.len(), c.last_key_value().map_or(0, |(k, _)| k))
+    });
+
+    // Reserved and paid amounts of each escrow, recomputed from its payouts.
+    let mut escrow_payouts: HashMap<u64, (u64, u64)> = HashMap::new();
+    PAYOUTS.with(|p| {
+        for (_, payout) in p.borrow().iter() {
+            let Some(escrow) = payout.escrow_challenge_id.and_then(escrow_of) else {
+                continue;
+            };
+            let cost = escrow.payout_cost(payout.amount, payout.fee);
+            let (reserved, paid) = escrow_payouts.entry(escrow.challenge_id).or_default();
+            if holds_payout_funds(&payout) {
+                *reserved += cost;
+            } else if let PayoutState::Paid { .. } = payout.state {
+                *paid += cost;
+            }
+        }
+    });
+    CHALLENGE_ESCROWS.with(|e| {
+        for (challenge_id, escrow) in e.borrow().iter() {
+            if !CHALLENGES.with(|c| c.borrow().contains_key(&challenge_id)) {
+                report_issue(format!("Escrow of unknown challenge {}.", challenge_id));
+            }
+            if escrow.reserved.saturating_add(escrow.paid) > escrow.deposited {
+                report_issue(format!("Escrow of challenge {} spent more than was deposited.", challenge_id));
+            }
+            let (reserved, paid) = escrow_payouts.get(&challenge_id).copied().unwrap_or_default();
+            if escrow.reserved != reserved || escrow.paid != paid {
+                report_issue(format!(
+                    "Escrow of challenge {} records {} reserved and {} paid, its payouts add up to {} and {}.",
+                    challenge_id, escrow.reserved, escrow.paid, reserved, paid
+                ));
+            }
+        }
+    });
+
+    let votes = VOTES.with(|v| {
+        let v = v.borrow();
+        for (key, vote) in v.iter() {
+            if !SUBMISSIONS.with(|s| s.borrow().contains_key(&key.data_id)) {
+                report_issue(format!("Vote of {} is on unknown submission {}.", key.user, key.data_id));
+            }
+            if vote.user != key.user || vote.data_id != key.data_id {
+                report_issue(format!("Vote stored under ({}, {}) does not match its key.", key.data_id, key.user));
+            }
+            let index_key = UserDataKey { user: key.user.clone(), data_id: key.data_id };
+            if !USER_VOTES.with(|i| i.borrow().contains_key(&index_key)) {
+                report_issue(format!("Vote of {} on {} is missing from the user index.", key.user, key.data_id));
+            }
+        }
+        v.len()
+    });
+
+    USER_VOTES.with(|i| {
+        for (key, _) in i.borrow().iter() {
+            let vote_key = VoteKey { data_id: key.data_id, user: key.user.clone() };
+            if !VOTES.with(|v| v.borrow().contains_key(&vote_key)) {
+                report_issue(format!("User index lists a missing vote of {} on {}.", key.user, key.data_id));
+            }
+        }
+    });
+
+    PRINCIPAL_USERS.with(|p| {
+        for (principal, user_id) in p.borrow().iter() {
+            if !USERS.with(|u| u.borrow().contains_key(&user_id)) {
+                report_issue(format!("Principal {} is linked to unknown user {}.", principal, user_id));
+            }
+        }
+    });
+
+    let payouts = PAYOUTS.with(|p| {
+        let p = p.borrow();
+        for (data_id, _) in p.iter() {
+            if !SUBMISSIONS.with(|s| s.borrow().contains_key(&data_id)) {
+                report_issue(format!("Payout exists for unknown submission {}.", data_id));
+            }
+        }
+        p.len()
+    });
+
+    let counters = ID_COUNTERS.with(|c| c.borrow().get().clone());
+    if counters.last_submission_id < max_submission_id || counters.last_challenge_id < max_challenge_id {
+        report_issue("Id counters are behind the highest stored ids.".to_string());
+    }
+
+    IntegrityReport {
+        schema_version: SCHEMA_VERSION.with(|v| *v.borrow().get()),
+        checked_at: time(),
+        migrations,
+        submissions,
+        users: USERS.with(|u| u.borrow().len()),
+        challenges,
+        votes,
+        payouts,
+        issues,
+    }
+}
+
+/// Report of the integrity check run by the last install or upgrade.
+#[query]
+#[candid_method(query)]
+fn get_integrity_report() -> IntegrityReport {
+    INTEGRITY_REPORT.with(|r| r.borrow().get().clone())
+}
+
+// -------- Submission storage --------
+// Every write to `SUBMISSIONS` goes through `store_submission`, which updates
+// the secondary indexes in the same call, so they cannot drift apart.
+
+/// Lowercased, trimmed city with inner whitespace collapsed, as used by the
+/// city index.
+fn normalize_city(city: &str) -> String {
+    city.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+fn city_key(city: &str, data_id: u64) -> CityKey {
+    let mut city = normalize_city(city);
+    let mut end = city.len().min(MAX_CITY_KEY_BYTES as usize);
+    while !city.is_char_boundary(end) {
+        end -= 1;
+    }
+    city.truncate(end);
+    CityKey { city, data_id }
+}
+
+/// Whether `user_id` fits the per-user index keys.
+fn is_indexable_user(user_id: &str) -> bool {
+    user_id.len() <= MAX_USER_ID_BYTES as usize
+}
+
+fn add_index_entries(submission: &UserSubmission) {
+    let data_id = submission.data_id;
+    if is_indexable_user(&submission.user) {
+        SUBMISSIONS_BY_USER.with(|i| i.borrow_mut().insert(UserDataKey { user: submission.user.clone(), data_id }, ()));
+    } else {
+        ic_cdk::println!("WARNING: User id of submission {} is too long to index.", data_id);
+    }
+    SUBMISSIONS_BY_CITY.with(|i| i.borrow_mut().insert(city_key(&submission.data.city, data_id), ()));
+    SUBMISSIONS_BY_STATUS.with(|i| i.borrow_mut().insert(StatusKey::new(&submission.status, data_id), ()));
+    SUBMISSION_CELLS.with(|i| i.borrow_mut().insert(submission_cell(submission), ()));
+    if let Some(challenge_id) = submission.challenge_id {
+        SUBMISSIONS_BY_CHALLENGE.with(|i| i.borrow_mut().insert(ChallengeKey { challenge_id, data_id }, ()));
+    }
+}
+
+fn remove_index_entries(submission: &UserSubmission) {
+    let data_id = submission.data_id;
+    SUBMISSIONS_BY_USER.with(|i| i.borrow_mut().remove(&UserDataKey { user: submission.user.clone(), data_id }));
+    SUBMISSIONS_BY_CITY.with(|i| i.borrow_mut().remove(&city_key(&submission.data.city, data_id)));
+    SUBMISSIONS_BY_STATUS.with(|i| i.borrow_mut().remove(&StatusKey::new(&submission.status, data_id)));
+    SUBMISSION_CELLS.with(|i| i.borrow_mut().remove(&submission_cell(submission)));
+    if let Some(challenge_id) = submission.challenge_id {
+        SUBMISSIONS_BY_CHALLENGE.with(|i| i.borrow_mut().remove(&ChallengeKey { challenge_id, data_id }));
+    }
+}
+
+fn store_submission(submission: &UserSubmission) {
+    let previous = SUBMISSIONS.with(|s| s.borrow_mut().insert(submission.data_id, submission.clone()));
+    if let Some(previous) = previous {
+        remove_index_entries(&previous);
+    }
+    add_index_entries(submission);
+}
+
+/// Applies `f` to a stored submission and writes it back.
+fn update_submission<R>(data_id: u64, f: impl FnOnce(&mut UserSubmission) -> R) -> Option<R> {
+    let mut submission = SUBMISSIONS.with(|s| s.borrow().get(&data_id))?;
+    let result = f(&mut submission);
+    store_submission(&submission);
+    Some(result)
+}
+
+/// Ids of the submissions of `user_id`, in id order.
+fn submission_ids_of_user(user_id: &str) -> Vec<u64> {
+    if !is_indexable_user(user_id) {
+        return Vec::new();
+    }
+    SUBMISSIONS_BY_USER.with(|i| {
+        i.borrow()
+            .range(UserDataKey { user: user_id.to_string(), data_id: 0 }..)
+            .take_while(|(key, _)| key.user == user_id)
+            .map(|(key, _)| key.data_id)
+            .collect()
+    })
+}
+
+/// Ids of the submissions linked to `challenge_id`, in id order.
+fn submission_ids_of_challenge(challenge_id: u64) -> Vec<u64> {
+    SUBMISSIONS_BY_CHALLENGE.with(|i| {
+        i.borrow()
+            .range(ChallengeKey { challenge_id, data_id: 0 }..)
+            .take_while(|(key, _)| key.challenge_id == challenge_id)
+            .map(|(key, _)| key.data_id)
+            .collect()
+    })
+}
+
+/// The challenge a post made before submissions recorded their challenge
+/// belonged to: one whose geofence contains the post and whose window covers
+/// its time, the one ending first if several do.
+fn infer_challenge_id(submission: &UserSubmission) -> Option<u64> {
+    let (lat, lon, at) = (submission.data.latitude, submission.data.longitude, submission.data.timestamp);
+    let cells = geo::cover(&geo::BoundingBox::around(lat, lon, 0.0));
+    let ids = CHALLENGE_CELLS.with(|i| ids_in_cells(&i.borrow(), &cells));
+    ids.into_iter()
+        .filter_map(|id| CHALLENGES.with(|c| c.borrow().get(&id)))
+        .filter(|ch| ch.start_time <= at && at <= ch.expiration && challenge_contains(ch, lat, lon))
+        .min_by_key(|ch| (ch.expiration, ch.id))
+        .map(|ch| ch.id)
+}
+
+/// Ids of the submissions with `status`, in id order.
+fn submission_ids_with_status(status: &PostStatus) -> Vec<u64> {
+    let start = StatusKey::new(status, 0);
+    SUBMISSIONS_BY_STATUS.with(|i| {
+        i.borrow()
+            .range(start.clone()..)
+            .take_while(|(key, _)| key.status == start.status)
+            .map(|(key, _)| key.data_id)
+            .collect()
+    })
+}
+
+fn submissions_by_ids(data_ids: Vec<u64>) -> Vec<UserSubmission> {
+    SUBMISSIONS.with(|s| {
+        let s = s.borrow();
+        data_ids.into_iter().filter_map(|data_id| s.get(&data_id)).collect()
+    })
+}
+
+// -------- Spatial index --------
+// Candidates come from the geohash cells a query area overlaps; callers still
+// apply the exact geometry check.
+
+fn submission_cell(submission: &UserSubmission) -> GeoKey {
+    GeoKey {
+        cell: geo::encode(submission.data.latitude, submission.data.longitude, geo::MAX_PRECISION),
+        id: submission.data_id,
+    }
+}
+
+/// Boxes enclosing the geofence of a challenge.
+fn challenge_boxes(challenge: &Challenge) -> Vec<geo::BoundingBox> {
+    match challenge_boundary(challenge) {
+        Some(boundary) => vec![boundary.bounding_box()],
+        None => geo::BoundingBox::around(challenge.latitude, challenge.longitude, challenge.radius_m),
+    }
+}
+
+fn challenge_cells(challenge: &Challenge) -> Vec<GeoKey> {
+    geo::cover(&challenge_boxes(challenge))
+        .into_iter()
+        .map(|cell| GeoKey { cell, id: challenge.id })
+        .collect()
+}
+
+fn index_challenge(challenge: &Challenge) {
+    CHALLENGE_CELLS.with(|i| {
+        let mut i = i.borrow_mut();
+        for key in challenge_cells(challenge) {
+            i.insert(key, ());
+        }
+    });
+}
+
+/// Ids stored under any cell overlapping one of `cells`: the cells that are
+/// a prefix of a query cell, and those the query cell is a prefix of.
+fn ids_in_cells(index: &StableBTreeMap<GeoKey, (), VirtualMemory<DefaultMemoryImpl>>, cells: &[String]) -> BTreeSet<u64> {
+    let mut ids = BTreeSet::new();
+    for cell in cells {
+        for len in 1..cell.len() {
+            let prefix = &cell[..len];
+            ids.extend(
+                index.range(GeoKey { cell: prefix.to_string(), id: 0 }..)
+                    .take_while(|(key, _)| key.cell == prefix)
+                    .map(|(key, _)| key.id),
+            );
+        }
+        ids.extend(
+            index.range(GeoKey { cell: cell.clone(), id: 0 }..)
+                .take_while(|(key, _)| key.cell.starts_with(cell.as_str()))
+                .map(|(key, _)| key.id),
+        );
+    }
+    ids
+}
+
+/// Challenges whose geofence may contain the point.
+fn challenge_candidates_at(lat: f64, lon: f64) -> Vec<Challenge> {
+    let cells = [geo::encode(lat, lon, geo::MAX_PRECISION)];
+    let ids = CHALLENGE_CELLS.with(|i| ids_in_cells(&i.borrow(), &cells));
+    CHALLENGES.with(|c| {
+        let c = c.borrow();
+        ids.into_iter().filter_map(|id| c.get(&id)).collect()
+    })
+}
+
+/// Submissions inside `boxes` accepted by `filter`, newest first and at most
+/// `MAX_SPATIAL_RESULTS`.
+fn submissions_in_boxes(boxes: &[geo::BoundingBox], filter: impl Fn(&UserSubmission) -> bool) -> Vec<UserSubmission> {
+    let ids = SUBMISSION_CELLS.with(|i| ids_in_cells(&i.borrow(), &geo::cover(boxes)));
+    SUBMISSIONS.with(|s| {
+        let s = s.borrow();
+        ids.into_iter()
+            .rev()
+            .filter_map(|id| s.get(&id))
+            .filter(|sub| boxes.iter().any(|b| b.contains(sub.data.latitude, sub.data.longitude)) && filter(sub))
+            .take(MAX_SPATIAL_RESULTS)
+            .collect()
+    })
+}
+
+/// Submissions inside the box; `min_lon > max_lon` crosses the antimeridian.
+/// Newest first, at most `MAX_SPATIAL_RESULTS`.
+#[query]
+#[candid_method(query)]
+fn get_submissions_in_bbox(min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> Result<Vec<UserSubmission>, DaoError> {
+    let limits = validation_limits();
+    Validator::new(&limits)
+        .range("min_lat", min_lat, -90.0, 90.0)
+        .range("min_lon", min_lon, -180.0, 180.0)
+        .range("max_lat", max_lat, min_lat.max(-90.0), 90.0)
+        .range("max_lon", max_lon, -180.0, 180.0)
+        .finish()
+        .map_err(DaoError::Validation)?;
+
+    Ok(submissions_in_boxes(&geo::BoundingBox::split(min_lat, min_lon, max_lat, max_lon), |_| true))
+}
+
+/// Submissions within `radius_m` of the point, optionally limited to a time
+/// range. Newest first, at most `MAX_SPATIAL_RESULTS`.
+#[query]
+#[candid_method(query)]
+fn get_submissions_near(lat: f64, lon: f64, radius_m: f64, time_range: Option<TimeRange>) -> Result<Vec<UserSubmission>, DaoError> {
+    let limits = validation_limits();
+    Validator::new(&limits)
+        .range("lat", lat, -90.0, 90.0)
+        .range("lon", lon, -180.0, 180.0)
+        .radius("radius_m", radius_m)
+        .finish()
+        .map_err(DaoError::Validation)?;
+
+    Ok(submissions_in_boxes(&geo::BoundingBox::around(lat, lon, radius_m), |sub| {
+        haversine_distance(lat, lon, sub.data.latitude, sub.data.longitude) <= radius_m
+            && time_range.as_ref().is_none_or(|r| r.start <= sub.data.timestamp && sub.data.timestamp < r.end)
+    }))
+}
+
+// -------- Id allocation --------
+
+fn update_id_counters<R>(f: impl FnOnce(&mut IdCounters) -> R) -> R {
+    ID_COUNTERS.with(|c| {
+        let mut cell = c.borrow_mut();
+        let mut counters = cell.get().clone();
+        let result = f(&mut counters);
+        cell.set(counters).expect("Failed to store id counters");
+        result
+    })
+}
+
+fn next_submission_id() -> u64 {
+    update_id_counters(|c| {
+        c.last_submission_id += 1;
+        c.last_submission_id
+    })
+}
+
+fn next_challenge_id() -> u64 {
+    update_id_counters(|c| {
+        c.last_challenge_id += 1;
+        c.last_challenge_id
+    })
+}
+
+fn next_balance_entry_id() -> u64 {
+    update_id_counters(|c| {
+        c.last_balance_entry_id += 1;
+        c.last_balance_entry_id
+    })
+}
+
+fn next_claim_id() -> u64 {
+    update_id_counters(|c| {
+        c.last_claim_id += 1;
+        c.last_claim_id
+    })
+}
+
+/// Moves the counters past the highest stored keys, e.g. after a restore or
+/// on the first upgrade of a deployment that used `len() + 1` ids.
+fn sync_id_counters() {
+    let max_submission_id = SUBMISSIONS.with(|s| s.borrow().last_key_value().map_or(0, |(k, _)| k));
+    let max_challenge_id = CHALLENGES.with(|c| c.borrow().last_key_value().map_or(0, |(k, _)| k));
+
+    update_id_counters(|c| {
+        if c.last_submission_id < max_submission_id {
+            ic_cdk::println!("INFO: Bumping submission id counter from {} to {}", c.last_submission_id, max_submission_id);
+            c.last_submission_id = max_submission_id;
+        }
+        if c.last_challenge_id < max_challenge_id {
+            ic_cdk::println!("INFO: Bumping challenge id counter from {} to {}", c.last_challenge_id, max_challenge_id);
+            c.last_challenge_id = max_challenge_id;
+        }
+    });
+}
+
+// -------- Configuration --------
+
+fn config() -> DaoConfig {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+fn validate_config(config: &DaoConfig) -> Result<(), DaoError> {
+    let invalid = |msg: &str| Err(DaoError::InvalidInput(msg.to_string()));
+    if config.reward_amount == 0 {
+        return invalid("reward_amount must be positive.");
+    }
+    if config.voter_reward_bps >= 10_000 {
+        return invalid("voter_reward_bps must be below 10000.");
+    }
+    if config.fee_included_in_reward {
+        match config.transfer_fee {
+            Some(fee) if fee < config.submitter_reward() => {}
+            Some(_) => return invalid("transfer_fee must be below the submitter's part of reward_amount when it is included in the reward."),
+            None => return invalid("transfer_fee must be set when it is included in the reward."),
+        }
+    }
+    if config.voting_window_secs == 0 || config.challenge_voting_window_secs == 0 {
+        return invalid("Voting windows must be positive.");
+    }
+    if config.voting_window_secs > MAX_VOTING_WINDOW_SECS || config.challenge_voting_window_secs > MAX_VOTING_WINDOW_SECS {
+        return Err(DaoError::InvalidInput(format!("Voting windows must be at most {} seconds.", MAX_VOTING_WINDOW_SECS)));
+    }
+    if config.approval_threshold_bps >= 10_000 {
+        return invalid("approval_threshold_bps must be below 10000.");
+    }
+    Ok(())
+}
+
+fn store_config(config: DaoConfig) -> Result<(), DaoError> {
+    validate_config(&config)?;
+    CONFIG.with(|c| c.borrow_mut().set(config))
+        .map(|_| ())
+        .map_err(|e| DaoError::InvalidInput(format!("Failed to store config: {:?}", e)))
+}
+
+/// The status a post with `tally` ends in: `NoQuorum` below the quorum,
+/// `PENDING` if enough valid votes exceed the approval threshold, otherwise
+/// `EXPIRED`.
+fn decide_outcome(tally: &VoteTally, config: &DaoConfig) -> PostStatus {
+    if tally.total() < config.quorum {
+        return PostStatus::NoQuorum;
+    }
+    let weighted_total = tally.weighted_valid + tally.weighted_invalid;
+    let approved = tally.valid >= config.min_approvals
+        && tally.weighted_valid * 10_000 > weighted_total * config.approval_threshold_bps as u64;
+    if approved {
+        PostStatus::PENDING
+    } else {
+        PostStatus::EXPIRED
+    }
+}
+
+#[update]
+#[candid_method(update)]
+fn update_config(config: DaoConfig) -> Result<String, DaoError> {
+    require_admin()?;
+    store_config(config)?;
+    Ok("Config updated".to_string())
+}
+
+#[query]
+#[candid_method(query)]
+fn get_config() -> DaoConfig {
+    config()
+}
+
+// -------- Validation --------
+
+fn validation_limits() -> ValidationLimits {
+    VALIDATION_LIMITS.with(|l| l.borrow().get().clone())
+}
+
+/// Fields outside `required` may be left empty.
+fn validate_weather_data(user_id: &str, data: &WeatherData, required: &[WeatherField]) -> Result<(), DaoError> {
+    let limits = validation_limits();
+    Validator::new(&limits)
+        .text("telegram_id", user_id, MAX_USER_ID_BYTES)
+        .latitude("latitude", data.latitude)
+        .longitude("longitude", data.longitude)
+        .name_if(required.contains(&WeatherField::City), "city", &data.city)
+        .temperature("temperature", data.temperature)
+        .name_if(required.contains(&WeatherField::Weather), "weather", &data.weather)
+        .url_if(required.contains(&WeatherField::Photo), "submission_photo_url", &data.submission_photo_url)
+        .finish()
+        .map_err(DaoError::Validation)
+}
+
+fn validate_profile(profile: &User) -> Result<(), DaoError> {
+    let limits = validation_limits();
+    Validator::new(&limits)
+        .text("telegram_id", &profile.user_id, MAX_USER_ID_BYTES)
+        .optional_name("first_name", profile.first_name.as_deref())
+        .optional_name("last_name", profile.last_name.as_deref())
+        .optional_name("username", profile.username.as_deref())
+        .optional_name("language_code", profile.language_code.as_deref())
+        .optional_url("profile_picture_url", profile.profile_picture_url.as_deref())
+        .finish()
+        .map_err(DaoError::Validation)
+}
+
+/// Also rejects challenges whose encoding would not fit the bounded
+/// `CHALLENGES` map, so `to_bytes` can no longer trap on insert.
+fn validate_challenge(challenge: &Challenge, now: u64) -> Result<(), DaoError> {
+    let limits = validation_limits();
+    let encoded_len = serde_cbor::to_vec(challenge).map_or(usize::MAX, |bytes| bytes.len());
+    Validator::new(&limits)
+        .title("title", &challenge.title)
+        .latitude("latitude", challenge.latitude)
+        .longitude("longitude", challenge.longitude)
+        .radius("radius_m", challenge.radius_m)
+        .check("end_time", challenge.expiration > challenge.start_time, ValidationIssue::NotAfter { other: "start_time".to_string() })
+        .check("end_time", challenge.expiration > now, ValidationIssue::NotAfter { other: "now".to_string() })
+        .check("max_accepted", challenge.max_accepted != Some(0), ValidationIssue::NotPositive)
+        .url("picture_url", &challenge.picture_url)
+        .check(
+            "challenge",
+            encoded_len <= MAX_CHALLENGE_BYTES as usize,
+            ValidationIssue::TooLong { max_bytes: MAX_CHALLENGE_BYTES },
+        )
+        .finish()
+        .map_err(DaoError::Validation)
+}
+
+#[update]
+#[candid_method(update)]
+fn set_validation_limits(limits: ValidationLimits) -> Result<String, DaoError> {
+    require_admin()?;
+    limits.check().map_err(DaoError::InvalidInput)?;
+    VALIDATION_LIMITS.with(|l| l.borrow_mut().set(limits))
+        .map_err(|e| DaoError::InvalidInput(format!("Failed to store validation limits: {:?}", e)))?;
+    Ok("Validation limits updated".to_string())
+}
+
+#[query]
+#[candid_method(query)]
+fn get_validation_limits() -> ValidationLimits {
+    validation_limits()
+}
+
+// -------- Rate limiting --------
+
+fn rate_limits() -> RateLimits {
+    RATE_LIMITS.with(|l| l.borrow().get().clone())
+}
+
+/// The user's activity with entries no quota or duplicate window needs dropped.
+fn recent_activity(user_id: &str, limits: &RateLimits, now: u64) -> UserActivity {
+    let mut activity = USER_ACTIVITY.with(|a| a.borrow().get(&user_id.to_string())).unwrap_or_default();
+    let submission_horizon = now.saturating_sub(DAY_NS.max(secs_to_ns(limits.duplicate_window_secs)));
+    activity.submissions.retain(|s| s.timestamp > submission_horizon);
+    activity.vote_times.retain(|&t| t > now.saturating_sub(HOUR_NS));
+    activity
+}
+
+/// Fails with `RateLimited` when `limit` actions already happened in the
+/// window ending at `now`. `times` must be sorted.
+fn check_quota(times: &[u64], window_ns: u64, limit: u32, now: u64) -> Result<(), DaoError> {
+    if limit == 0 {
+        return Ok(());
+    }
+    let in_window: Vec<u64> = times.iter().copied().filter(|&t| t > now.saturating_sub(window_ns)).collect();
+    if in_window.len() < limit as usize {
+        return Ok(());
+    }
+    // The quota frees up once enough of the oldest actions leave the window.
+    let freed_at = in_window[in_window.len() - limit as usize] + window_ns;
+    Err(DaoError::RateLimited { retry_after_secs: freed_at.saturating_sub(now).div_ceil(1_000_000_000) })
+}
+
+/// Applies the submission quotas and returns the submission this one nearly
+/// duplicates, if any. Fails when duplicates are rejected.
+fn check_submission_allowed(user_id: &str, latitude: f64, longitude: f64, now: u64) -> Result<Option<u64>, DaoError> {
+    let limits = rate_limits();
+    let activity = recent_activity(user_id, &limits, now);
+    let times: Vec<u64> = activity.submissions.iter().map(|s| s.timestamp).collect();
+    check_quota(&times, HOUR_NS, limits.submissions_per_hour, now)?;
+    check_quota(&times, DAY_NS, limits.submissions_per_day, now)?;
+
+    if limits.duplicate_window_secs == 0 {
+        return Ok(None);
+    }
+    let window_start = now.saturating_sub(secs_to_ns(limits.duplicate_window_secs));
+    let duplicate_of = activity.submissions.iter()
+        .rev()
+        .filter(|s| s.timestamp > window_start)
+        .find(|s| haversine_distance(latitude, longitude, s.latitude, s.longitude) <= limits.duplicate_radius_m)
+        .map(|s| s.data_id);
+
+    match duplicate_of {
+        Some(data_id) if limits.reject_duplicates => Err(DaoError::DuplicateSubmission { data_id }),
+        Some(data_id) => {
+            ic_cdk::println!("INFO: Submission of {} nearly duplicates #{}", user_id, data_id);
+            Ok(Some(data_id))
+        }
+        None => Ok(None),
+    }
+}
+
+fn record_submission(submission: &UserSubmission) {
+    let limits = rate_limits();
+    let mut activity = recent_activity(&submission.user, &limits, submission.data.timestamp);
+    activity.submissions.push(RecentSubmission {
+        data_id: submission.data_id,
+        timestamp: submission.data.timestamp,
+        latitude: submission.data.latitude,
+        longitude: submission.data.longitude,
+    });
+    USER_ACTIVITY.with(|a| a.borrow_mut().insert(submission.user.clone(), activity));
+}
+
+fn check_vote_allowed(user_id: &str, now: u64) -> Result<(), DaoError> {
+    let limits = rate_limits();
+    let activity = recent_activity(user_id, &limits, now);
+    check_quota(&activity.vote_times, HOUR_NS, limits.votes_per_hour, now)
+}
+
+fn record_vote_activity(user_id: &str, now: u64) {
+    let limits = rate_limits();
+    let mut activity = recent_activity(user_id, &limits, now);
+    activity.vote_times.push(now);
+    USER_ACTIVITY.with(|a| a.borrow_mut().insert(user_id.to_string(), activity));
+}
+
+#[update]
+#[candid_method(update)]
+fn set_rate_limits(limits: RateLimits) -> Result<String, DaoError> {
+    require_admin()?;
+    if !(limits.duplicate_radius_m.is_finite() && limits.duplicate_radius_m >= 0.0) {
+        return Err(DaoError::InvalidInput("duplicate_radius_m must be a non-negative number.".to_string()));
+    }
+    if limits.duplicate_window_secs > MAX_DUPLICATE_WINDOW_SECS {
+        return Err(DaoError::InvalidInput(format!("duplicate_window_secs must be at most {}.", MAX_DUPLICATE_WINDOW_SECS)));
+    }
+    RATE_LIMITS.with(|l| l.borrow_mut().set(limits))
+        .map_err(|e| DaoError::InvalidInput(format!("Failed to store rate limits: {:?}", e)))?;
+    Ok("Rate limits updated".to_string())
+}
+
+#[query]
+#[candid_method(query)]
+fn get_rate_limits() -> RateLimits {
+    rate_limits()
+}
+
+// -------- Access control --------
+// Update calls come either from a user's own principal, which must be bound
+// to their Telegram user id, or from a trusted relayer (the Telegram bot
+// backend) acting on behalf of the user named in the arguments.
+
+fn caller_principal() -> Result<Principal, DaoError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(DaoError::Unauthorized("Anonymous callers are not allowed.".to_string()));
+    }
+    Ok(caller)
+}
+
+fn is_trusted_relayer(principal: &Principal) -> bool {
+    TRUSTED_RELAYERS.with(|r| r.borrow().contains_key(principal))
+}
+
+fn bound_user_id(principal: &Principal) -> Option<UserId> {
+    PRINCIPAL_USERS.with(|p| p.borrow().get(principal))
+}
+
+fn require_controller() -> Result<Principal, DaoError> {
+    let caller = caller_principal()?;
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err(DaoError::Unauthorized("Only canister controllers can call this method.".to_string()));
+    }
+    Ok(caller)
+}
+
+/// Controllers and trusted relayers may run operational methods such as payouts.
+fn require_privileged() -> Result<Principal, DaoError> {
+    let caller = caller_principal()?;
+    if !ic_cdk::api::is_controller(&caller) && !is_trusted_relayer(&caller) {
+        return Err(DaoError::Unauthorized("Only controllers or trusted relayers can call this method.".to_string()));
+    }
+    Ok(caller)
+}
+
+/// Whether `caller` is a controller or linked to a user with one of `roles`.
+fn has_role(caller: &Principal, roles: &[Role]) -> bool {
+    ic_cdk::api::is_controller(caller)
+        || bound_user_id(caller)
+            .and_then(|user_id| USERS.with(|u| u.borrow().get(&user_id)))
+            .is_some_and(|user| roles.contains(&user.role))
+}
+
+/// Controllers, or callers linked to a user with the `Admin` role.
+fn require_admin() -> Result<Principal, DaoError> {
+    let caller = caller_principal()?;
+    if !has_role(&caller, &[Role::Admin]) {
+        return Err(DaoError::Unauthorized("Only admins can call this method.".to_string()));
+    }
+    Ok(caller)
+}
+
+/// Controllers, or callers linked to a user with the `Admin` or `Moderator` role.
+fn require_moderator() -> Result<Principal, DaoError> {
+    let caller = caller_principal()?;
+    if !has_role(&caller, &[Role::Admin, Role::Moderator]) {
+        return Err(DaoError::Unauthorized("Only admins and moderators can call this method.".to_string()));
+    }
+    Ok(caller)
+}
+
+/// Checks that the caller may act as `user_id`.
+fn authorize_user(user_id: &str) -> Result<Principal, DaoError> {
+    let caller = caller_principal()?;
+    if is_trusted_relayer(&caller) {
+        return Ok(caller);
+    }
+    match bound_user_id(&caller) {
+        Some(bound) if bound == user_id => Ok(caller),
+        Some(_) => Err(DaoError::Unauthorized(format!("Caller is not authorized to act as user {}.", user_id))),
+        None => Err(DaoError::Unauthorized("Caller principal is not linked to any user.".to_string())),
+    }
+}
+
+#[update]
+#[candid_method(update)]
+fn add_trusted_relayer(relayer: Principal) -> Result<String, DaoError> {
+    require_controller()?;
+    if relayer == Principal::anonymous() {
+        return Err(DaoError::InvalidInput("The anonymous principal cannot be a relayer.".to_string()));
+    }
+    TRUSTED_RELAYERS.with(|r| r.borrow_mut().insert(relayer, ()));
+    Ok(format!("Added trusted relayer {}", relayer))
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_trusted_relayer(relayer: Principal) -> Result<String, DaoError> {
+    require_controller()?;
+    match TRUSTED_RELAYERS.with(|r| r.borrow_mut().remove(&relayer)) {
+        Some(()) => Ok(format!("Removed trusted relayer {}", relayer)),
+        None => Err(DaoError::NotFound(format!("{} is not a trusted relayer.", relayer))),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn get_trusted_relayers() -> Vec<Principal> {
+    TRUSTED_RELAYERS.with(|r| r.borrow().iter().map(|(p, _)| p).collect())
+}
+
+/// Links a principal to an existing Telegram user so that the user can call
+/// the canister directly. Only relayers, which have verified the Telegram
+/// identity, can create links.
+#[update]
+#[candid_method(update)]
+fn bind_principal(user_id: String, principal: Principal) -> Result<String, DaoError> {
+    let caller = caller_principal()?;
+    if !is_trusted_relayer(&caller) && !ic_cdk::api::is_controller(&caller) {
+        return Err(DaoError::Unauthorized("Only trusted relayers can link principals.".to_string()));
+    }
+    if principal == Principal::anonymous() {
+        return Err(DaoError::InvalidInput("The anonymous principal cannot be linked to a user.".to_string()));
+    }
+    if !USERS.with(|u| u.borrow().contains_key(&user_id)) {
+        return Err(DaoError::NotFound(format!("User {} not found.", user_id)));
+    }
+    PRINCIPAL_USERS.with(|p| p.borrow_mut().insert(principal, user_id.clone()));
+    Ok(format!("Linked {} to user {}", principal, user_id))
+}
+
+/// Removes a principal link. Relayers and controllers can remove any link;
+/// a user can remove their own principal.
+#[update]
+#[candid_method(update)]
+fn unbind_principal(principal: Principal) -> Result<String, DaoError> {
+    let caller = caller_principal()?;
+    if caller != principal && !is_trusted_relayer(&caller) && !ic_cdk::api::is_controller(&caller) {
+        return Err(DaoError::Unauthorized("Caller cannot unlink this principal.".to_string()));
+    }
+    match PRINCIPAL_USERS.with(|p| p.borrow_mut().remove(&principal)) {
+        Some(user_id) => Ok(format!("Unlinked {} from user {}", principal, user_id)),
+        None => Err(DaoError::NotFound(format!("{} is not linked to any user.", principal))),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn get_caller_user_id() -> Option<UserId> {
+    bound_user_id(&ic_cdk::caller())
+}
+
+// -------- Pagination --------
+
+/// Page size for a requested `limit`; zero or anything above the maximum
+/// gives a full page.
+fn page_size(limit: u32) -> usize {
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        MAX_PAGE_SIZE as usize
+    } else {
+        limit as usize
+    }
+}
+
+/// Walks `map` from just after `start_after`, keeping the entries `select`
+/// maps to an item, until a page is full or `MAX_PAGE_SCAN` entries were
+/// visited. Returns the page and the cursor of the next one, which may follow
+/// a short or empty page when the scan stopped first.
+fn page_of<K, V, M, T>(
+    map: &StableBTreeMap<K, V, M>,
+    start_after: Option<K>,
+    limit: u32,
+    mut select: impl FnMut(&K, V) -> Option<T>,
+) -> (Vec<T>, Option<K>)
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+    M: ic_stable_structures::Memory,
+{
+    use std::ops::Bound as RangeBound;
+
+    let size = page_size(limit);
+    let start = match start_after {
+        Some(key) => RangeBound::Excluded(key),
+        None => RangeBound::Unbounded,
+    };
+
+    let mut items = Vec::new();
+    let mut last_key = None;
+    let mut scanned = 0;
+    let mut entries = map.range((start, RangeBound::Unbounded));
+    for (key, value) in entries.by_ref() {
+        items.extend(select(&key, value));
+        last_key = Some(key);
+        scanned += 1;
+        if items.len() == size || scanned == MAX_PAGE_SCAN {
+            break;
+        }
+    }
+
+    let stopped = items.len() == size || scanned == MAX_PAGE_SCAN;
+    let next_cursor = if stopped && entries.next().is_some() { last_key } else { None };
+    (items, next_cursor)
+}
+
+fn submission_info(submission: &UserSubmission, user: Option<&User>) -> SubmissionInfo {
+    SubmissionInfo {
+        data_id: submission.data_id,
+        user_id: submission.user.clone(),
+        username: user.and_then(|u| u.username.clone()),
+        first_name: user.and_then(|u| u.first_name.clone()),
+        last_name: user.and_then(|u| u.last_name.clone()),
+        profile_picture_url: user.and_then(|u| u.profile_picture_url.clone()),
+        latitude: submission.data.latitude,
+        longitude: submission.data.longitude,
+        city: submission.data.city.clone(),
+        temperature: submission.data.temperature,
+        weather: submission.data.weather.clone(),
+        timestamp: submission.data.timestamp,
+        submission_photo_url: submission.data.submission_photo_url.clone(),
+        rewarded: submission.rewarded,
+        status: submission.status.clone(),
+    }
+}
+
+fn group_by_city(infos: Vec<SubmissionInfo>) -> Vec<(String, Vec<SubmissionInfo>)> {
+    let mut city_map: HashMap<String, Vec<SubmissionInfo>> = HashMap::new();
+    for info in infos {
+        city_map.entry(info.city.clone()).or_default().push(info);
+    }
+    city_map.into_iter().collect()
+}
+
+fn is_paid(submission: &UserSubmission) -> bool {
+    submission.rewarded || submission.status == PostStatus::PAID
+}
+
+/// A page of the city map over the submissions `filter` accepts.
+fn city_map_page(start_after: Option<u64>, limit: u32, filter: impl Fn(&UserSubmission) -> bool) -> CityMapPage {
+    let (infos, next_cursor) = SUBMISSIONS.with(|s| {
+        page_of(&s.borrow(), start_after, limit, |_, sub| {
+            filter(&sub).then(|| {
+                let user = USERS.with(|u| u.borrow().get(&sub.user));
+                submission_info(&sub, user.as_ref())
+            })
+        })
+    });
+    CityMapPage { items: group_by_city(infos), next_cursor }
+}
+
+// -------- User functions --------
+#[update]
+#[candid_method(update)]
+fn create_tg_user(telegram_id: String, first_name: String, last_name: String, username: String, language_code: String, is_bot: bool, profile_picture_url: String) -> Result<String, DaoError> {
+    require_privileged()?;
+
+    let profile = User {
+        user_id: telegram_id,
+        first_name: Some(first_name),
+        last_name: Some(last_name),
+        username: Some(username),
+        language_code: Some(language_code),
+        is_bot,
+        profile_picture_url: Some(profile_picture_url),
+        ..User::default()
+    };
+    validate_profile(&profile)?;
+
+    let (user, created) = upsert_tg_user(profile);
+    if created {
+        Ok(format!("Created new user: {}", user.user_id))
+    } else {
+        Ok(format!("Updated user: {}", user.user_id))
+    }
+}
+
+/// Creates or updates a user from the Telegram profile fields, keeping the
+/// balance, wallet and role of an existing user. Returns the stored user and
+/// whether it was newly created.
+fn upsert_tg_user(profile: User) -> (User, bool) {
+    USERS.with(|users_map| {
+        let mut users = users_map.borrow_mut();
+        let user_id = profile.user_id.clone();
+
+        if let Some(user) = users.get(&user_id) {
+            let mut updated_user = user;
+            ic_cdk::println!("👤 Existing user found: {}. Updating info.", user_id);
+            updated_user.first_name = profile.first_name;
+            updated_user.last_name = profile.last_name;
+            updated_user.username = profile.username;
+            updated_user.language_code = profile.language_code;
+            updated_user.is_bot = profile.is_bot;
+            updated_user.profile_picture_url = profile.profile_picture_url;
+            users.insert(user_id, updated_user.clone());
+            (updated_user, false)
+        } else {
+            let new_user = User {
+                balance: 0,
+                wallet_address: None,
+                role: Role::User,
+                ..profile
+            };
+            users.insert(user_id.clone(), new_user.clone());
+            ic_cdk::println!("Created new user: {}", user_id);
+            (new_user, true)
+        }
+    })
+}
+
+/// Creates or updates the user described by a Telegram Mini App `initData`
+/// string once its signature is verified, and links the caller's principal
+/// to that user.
+#[update]
+#[candid_method(update)]
+fn create_tg_user_with_init_data(init_data: String) -> Result<User, DaoError> {
+    let caller = caller_principal()?;
+
+    let bot_token = TELEGRAM_BOT_TOKEN.with(|t| t.borrow().get().clone());
+    if bot_token.is_empty() {
+        return Err(DaoError::InvalidState("Telegram bot token is not configured.".to_string()));
+    }
+
+    let now_secs = time() / 1_000_000_000;
+    let verified = telegram::verify_init_data(&init_data, &bot_token, now_secs, INIT_DATA_MAX_AGE_SECS)
+        .map_err(DaoError::Unauthorized)?;
+    let auth_date = verified.auth_date;
+    consume_init_data(auth_date, verified.hash, caller, now_secs)?;
+    let tg_user = verified.user;
+
+    let profile = User {
+        user_id: tg_user.id.to_string(),
+        first_name: Some(tg_user.first_name),
+        last_name: tg_user.last_name,
+        username: tg_user.username,
+        language_code: tg_user.language_code,
+        is_bot: tg_user.is_bot,
+        profile_picture_url: tg_user.photo_url,
+        ..User::default()
+    };
+    validate_profile(&profile)?;
+
+    let (user, _) = upsert_tg_user(profile);
+    PRINCIPAL_USERS.with(|p| p.borrow_mut().insert(caller, user.user_id.clone()));
+    ic_cdk::println!("Verified Telegram user {} (auth_date {}) for {}", user.user_id, auth_date, caller);
+    Ok(user)
+}
+
+/// Makes a verified `initData` single-use: only the principal that first
+/// presented it may present it again. Expired entries are dropped here, as
+/// `verify_init_data` rejects their `initData` anyway.
+fn consume_init_data(auth_date: u64, hash: [u8; 32], caller: Principal, now_secs: u64) -> Result<(), DaoError> {
+    CONSUMED_INIT_DATA.with(|c| {
+        let mut consumed = c.borrow_mut();
+        let oldest_valid = now_secs.saturating_sub(INIT_DATA_MAX_AGE_SECS);
+        let expired: Vec<InitDataKey> = consumed.range(..(oldest_valid, [0u8; 32])).map(|(key, _)| key).collect();
+        for key in expired {
+            consumed.remove(&key);
+        }
+
+        match consumed.get(&(auth_date, hash)) {
+            Some(owner) if owner != caller => {
+                Err(DaoError::Unauthorized("initData was already used by another principal.".to_string()))
+            }
+            Some(_) => Ok(()),
+            None => {
+                consumed.insert((auth_date, hash), caller);
+                Ok(())
+            }
+        }
+    })
+}
+
+#[update]
+#[candid_method(update)]
+fn set_telegram_bot_token(bot_token: String) -> Result<String, DaoError> {
+    require_controller()?;
+    TELEGRAM_BOT_TOKEN.with(|t| t.borrow_mut().set(bot_token))
+        .map_err(|e| DaoError::InvalidInput(format!("Failed to store bot token: {:?}", e)))?;
+    Ok("Telegram bot token updated".to_string())
+}
+
+#[query]
+#[candid_method(query)]
+fn get_tg_user(user_id: String) -> Result<User, DaoError> {
+    USERS.with(|users| {
+        let users = users.borrow();
+        match users.get(&user_id) {
+            Some(user) => Ok(user.clone()),
+            None => Err(DaoError::NotFound(format!("User {} not found.", user_id))),
+        }
+    })
+}
+
+#[update]
+#[candid_method(update)]
+fn update_wallet_address(user_id: String, wallet_address: String) -> Result<String, DaoError> {
+    authorize_user(&user_id)?;
+
+    if Principal::from_text(&wallet_address).is_err() {
+        return Err(DaoError::InvalidInput("Wallet address is not a valid principal.".to_string()));
+    }
+
+    USERS.with(|users| {
+        let mut users = users.borrow_mut();
+        match users.get(&user_id) {
+            Some(mut user) => {
+                user.wallet_address = Some(wallet_address);
+                users.insert(user_id.clone(), user);
+                Ok(format!("Wallet address updated for user: {}", user_id))
+            }
+            None => Err(DaoError::NotFound(format!("User {} not found", user_id))),
+        }
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_all_users() -> Vec<User> {
+    USERS.with(|users_map| {
+        let users = users_map.borrow();
+        let all_users: Vec<User> = users.iter().map(|(_, user)| user.clone()).collect();
+        ic_cdk::println!("DEBUG: Returning all users, count: {}", all_users.len());
+        all_users
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_users_page(start_after: Option<UserId>, limit: u32) -> UserPage {
+    let (items, next_cursor) = USERS.with(|u| page_of(&u.borrow(), start_after, limit, |_, user| Some(user)));
+    UserPage { items, next_cursor }
+}
+
+#[query]
+fn get_balance(user_id: UserId) -> u64 {
+    USERS.with(|users| {
+        let users = users.borrow();
+
+        if users.is_empty() {
+            ic_cdk::println!("ERROR: USERS map is empty, returning 0 for {}", user_id);
+            return 0;
+        }
+
+        match users.get(&user_id) {
+            Some(user) => {
+                ic_cdk::println!("🔍 DEBUG: Final balance check for user {}: {}", user_id, user.balance);
+                user.balance
+            }
+            None => {
+                ic_cdk::println!("⚠️ WARNING: No balance found for user {}, returning 0", user_id);
+                0
+            }
+        }
+    })
+}
+
+// -------- Submission functions --------
+
+/// Voting window of a post, which is shorter for challenge posts.
+fn voting_window_ns(challenge_id: Option<u64>) -> u64 {
+    let config = config();
+    let secs = match challenge_id {
+        Some(_) => config.challenge_voting_window_secs,
+        None => config.voting_window_secs,
+    };
+    secs_to_ns(secs)
+}
+
+#[update]
+#[candid_method(update)]
+fn submit_weather_data(telegram_id: String, latitude: f64, longitude: f64, city: String, temperature: f64, weather: String, submission_photo_url: String) -> Result<u64, DaoError> {
+    authorize_user(&telegram_id)?;
+
+    let timestamp = time();
+    let expiration_timestamp = timestamp + voting_window_ns(None);
+    ic_cdk::println!("Received weather submission from {}", telegram_id);
+    ic_cdk::println!("Submission time (timestamp): {}", timestamp);
+    ic_cdk::println!("Expiration time (timestamp): {}", expiration_timestamp);
+
+    let data = WeatherData {
+        latitude,
+        longitude,
+        city,
+        temperature,
+        weather,
+        timestamp,
+        submission_photo_url,
+    };
+    validate_weather_data(&telegram_id, &data, &ALL_WEATHER_FIELDS)?;
+    let duplicate_of = check_submission_allowed(&telegram_id, latitude, longitude, timestamp)?;
+
+    let data_id = next_submission_id();
+
+    let new_data = UserSubmission {
+        data_id,
+        user: telegram_id.clone(),
+        data,
+        rewarded: false,
+        status: PostStatus::OPEN,
+        expiration_timestamp,
+        finalization: None,
+        duplicate_of,
+        challenge_id: None,
+        reward_credit: 0,
+        claimed: 0,
+        payout_block_index: None,
+    };
+
+    store_submission(&new_data);
+    ic_cdk::println!("SUBMISSIONS len after insert: {}", SUBMISSIONS.with(|s| s.borrow().len()));
+    record_submission(&new_data);
+
+    ic_cdk::println!("Inserted data #{}: {:?}", data_id, new_data);
+
+    Ok(data_id)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_all_submissions() -> Vec<UserSubmission> {
+    SUBMISSIONS.with(|submissions| {
+        let submissions = submissions.borrow();
+        let all_data: Vec<UserSubmission> = submissions.iter().map(|(_, v)| v.clone()).collect();
+        
+        ic_cdk::println!("DEBUG: Returning all submissions, count: {}", all_data.len());
+        all_data
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_submissions_page(start_after: Option<u64>, limit: u32) -> SubmissionPage {
+    let (items, next_cursor) = SUBMISSIONS.with(|s| page_of(&s.borrow(), start_after, limit, |_, sub| Some(sub)));
+    SubmissionPage { items, next_cursor }
+}
+
+#[query]
+#[candid_method(query)] 
+fn get_submission(data_id: u64) -> Result<UserSubmission, DaoError> {
+    SUBMISSIONS.with(|submissions| {
+        let submissions = submissions.borrow();
+        let total_count = submissions.len();
+        ic_cdk::println!("DEBUG: Total submissions stored: {}", total_count);
+
+        match submissions.get(&data_id) {
+            Some(sub) => {
+                ic_cdk::println!("DEBUG: Found submission with data_id: {}", data_id);
+                Ok(sub.clone()) 
+            }
+            None => {
+                ic_cdk::println!("ERROR: Submission not found for data_id: {} | Current total: {}", data_id, total_count);
+                Err(DaoError::NotFound(format!("Submission {} not found. Total records: {}", data_id, total_count)))
+            }
+        }
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_user_posts(user_id: String) -> Vec<UserSubmission> {
+    let user_posts = submissions_by_ids(submission_ids_of_user(&user_id));
+
+    ic_cdk::println!(
+        "DEBUG: Returning {} posts submitted by user {}",
+        user_posts.len(),
+        user_id
+    );
+
+    user_posts
+}
+
+/// Matches cities case-insensitively, ignoring extra whitespace.
+#[query]
+#[candid_method(query)]
+fn get_submissions_by_city(city: String) -> Vec<UserSubmission> {
+    let start = city_key(&city, 0);
+    let data_ids: Vec<u64> = SUBMISSIONS_BY_CITY.with(|i| {
+        i.borrow()
+            .range(start.clone()..)
+            .take_while(|(key, _)| key.city == start.city)
+            .map(|(key, _)| key.data_id)
+            .collect()
+    });
+
+    // Keys are truncated, so long names can share a key prefix.
+    let city = normalize_city(&city);
+    submissions_by_ids(data_ids)
+        .into_iter()
+        .filter(|sub| normalize_city(&sub.data.city) == city)
+        .collect()
+}
+
+#[query]
+#[candid_method(query)]
+fn get_rewarded_submissions(user_id: String) -> Vec<UserSubmission> {
+    submissions_by_ids(submission_ids_of_user(&user_id))
+        .into_iter()
+        .filter(|sub| sub.rewarded)
+        .collect()
+}
+
+#[query]
+#[candid_method(query)]
+fn get_user_submission_locations(user_id: String) -> Vec<SubmissionLocationInfo> {
+    submissions_by_ids(submission_ids_of_user(&user_id))
+        .into_iter()
+        .map(|sub| SubmissionLocationInfo {
+            data_id: sub.data_id,
+            latitude: sub.data.latitude,
+            longitude: sub.data.longitude,
+            status: sub.status,
+        })
+        .collect()
+}
+
+#[query]
+#[candid_method(query)]
+fn get_user_submission_summary(user_id: String) -> Vec<UserSubmissionSummary> {
+    submissions_by_ids(submission_ids_of_user(&user_id))
+        .into_iter()
+        .map(|sub| UserSubmissionSummary {
+            data_id: sub.data_id,
+            city: sub.data.city,
+            status: sub.status,
+        })
+        .collect()
+}
+
+#[query]
+#[candid_method(query)]
+fn get_submission_map_by_city() -> Vec<(String, Vec<SubmissionInfo>)> {
+    let submissions: Vec<_> = SUBMISSIONS.with(|s| s.borrow().iter().map(|(_, v)| v.clone()).collect());
+    let users: HashMap<_,_> = USERS.with(|u| u.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect());
+
+    let infos = submissions.iter()
+        .map(|submission| submission_info(submission, users.get(&submission.user)))
+        .collect();
+    group_by_city(infos)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_submission_map_by_city_page(start_after: Option<u64>, limit: u32) -> CityMapPage {
+    city_map_page(start_after, limit, |_| true)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_paid_submission_map_by_city() -> Vec<(String, Vec<SubmissionInfo>)> {
+    let submissions: Vec<_> = SUBMISSIONS.with(|s| s.borrow().iter().map(|(_, v)| v.clone()).collect());
+    let users: HashMap<_,_> = USERS.with(|u| u.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect());
+
+    let infos = submissions.iter()
+        .filter(|submission| is_paid(submission))
+        .map(|submission| submission_info(submission, users.get(&submission.user)))
+        .collect();
+    group_by_city(infos)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_paid_submission_map_by_city_page(start_after: Option<u64>, limit: u32) -> CityMapPage {
+    city_map_page(start_after, limit, is_paid)
+}
+
+// -------- Post status functions --------
+#[query]
+#[candid_method(query)]
+fn get_post_status(data_id: u64) -> Result<PostStatus, DaoError> {
+    SUBMISSIONS.with(|subs| {
+        let subs = subs.borrow();
+        match subs.get(&data_id) {
+            Some(sub) => Ok(sub.status),
+            None => Err(DaoError::NotFound(format!("Submission {} not found.", data_id))),
+        }
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_expiration_time(data_id: u64) -> Result<u64, DaoError> {
+    SUBMISSIONS.with(|subs| {
+        let subs = subs.borrow();
+        match subs.get(&data_id) {
+            Some(sub) => Ok(sub.expiration_timestamp),
+            None => Err(DaoError::NotFound(format!("Submission {} not found.", data_id))),
+        }
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_all_expiration_times() -> Vec<ExpirationInfo> {
+    SUBMISSIONS.with(|subs| {
+        subs.borrow()
+            .iter()
+            .map(|(id, sub)| ExpirationInfo {
+                data_id: id,
+                expiration_timestamp: sub.expiration_timestamp,
+            })
+            .collect()
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_expiration_times_page(start_after: Option<u64>, limit: u32) -> ExpirationPage {
+    let (items, next_cursor) = SUBMISSIONS.with(|s| {
+        page_of(&s.borrow(), start_after, limit, |&data_id, sub| {
+            Some(ExpirationInfo { data_id, expiration_timestamp: sub.expiration_timestamp })
+        })
+    });
+    ExpirationPage { items, next_cursor }
+}
+
+#[update]
+#[candid_method(update)]
+fn finalize_post_status(data_id: u64) -> Result<PostStatus, DaoError> {
+    finalize_submission(data_id)
+}
+
+/// Moves an expired `OPEN` post to `PENDING`, `EXPIRED` or `NoQuorum` based
+/// on its votes, recording the tally and rule on the submission.
+fn finalize_submission(data_id: u64) -> Result<PostStatus, DaoError> {
+    use PostStatus::*;
+
+    let mut sub = SUBMISSIONS.with(|subs| subs.borrow().get(&data_id))
+        .ok_or_else(|| DaoError::NotFound(format!("Submission {} not found.", data_id)))?;
+
+    let now = time();
+    if now < sub.expiration_timestamp {
+        return Err(DaoError::InvalidState("Post is still open. Not finalized.".to_string()));
+    }
+
+    if sub.status != OPEN {
+        return Err(DaoError::InvalidState(format!("Post already finalized with status {:?}", sub.status)));
+    }
+
+    let votes = votes_for_submission(data_id);
+    let tally = VoteTally::of(&votes);
+    let config = config();
+
+    sub.status = decide_outcome(&tally, &config);
+    let (rewarded_voters, voter_reward) = match sub.status {
+        PENDING | EXPIRED => {
+            let accepted = sub.status == PENDING;
+            update_reputations(&votes, accepted);
+            credit_voter_rewards(data_id, &votes, accepted, &config)
+        }
+        _ => (0, 0),
+    };
+    // Voters are judged on the votes alone; the cap only decides the status.
+    let capacity_reached = sub.status == PENDING && sub.challenge_id.is_some_and(challenge_is_full);
+    if capacity_reached {
+        sub.status = EXPIRED;
+    }
+    if sub.status == PENDING {
+        credit_submission_reward(&mut sub, &config);
+    }
+    // Paid before the votes were final.
+    if sub.status == PENDING && sub.rewarded {
+        sub.status = PAID;
+    }
+    sub.finalization = Some(FinalizationRecord {
+        tally,
+        quorum: config.quorum,
+        min_approvals: config.min_approvals,
+        approval_threshold_bps: config.approval_threshold_bps,
+        finalized_at: now,
+        capacity_reached,
+        rewarded_voters,
+        voter_reward,
+    });
+
+    store_submission(&sub);
+    Ok(sub.status)
+}
+
+// -------- Reputation functions --------
+
+fn reputation_of(user_id: &str) -> Reputation {
+    REPUTATIONS.with(|r| r.borrow().get(&user_id.to_string()))
+        .unwrap_or_else(|| Reputation::new(user_id.to_string()))
+}
+
+/// Rewards voters who sided with `accepted` and penalizes the others.
+fn update_reputations(votes: &[Vote], accepted: bool) {
+    REPUTATIONS.with(|r| {
+        let mut reputations = r.borrow_mut();
+        for vote in votes {
+            let mut reputation = reputations.get(&vote.user)
+                .unwrap_or_else(|| Reputation::new(vote.user.clone()));
+            if vote.vote_value == accepted {
+                reputation.correct_votes += 1;
+                reputation.score = (reputation.score + REPUTATION_CORRECT_GAIN).min(REPUTATION_MAX_SCORE);
+            } else {
+                reputation.incorrect_votes += 1;
+                reputation.score = reputation.score.saturating_sub(REPUTATION_INCORRECT_LOSS).max(REPUTATION_MIN_SCORE);
+            }
+            reputations.insert(vote.user.clone(), reputation);
+        }
+    });
+}
+
+#[query]
+#[candid_method(query)]
+fn get_reputation(user_id: String) -> Reputation {
+    reputation_of(&user_id)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_reputation_leaderboard() -> Vec<Reputation> {
+    let mut reputations: Vec<Reputation> = REPUTATIONS.with(|r| {
+        r.borrow().iter().map(|(_, reputation)| reputation).collect()
+    });
+
+    reputations.sort_by(|a, b| {
+        b.score.cmp(&a.score)
+            .then_with(|| b.correct_votes.cmp(&a.correct_votes))
+            .then_with(|| a.user_id.cmp(&b.user_id))
+    });
+
+    reputations.into_iter().take(10).collect()
+}
+
+// -------- Finalizer timer --------
+
+/// (Re)arms the finalizer timer from the stored settings. Timers do not
+/// survive upgrades, so this runs in both `init` and `post_upgrade`.
+fn schedule_finalizer() {
+    if let Some(timer_id) = FINALIZER_TIMER.with(|t| t.borrow_mut().take()) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+
+    let settings = FINALIZER_SETTINGS.with(|s| s.borrow().get().clone());
+    if !settings.enabled {
+        ic_cdk::println!("INFO: Finalizer timer disabled.");
+        return;
+    }
+
+    let interval = Duration::from_secs(settings.interval_secs.max(1));
+    let timer_id = ic_cdk_timers::set_timer_interval(interval, || {
+        ic_cdk::spawn(async {
+            let report = run_finalizer().await;
+            if !report.pending.is_empty() || !report.expired.is_empty() || !report.no_quorum.is_empty()
+                || !report.closed_challenges.is_empty()
+            {
+                ic_cdk::println!("INFO: Finalizer run: {:?}", report);
+            }
+        });
+    });
+    FINALIZER_TIMER.with(|t| *t.borrow_mut() = Some(timer_id));
+}
+
+/// Finalizes up to `batch_size` expired `OPEN` posts and, if enabled, pays
+/// out the ones that became `PENDING`. Overlapping runs are skipped.
+async fn run_finalizer() -> FinalizerReport {
+    let mut report = FinalizerReport::default();
+    if FINALIZER_RUNNING.with(|r| r.replace(true)) {
+        return report;
+    }
+    let _running = FinalizerRunningGuard;
+
+    let settings = FINALIZER_SETTINGS.with(|s| s.borrow().get().clone());
+    let now = time();
+    // Only OPEN posts are visited, through the status index, and only until
+    // the batch is full.
+    let start = StatusKey::new(&PostStatus::OPEN, 0);
+    let expired_ids: Vec<u64> = SUBMISSIONS_BY_STATUS.with(|i| {
+        i.borrow()
+            .range(start.clone()..)
+            .take_while(|(key, _)| key.status == start.status)
+            .map(|(key, _)| key.data_id)
+            .filter(|data_id| {
+                SUBMISSIONS.with(|subs| subs.borrow().get(data_id))
+                    .is_some_and(|sub| sub.expiration_timestamp <= now)
+            })
+            .take(settings.batch_size as usize)
+            .collect()
+    });
+
+    report.closed_challenges = close_ended_challenges(now);
+
+    for data_id in expired_ids {
+        match finalize_submission(data_id) {
+            Ok(PostStatus::PENDING) => report.pending.push(data_id),
+            Ok(PostStatus::NoQuorum) => report.no_quorum.push(data_id),
+            Ok(_) => report.expired.push(data_id),
+            Err(e) => ic_cdk::println!("WARNING: Could not finalize {}: {:?}", data_id, e),
+        }
+    }
+
+    if settings.auto_payout {
+        for data_id in report.pending.clone() {
+            match pay_reward(data_id).await {
+                Ok(_) => report.paid.push(data_id),
+                Err(e) => report.payout_errors.push((data_id, e)),
+            }
+        }
+    }
+
+    report
+}
+
+/// Clears `FINALIZER_RUNNING` when a run ends, including when a trap in a
+/// ledger callback drops the run's future.
+struct FinalizerRunningGuard;
+
+impl Drop for FinalizerRunningGuard {
+    fn drop(&mut self) {
+        FINALIZER_RUNNING.with(|r| r.set(false));
+    }
 }
 
 #[update]
-fn update_wallet_address(user_id: String, wallet_address: String) -> String {
-    USERS.with(|users| {
-        let mut users = users.borrow_mut();
-        match users.get(&user_id) {
-            Some(mut user) => {
-                user.wallet_address = Some(wallet_address);
-                users.insert(user_id.clone(), user);
-                format!("Wallet address updated for user: {}", user_id)
+#[candid_method(update)]
+async fn run_finalizer_now() -> Result<FinalizerReport, DaoError> {
+    require_privileged()?;
+    Ok(run_finalizer().await)
+}
+
+#[update]
+#[candid_method(update)]
+fn set_finalizer_settings(settings: FinalizerSettings) -> Result<String, DaoError> {
+    require_controller()?;
+    if settings.batch_size == 0 {
+        return Err(DaoError::InvalidInput("batch_size must be positive.".to_string()));
+    }
+    FINALIZER_SETTINGS.with(|s| s.borrow_mut().set(settings))
+        .map_err(|e| DaoError::InvalidInput(format!("Failed to store finalizer settings: {:?}", e)))?;
+    schedule_finalizer();
+    Ok("Finalizer settings updated".to_string())
+}
+
+#[query]
+#[candid_method(query)]
+fn get_finalizer_settings() -> FinalizerSettings {
+    FINALIZER_SETTINGS.with(|s| s.borrow().get().clone())
+}
+
+// -------- Vote functions --------
+
+fn votes_for_submission(data_id: u64) -> Vec<Vote> {
+    VOTES.with(|votes| {
+        votes.borrow()
+            .range(VoteKey { data_id, user: UserId::new() }..)
+            .take_while(|(key, _)| key.data_id == data_id)
+            .map(|(_, vote)| vote)
+            .collect()
+    })
+}
+
+fn insert_vote(vote: Vote) {
+    let key = VoteKey { data_id: vote.data_id, user: vote.user.clone() };
+    USER_VOTES.with(|index| {
+        index.borrow_mut().insert(UserDataKey { user: vote.user.clone(), data_id: vote.data_id }, ());
+    });
+    VOTES.with(|votes| {
+        votes.borrow_mut().insert(key, vote);
+    });
+}
+
+fn remove_vote(user_id: &str, data_id: u64) -> Option<Vote> {
+    USER_VOTES.with(|index| {
+        index.borrow_mut().remove(&UserDataKey { user: user_id.to_string(), data_id });
+    });
+    VOTES.with(|votes| {
+        votes.borrow_mut().remove(&VoteKey { data_id, user: user_id.to_string() })
+    })
+}
+
+fn vote_summaries() -> Vec<VoteSummary> {
+    VOTES.with(|votes| {
+        let mut summaries: Vec<VoteSummary> = Vec::new();
+        for (key, vote) in votes.borrow().iter() {
+            if summaries.last().map(|s| s.data_id) != Some(key.data_id) {
+                summaries.push(VoteSummary { data_id: key.data_id, upvotes: 0, downvotes: 0 });
+            }
+            let summary = summaries.last_mut().expect("summary was just pushed");
+            if vote.vote_value {
+                summary.upvotes += 1;
+            } else {
+                summary.downvotes += 1;
+            }
+        }
+        summaries
+    })
+}
+
+/// Checks that votes on `data_id` can still be cast or changed: the post
+/// must be `OPEN` and its voting window must not have ended.
+fn ensure_voting_open(data_id: u64) -> Result<UserSubmission, DaoError> {
+    let submission = SUBMISSIONS.with(|subs| subs.borrow().get(&data_id))
+        .ok_or_else(|| DaoError::NotFound(format!("Submission {} not found.", data_id)))?;
+
+    if submission.status != PostStatus::OPEN || time() >= submission.expiration_timestamp {
+        return Err(DaoError::VotingClosed);
+    }
+
+    Ok(submission)
+}
+
+fn check_voter(user_id: &str) -> Result<(), DaoError> {
+    authorize_user(user_id)?;
+    if user_id.len() > MAX_USER_ID_BYTES as usize {
+        return Err(DaoError::InvalidInput("User id is too long.".to_string()));
+    }
+    Ok(())
+}
+
+#[update]
+#[candid_method(update)]
+fn vote_on_data(user_id: String, data_id: u64, vote_value: bool) -> Result<String, DaoError> {
+    ic_cdk::println!("DEBUG: vote_on_data called with user_id: {}, data_id: {}, vote_value: {}", user_id, data_id, vote_value);
+
+    check_voter(&user_id)?;
+    let submission = ensure_voting_open(data_id)?;
+
+    if submission.user == user_id {
+        return Err(DaoError::SelfVote);
+    }
+
+    let already_voted = VOTES.with(|votes| {
+        votes.borrow().contains_key(&VoteKey { data_id, user: user_id.clone() })
+    });
+
+    if already_voted {
+        ic_cdk::println!("DEBUG: User {} already voted on submission {}", user_id, data_id);
+        return Err(DaoError::AlreadyVoted);
+    }
+
+    let now = time();
+    check_vote_allowed(&user_id, now)?;
+
+    let new_vote = Vote {
+        user: user_id.clone(),
+        data_id,
+        vote_value,
+        submission_id: data_id,
+    };
+    ic_cdk::println!("DEBUG: New vote created: {:?}", new_vote);
+
+    insert_vote(new_vote);
+    record_vote_activity(&user_id, now);
+    Ok(format!("User {} successfully voted on data {}.", user_id, data_id))
+}
+
+#[query]
+#[candid_method(query)]
+fn get_vote_summary(data_id: u64) -> VoteSummary {
+    let votes = votes_for_submission(data_id);
+
+    let upvotes = votes.iter().filter(|v| v.vote_value).count() as u32;
+    let downvotes = votes.len() as u32 - upvotes;
+
+    VoteSummary {
+        data_id,
+        upvotes,
+        downvotes,
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn get_votes_by_user(user_id: String) -> Vec<Vote> {
+    if user_id.len() > MAX_USER_ID_BYTES as usize {
+        return Vec::new();
+    }
+
+    let data_ids: Vec<u64> = USER_VOTES.with(|index| {
+        index.borrow()
+            .range(UserDataKey { user: user_id.clone(), data_id: 0 }..)
+            .take_while(|(key, _)| key.user == user_id)
+            .map(|(key, _)| key.data_id)
+            .collect()
+    });
+
+    let user_votes: Vec<Vote> = VOTES.with(|votes| {
+        let votes = votes.borrow();
+        data_ids
+            .into_iter()
+            .filter_map(|data_id| votes.get(&VoteKey { data_id, user: user_id.clone() }))
+            .collect()
+    });
+
+    ic_cdk::println!(
+        "DEBUG: Found {} votes for user {}",
+        user_votes.len(),
+        user_id
+    );
+
+    user_votes
+}
+
+#[update]
+#[candid_method(update)]
+fn update_vote(user_id: String, data_id: u64, new_vote_value: bool) -> Result<String, DaoError> {
+    check_voter(&user_id)?;
+    ensure_voting_open(data_id)?;
+
+    let mut vote = VOTES.with(|votes| votes.borrow().get(&VoteKey { data_id, user: user_id.clone() }))
+        .ok_or_else(|| {
+            ic_cdk::println!("DEBUG: User {} has not voted yet on data {}", user_id, data_id);
+            DaoError::NotFound(format!("Vote of user {} on submission {} not found.", user_id, data_id))
+        })?;
+
+    let now = time();
+    check_vote_allowed(&user_id, now)?;
+
+    vote.vote_value = new_vote_value;
+    insert_vote(vote);
+    record_vote_activity(&user_id, now);
+    ic_cdk::println!(
+        "DEBUG: Updated vote for user {} on data {} to {}",
+        user_id, data_id, new_vote_value
+    );
+    Ok(format!("Vote updated successfully for user {}", user_id))
+}
+
+#[update]
+#[candid_method(update)]
+fn delete_vote(user_id: String, data_id: u64) -> Result<String, DaoError> {
+    check_voter(&user_id)?;
+    ensure_voting_open(data_id)?;
+
+    if remove_vote(&user_id, data_id).is_none() {
+        ic_cdk::println!(
+            "DEBUG: Vote of user {} not found for data {}",
+            user_id,
+            data_id
+        );
+        return Err(DaoError::NotFound(format!("Vote of user {} on submission {} not found.", user_id, data_id)));
+    }
+
+    ic_cdk::println!(
+        "DEBUG: Deleted vote of user {} for data {}",
+        user_id,
+        data_id
+    );
+    Ok("Vote deleted successfully.".to_string())
+}
+
+#[query]
+#[candid_method(query)]
+fn get_leaderboard_by_total_votes() -> Vec<VoteSummary> {
+    let mut summaries = vote_summaries();
+
+    summaries.sort_by(|a, b| {
+        let total_a = a.upvotes + a.downvotes;
+        let total_b = b.upvotes + b.downvotes;
+        total_b.cmp(&total_a)  
+            .then_with(|| a.data_id.cmp(&b.data_id)) 
+    });
+
+    summaries.into_iter().take(10).collect()
+}
+
+#[query]
+#[candid_method(query)]
+fn get_leaderboard_by_upvotes() -> Vec<VoteSummary> {
+    let mut summaries = vote_summaries();
+
+    summaries.sort_by(|a, b| {
+        b.upvotes.cmp(&a.upvotes) 
+            .then_with(|| a.data_id.cmp(&b.data_id)) 
+    });
+
+    summaries.into_iter().take(10).collect()
+}
+
+// -------- Challenge functions --------
+
+/// State of a challenge at `now`: an `Active` challenge past its end time is
+/// `Closed` even before the finalizer records it.
+fn challenge_state(challenge: &Challenge, now: u64) -> ChallengeState {
+    match challenge.state {
+        ChallengeState::Active if challenge.expiration <= now => ChallengeState::Closed,
+        state => state,
+    }
+}
+
+/// The challenge as clients see it, with its current state.
+fn with_current_state(mut challenge: Challenge, now: u64) -> Challenge {
+    challenge.state = challenge_state(&challenge, now);
+    challenge
+}
+
+fn load_challenge(challenge_id: u64) -> Result<Challenge, DaoError> {
+    CHALLENGES.with(|c| c.borrow().get(&challenge_id))
+        .ok_or_else(|| DaoError::NotFound(format!("Challenge {} not found.", challenge_id)))
+}
+
+fn store_challenge(challenge: &Challenge) {
+    CHALLENGES.with(|c| c.borrow_mut().insert(challenge.id, challenge.clone()));
+}
+
+/// Whether the challenge takes submissions at `now`.
+fn is_challenge_open(challenge: &Challenge, now: u64) -> bool {
+    challenge_state(challenge, now) == ChallengeState::Active && challenge.start_time <= now
+}
+
+fn accepted_submissions(challenge_id: u64) -> u64 {
+    submissions_by_ids(submission_ids_of_challenge(challenge_id))
+        .iter()
+        .filter(|sub| matches!(sub.status, PostStatus::PENDING | PostStatus::PAID))
+        .count() as u64
+}
+
+/// Whether the challenge already has `max_accepted` accepted posts.
+fn challenge_is_full(challenge_id: u64) -> bool {
+    CHALLENGES.with(|c| c.borrow().get(&challenge_id))
+        .and_then(|ch| ch.max_accepted)
+        .is_some_and(|max| accepted_submissions(challenge_id) >= max as u64)
+}
+
+fn normalize_fields(mut fields: Vec<WeatherField>) -> Vec<WeatherField> {
+    fields.sort();
+    fields.dedup();
+    fields
+}
+
+/// Creates a challenge, as a draft unless `input.publish` is set. With a
+/// `boundary_geojson` the polygon is the geofence, and the center and radius
+/// are replaced by a circle enclosing it.
+#[update]
+#[candid_method(update)]
+fn create_challenge(input: ChallengeInput) -> Result<u64, DaoError> {
+    let creator = require_moderator()?;
+    let now = time();
+    // Validated with the widest id so the encoded size check is an upper bound.
+    let mut challenge = Challenge {
+        id: u64::MAX,
+        title: input.title,
+        latitude: input.latitude,
+        longitude: input.longitude,
+        radius_m: input.radius_m,
+        expiration: input.end_time,
+        picture_url: input.picture_url,
+        has_boundary: false,
+        creator,
+        start_time: input.start_time.unwrap_or(now),
+        max_accepted: input.max_accepted,
+        required_fields: normalize_fields(input.required_fields.unwrap_or_else(all_weather_fields)),
+        state: if input.publish { ChallengeState::Active } else { ChallengeState::Draft },
+    };
+
+    let boundary = match input.boundary_geojson {
+        Some(geojson) => {
+            let boundary = parse_boundary(&geojson)?;
+            let (latitude, longitude, radius_m) = enclosing_circle(&boundary);
+            challenge.latitude = latitude;
+            challenge.longitude = longitude;
+            challenge.radius_m = radius_m;
+            challenge.has_boundary = true;
+            Some(boundary)
+        }
+        None => None,
+    };
+    validate_challenge(&challenge, now)?;
+
+    let id = next_challenge_id();
+    challenge.id = id;
+    store_challenge(&challenge);
+    if let Some(boundary) = boundary {
+        CHALLENGE_BOUNDARIES.with(|b| b.borrow_mut().insert(id, boundary));
+    }
+    index_challenge(&challenge);
+    ic_cdk::println!("Created challenge: {} (id: {}, state: {:?})", challenge.title, challenge.id, challenge.state);
+    Ok(id)
+}
+
+/// Edits a draft or active challenge. The geofence cannot change; cancel the
+/// challenge and create a new one instead.
+#[update]
+#[candid_method(update)]
+fn update_challenge(challenge_id: u64, update: ChallengeUpdate) -> Result<Challenge, DaoError> {
+    require_moderator()?;
+    let now = time();
+    let mut challenge = load_challenge(challenge_id)?;
+    let state = challenge_state(&challenge, now);
+    match state {
+        ChallengeState::Draft => {}
+        ChallengeState::Active => {
+            if update.start_time.is_some() || update.required_fields.is_some() {
+                return Err(DaoError::InvalidState(
+                    "The start time and required fields can only change while the challenge is a draft.".to_string(),
+                ));
             }
-            None => format!("User {} not found", user_id),
         }
-    })
+        _ => return Err(DaoError::InvalidState(format!("Challenge {} is {:?} and can no longer be edited.", challenge_id, state))),
+    }
+
+    if let Some(Some(max)) = update.max_accepted {
+        let accepted = accepted_submissions(challenge_id);
+        if (max as u64) < accepted {
+            return Err(DaoError::InvalidInput(format!(
+                "Challenge {} already has {} accepted submissions.", challenge_id, accepted
+            )));
+        }
+    }
+
+    if let Some(title) = update.title {
+        challenge.title = title;
+    }
+    if let Some(picture_url) = update.picture_url {
+        challenge.picture_url = picture_url;
+    }
+    if let Some(start_time) = update.start_time {
+        challenge.start_time = start_time;
+    }
+    if let Some(end_time) = update.end_time {
+        challenge.expiration = end_time;
+    }
+    if let Some(max_accepted) = update.max_accepted {
+        challenge.max_accepted = max_accepted;
+    }
+    if let Some(required_fields) = update.required_fields {
+        challenge.required_fields = normalize_fields(required_fields);
+    }
+    validate_challenge(&challenge, now)?;
+
+    store_challenge(&challenge);
+    Ok(challenge)
+}
+
+/// Opens a draft for submissions from its start time on.
+#[update]
+#[candid_method(update)]
+fn publish_challenge(challenge_id: u64) -> Result<Challenge, DaoError> {
+    require_moderator()?;
+    let now = time();
+    let mut challenge = load_challenge(challenge_id)?;
+    if challenge.state != ChallengeState::Draft {
+        return Err(DaoError::InvalidState(format!("Challenge {} is not a draft.", challenge_id)));
+    }
+    challenge.state = ChallengeState::Active;
+    validate_challenge(&challenge, now)?;
+
+    store_challenge(&challenge);
+    Ok(challenge)
+}
+
+/// Cancels a challenge that is not settled yet. Its posts keep their status.
+#[update]
+#[candid_method(update)]
+fn cancel_challenge(challenge_id: u64) -> Result<Challenge, DaoError> {
+    require_moderator()?;
+    let mut challenge = load_challenge(challenge_id)?;
+    match challenge.state {
+        ChallengeState::Settled | ChallengeState::Cancelled => {
+            return Err(DaoError::InvalidState(format!("Challenge {} is already {:?}.", challenge_id, challenge.state)));
+        }
+        _ => {}
+    }
+    challenge.state = ChallengeState::Cancelled;
+
+    store_challenge(&challenge);
+    ic_cdk::println!("Cancelled challenge {}", challenge_id);
+    Ok(challenge)
+}
+
+/// Settles a closed challenge once none of its posts is still `OPEN`.
+#[update]
+#[candid_method(update)]
+fn settle_challenge(challenge_id: u64) -> Result<Challenge, DaoError> {
+    require_moderator()?;
+    let mut challenge = load_challenge(challenge_id)?;
+    let state = challenge_state(&challenge, time());
+    if state != ChallengeState::Closed {
+        return Err(DaoError::InvalidState(format!("Challenge {} is {:?}, not Closed.", challenge_id, state)));
+    }
+    let open = submissions_by_ids(submission_ids_of_challenge(challenge_id))
+        .iter()
+        .filter(|sub| sub.status == PostStatus::OPEN)
+        .count();
+    if open > 0 {
+        return Err(DaoError::InvalidState(format!("Challenge {} still has {} open posts.", challenge_id, open)));
+    }
+    challenge.state = ChallengeState::Settled;
+
+    store_challenge(&challenge);
+    Ok(challenge)
+}
+
+/// Records `Closed` on the active challenges whose end time has passed.
+fn close_ended_challenges(now: u64) -> Vec<u64> {
+    let ended: Vec<Challenge> = CHALLENGES.with(|c| {
+        c.borrow().iter()
+            .map(|(_, ch)| ch)
+            .filter(|ch| ch.state == ChallengeState::Active && ch.expiration <= now)
+            .collect()
+    });
+    ended.into_iter()
+        .map(|mut challenge| {
+            challenge.state = ChallengeState::Closed;
+            store_challenge(&challenge);
+            challenge.id
+        })
+        .collect()
 }
 
 #[query]
 #[candid_method(query)]
-fn get_all_users() -> Vec<User> {
-    USERS.with(|users_map| {
-        let users = users_map.borrow();
-        let all_users: Vec<User> = users.iter().map(|(_, user)| user.clone()).collect();
-        ic_cdk::println!("DEBUG: Returning all users, count: {}", all_users.len());
-        all_users
+fn get_challenge_stats(challenge_id: u64) -> Result<ChallengeStats, DaoError> {
+    let challenge = load_challenge(challenge_id)?;
+    let submissions = submissions_by_ids(submission_ids_of_challenge(challenge_id));
+    let participants: BTreeSet<&str> = submissions.iter().map(|sub| sub.user.as_str()).collect();
+    let count = |status: PostStatus| submissions.iter().filter(|sub| sub.status == status).count() as u64;
+
+    let accepted = count(PostStatus::PENDING) + count(PostStatus::PAID);
+    Ok(ChallengeStats {
+        challenge_id,
+        state: challenge_state(&challenge, time()),
+        submissions: submissions.len() as u64,
+        participants: participants.len() as u64,
+        open: count(PostStatus::OPEN),
+        accepted,
+        paid: count(PostStatus::PAID),
+        expired: count(PostStatus::EXPIRED),
+        no_quorum: count(PostStatus::NoQuorum),
+        remaining_capacity: challenge.max_accepted.map(|max| (max as u64).saturating_sub(accepted) as u32),
+    })
+}
+
+fn parse_boundary(geojson: &str) -> Result<geo::Boundary, DaoError> {
+    let max_vertices = validation_limits().max_boundary_vertices;
+    geo::Boundary::from_geojson(geojson, max_vertices).map_err(|reason| {
+        DaoError::Validation(vec![FieldError {
+            field: "boundary_geojson".to_string(),
+            issue: ValidationIssue::InvalidGeometry { reason },
+        }])
     })
 }
 
+/// Center of the bounding box of `boundary` and the distance to its
+/// farthest position, with a meter of slack for rounding.
+fn enclosing_circle(boundary: &geo::Boundary) -> (f64, f64, f64) {
+    let bbox = boundary.bounding_box();
+    let latitude = (bbox.min_lat + bbox.max_lat) / 2.0;
+    let longitude = (bbox.min_lon + bbox.max_lon) / 2.0;
+    let radius_m = boundary.polygons.iter().flatten().flatten()
+        .map(|&[lon, lat]| haversine_distance(latitude, longitude, lat, lon))
+        .fold(0.0, f64::max);
+    (latitude, longitude, radius_m + 1.0)
+}
+
+fn challenge_boundary(challenge: &Challenge) -> Option<geo::Boundary> {
+    if !challenge.has_boundary {
+        return None;
+    }
+    CHALLENGE_BOUNDARIES.with(|b| b.borrow().get(&challenge.id))
+}
+
+/// Whether the point lies in the challenge's polygon, or its circle when it
+/// has none.
+fn challenge_contains(challenge: &Challenge, lat: f64, lon: f64) -> bool {
+    match challenge_boundary(challenge) {
+        Some(boundary) => boundary.contains(lat, lon),
+        None => is_within_geofence(lat, lon, challenge.latitude, challenge.longitude, challenge.radius_m),
+    }
+}
+
 #[query]
-fn get_balance(user_id: UserId) -> u64 {
-    USERS.with(|users| {
-        let users = users.borrow();
+#[candid_method(query)]
+fn get_challenge_boundary(challenge_id: u64) -> Option<geo::Boundary> {
+    CHALLENGE_BOUNDARIES.with(|b| b.borrow().get(&challenge_id))
+}
 
-        if users.is_empty() {
-            ic_cdk::println!("ERROR: USERS map is empty, returning 0 for {}", user_id);
-            return 0;
-        }
+#[query]
+#[candid_method(query)]
+fn get_active_challenges(lat: f64, lon: f64) -> Vec<Challenge> {
+    let now = time();
+    ic_cdk::println!("Starting get_active_challenges for location: ({}, {})", lat, lon);
+    let challenges: Vec<Challenge> = challenge_candidates_at(lat, lon)
+        .into_iter()
+        .filter(|ch| {
+            ic_cdk::println!("Checking challenge: lat={}, lon={}, radius={}, expiration={}", 
+                ch.latitude, ch.longitude, ch.radius_m, ch.expiration);
+            is_challenge_open(ch, now) && challenge_contains(ch, lat, lon)
+        })
+        .collect();
+    ic_cdk::println!("Found {} active challenges", challenges.len());
+    challenges
+}
 
-        match users.get(&user_id) {
-            Some(user) => {
-                ic_cdk::println!("🔍 DEBUG: Final balance check for user {}: {}", user_id, user.balance);
-                user.balance
-            }
-            None => {
-                ic_cdk::println!("⚠️ WARNING: No balance found for user {}, returning 0", user_id);
-                0
-            }
+/// Checks that `challenge` takes a post at this time and place.
+fn check_challenge_accepts(challenge: &Challenge, lat: f64, lon: f64, now: u64) -> Result<(), DaoError> {
+    match challenge_state(challenge, now) {
+        ChallengeState::Active if now < challenge.start_time => {
+            return Err(DaoError::InvalidState("Challenge has not started yet".to_string()));
         }
-    })
+        ChallengeState::Active => {}
+        ChallengeState::Draft => return Err(DaoError::InvalidState("Challenge is not published".to_string())),
+        ChallengeState::Cancelled => return Err(DaoError::InvalidState("Challenge was cancelled".to_string())),
+        ChallengeState::Closed | ChallengeState::Settled => {
+            return Err(DaoError::InvalidState("Challenge expired".to_string()));
+        }
+    }
+    if challenge_is_full(challenge.id) {
+        return Err(DaoError::InvalidState("Challenge has reached its maximum of accepted submissions".to_string()));
+    }
+    if !challenge_contains(challenge, lat, lon) {
+        return Err(DaoError::InvalidInput("Location outside challenge geofence".to_string()));
+    }
+    Ok(())
+}
+
+fn is_within_geofence(
+    lat1: f64,
+    lon1: f64,
+    lat2: f64,
+    lon2: f64,
+    radius_m: f64,
+) -> bool {
+    let distance = haversine_distance(lat1, lon1, lat2, lon2);
+    ic_cdk::println!("distance: {}, radius: {}", distance, radius_m);
+    distance <= radius_m
+}
+
+fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let r = 6371e3_f64; // meters
+    let phi1 = lat1.to_radians();
+    let phi2 = lat2.to_radians();
+    let delta_phi = (lat2 - lat1).to_radians();
+    let delta_lambda = (lon2 - lon1).to_radians();
+
+    let a = (delta_phi / 2.0).sin().powi(2)
+        + phi1.cos() * phi2.cos() * (delta_lambda / 2.0).sin().powi(2);
+    let c = 2.0 * a.sqrt().atan2((1.0 - a).sqrt());
+
+    r * c
 }
 
-// -------- Submission functions --------
 #[update]
-fn submit_weather_data(telegram_id: String, latitude: f64, longitude: f64, city: String, temperature: f64, weather: String, submission_photo_url: String) -> u64 {
-    const SECOND: u64 = 1_000_000_000;
+#[candid_method(update)]
+fn submit_weather_data_with_challenge(
+    telegram_id: String,
+    latitude: f64,
+    longitude: f64,
+    city: String,
+    temperature: f64,
+    weather: String,
+    submission_photo_url: String,
+    challenge_id: u64,
+) -> Result<u64, DaoError> {
+    authorize_user(&telegram_id)?;
+
     let timestamp = time();
-    let expiration_timestamp = timestamp + 900 * SECOND;
-    ic_cdk::println!("Received weather submission from {}", telegram_id);
-    ic_cdk::println!("Submission time (timestamp): {}", timestamp);
-    ic_cdk::println!("Expiration time (timestamp): {}", expiration_timestamp);
-    
-    let data_id = SUBMISSIONS.with(|s| s.borrow().len() as u64 + 1);
+    let expiration_timestamp = timestamp + voting_window_ns(Some(challenge_id));
+
+    let data = WeatherData {
+        latitude,
+        longitude,
+        city,
+        temperature,
+        weather,
+        timestamp,
+        submission_photo_url,
+    };
+    let challenge = load_challenge(challenge_id)?;
+    validate_weather_data(&telegram_id, &data, &challenge.required_fields)?;
+
+    check_challenge_accepts(&challenge, latitude, longitude, timestamp)?;
+    let duplicate_of = check_submission_allowed(&telegram_id, latitude, longitude, timestamp)?;
+
+    let data_id = next_submission_id();
 
     let new_data = UserSubmission {
         data_id,
         user: telegram_id.clone(),
-        data: WeatherData {
-            latitude,
-            longitude,
-            city,
-            temperature,
-            weather,
-            timestamp,
-            submission_photo_url,
-        },
+        data,
         rewarded: false,
         status: PostStatus::OPEN,
         expiration_timestamp,
+        finalization: None,
+        duplicate_of,
+        challenge_id: Some(challenge_id),
+        reward_credit: 0,
+        claimed: 0,
+        payout_block_index: None,
     };
 
-    SUBMISSIONS.with(|s| {
-        s.borrow_mut().insert(data_id, new_data.clone());
-        ic_cdk::println!("SUBMISSIONS len after insert: {}", s.borrow().len());
-    });
-
-    ic_cdk::println!("Inserted data #{}: {:?}", data_id, new_data);
+    store_submission(&new_data);
+    record_submission(&new_data);
 
-    data_id
+    Ok(data_id)
 }
 
 #[query]
 #[candid_method(query)]
-fn get_all_submissions() -> Vec<UserSubmission> {
-    SUBMISSIONS.with(|submissions| {
-        let submissions = submissions.borrow();
-        let all_data: Vec<UserSubmission> = submissions.iter().map(|(_, v)| v.clone()).collect();
-        
-        ic_cdk::println!("DEBUG: Returning all submissions, count: {}", all_data.len());
-        all_data
+fn get_challenge(challenge_id: u64) -> Result<Challenge, DaoError> {
+    let now = time();
+    CHALLENGES.with(|c| {
+        c.borrow().get(&challenge_id)
+        .map(|ch| with_current_state(ch, now))
+        .ok_or_else(|| DaoError::NotFound("Challenge not found.".to_string()))
     })
 }
 
 #[query]
-#[candid_method(query)] 
-fn get_submission(data_id: u64) -> Result<UserSubmission, String> {
-    SUBMISSIONS.with(|submissions| {
-        let submissions = submissions.borrow();
-        let total_count = submissions.len();
-        ic_cdk::println!("DEBUG: Total submissions stored: {}", total_count);
-
-        match submissions.get(&data_id) {
-            Some(sub) => {
-                ic_cdk::println!("DEBUG: Found submission with data_id: {}", data_id);
-                Ok(sub.clone()) 
-            }
-            None => {
-                ic_cdk::println!("ERROR: Submission not found for data_id: {} | Current total: {}", data_id, total_count);
-                Err(format!("Submission {} not found. Total records: {}", data_id, total_count))
-            }
-        }
+#[candid_method(query)]
+fn get_all_challenges() -> Vec<Challenge> {
+    let now = time();
+    CHALLENGES.with(|c| {
+        c.borrow().iter().map(|(_, ch)| with_current_state(ch, now)).collect()
     })
 }
 
 #[query]
 #[candid_method(query)]
-fn get_user_posts(user_id: String) -> Vec<UserSubmission> {
-    SUBMISSIONS.with(|submissions| {
-        let submissions = submissions.borrow();
+fn get_challenges_page(start_after: Option<u64>, limit: u32) -> ChallengePage {
+    let now = time();
+    let (items, next_cursor) = CHALLENGES.with(|c| {
+        page_of(&c.borrow(), start_after, limit, |_, ch| Some(with_current_state(ch, now)))
+    });
+    ChallengePage { items, next_cursor }
+}
 
-        let user_posts: Vec<UserSubmission> = submissions
-            .iter()
-            .filter(|(_, sub)| sub.user == user_id)
-            .map(|(id, sub)| {
-                let mut sub_with_id = sub.clone();
-                sub_with_id.data_id = id;
-                sub_with_id
-            })
-            .collect();
+#[query]
+#[candid_method(query)]
+fn get_user_submissions_by_challenge(user_id: String, challenge_id: u64) -> Vec<UserSubmission> {
+    submissions_by_ids(submission_ids_of_challenge(challenge_id))
+        .into_iter()
+        .filter(|sub| sub.user == user_id)
+        .collect()
+}
 
-        ic_cdk::println!(
-            "DEBUG: Returning {} posts submitted by user {}",
-            user_posts.len(),
-            user_id
-        );
+/// Whether an active challenge has not ended yet but will within `window_ns`.
+fn expires_within(challenge: &Challenge, now: u64, window_ns: u64) -> bool {
+    challenge.state == ChallengeState::Active
+        && challenge.expiration > now
+        && challenge.expiration - now <= window_ns
+}
 
-        user_posts
+#[query]
+#[candid_method(query)]
+fn get_challenges_expiring_soon(cutoff_seconds: u64) -> Vec<Challenge> {
+    let now = time();
+    CHALLENGES.with(|c| {
+        c.borrow().iter()
+            .filter(|(_, ch)| expires_within(ch, now, secs_to_ns(cutoff_seconds)))
+            .map(|(_, ch)| ch.clone())
+            .collect()
     })
 }
 
 #[query]
 #[candid_method(query)]
-fn get_submissions_by_city(city: String) -> Vec<UserSubmission> {
-    SUBMISSIONS.with(|submissions| {
-        let submissions = submissions.borrow();
-        submissions
-            .iter()
-            .filter(|(_, sub)| sub.data.city.to_lowercase() == city.to_lowercase())
-            .map(|(_, sub)| sub.clone())
+fn get_challenges_by_radius(lat: f64, lon: f64, radius_m: f64) -> Vec<Challenge> {
+    let now = time();
+    let cells = geo::cover(&geo::BoundingBox::around(lat, lon, radius_m.max(0.0)));
+    let ids = CHALLENGE_CELLS.with(|i| ids_in_cells(&i.borrow(), &cells));
+    CHALLENGES.with(|c| {
+        let c = c.borrow();
+        ids.into_iter()
+            .filter_map(|id| c.get(&id))
+            .filter(|ch| {
+                is_challenge_open(ch, now) &&
+                haversine_distance(lat, lon, ch.latitude, ch.longitude) <= radius_m
+            })
             .collect()
     })
 }
 
 #[query]
 #[candid_method(query)]
-fn get_rewarded_submissions(user_id: String) -> Vec<UserSubmission> {
-    SUBMISSIONS.with(|submissions| {
-        let submissions = submissions.borrow();
-        submissions
-            .iter()
-            .filter(|(_, sub)| sub.user == user_id && sub.rewarded)
-            .map(|(_, sub)| sub.clone())
-            .collect()
+fn get_submissions_by_challenge(challenge_id: u64) -> Vec<UserSubmission> {
+    submissions_by_ids(submission_ids_of_challenge(challenge_id))
+}
+
+// -------- Reward functions --------
+
+#[update]
+#[candid_method(update)]
+async fn reward_user(data_id: u64) -> Result<String, DaoError> {
+    require_privileged()?;
+    pay_reward(data_id).await
+}
+
+/// Memo of the reward transfer for a submission. Deterministic, so a retried
+/// transfer is recognized by the ledger as a duplicate.
+fn reward_memo(data_id: u64) -> Vec<u8> {
+    let mut memo = b"ecoproof:reward:".to_vec();
+    memo.extend_from_slice(&data_id.to_be_bytes());
+    memo
+}
+
+fn store_payout(payout: &Payout) {
+    PAYOUTS.with(|p| p.borrow_mut().insert(payout.data_id, payout.clone()));
+}
+
+/// Marks a payout as being awaited for as long as it is alive, including
+/// when a trap in the ledger callback drops the pending future.
+struct PayoutInProgress(u64);
+
+impl PayoutInProgress {
+    fn start(data_id: u64) -> Option<Self> {
+        PAYOUTS_IN_PROGRESS
+            .with(|p| p.borrow_mut().insert(data_id))
+            .then_some(PayoutInProgress(data_id))
+    }
+}
+
+impl Drop for PayoutInProgress {
+    fn drop(&mut self) {
+        PAYOUTS_IN_PROGRESS.with(|p| p.borrow_mut().remove(&self.0));
+    }
+}
+
+/// Builds the payout for an approved submission that has none yet.
+fn schedule_reward_payout(submission: &UserSubmission) -> Result<Payout, DaoError> {
+    let data_id = submission.data_id;
+    let config = config();
+
+    // Finalized posts are judged by their recorded outcome, open ones by the
+    // current votes.
+    let outcome = match submission.finalization {
+        Some(_) => submission.status.clone(),
+        None => decide_outcome(&VoteTally::of(&votes_for_submission(data_id)), &config),
+    };
+    match outcome {
+        PostStatus::PENDING => {}
+        PostStatus::NoQuorum => return Err(DaoError::InvalidState("Not enough votes to approve the submission, no reward.".to_string())),
+        _ => return Err(DaoError::InvalidState("Votes did not approve the submission, no reward.".to_string())),
+    }
+
+    let recipient_address = USERS.with(|u| {
+        u.borrow().get(&submission.user).and_then(|u| u.wallet_address.clone())
+    }).ok_or_else(|| DaoError::InvalidState("User has no wallet address. Please connect your wallet first.".to_string()))?;
+
+    let recipient_principal = Principal::from_text(&recipient_address)
+        .map_err(|_| DaoError::InvalidInput("Recipient address is not a valid principal".to_string()))?;
+
+    // Posts of a sponsored challenge are paid from its escrow, which sets the
+    // cost aside until the transfer is recorded.
+    let escrow_challenge_id = submission.challenge_id.filter(|&id| escrow_of(id).is_some());
+
+    // A credited reward is sent out of the user's balance, less what claims
+    // already took. The voters' share is credited by the DAO, so an escrow
+    // pays its submitters the whole pool.
+    let reward = if submission.reward_credit > 0 {
+        match submission.reward_credit.saturating_sub(submission.claimed) {
+            0 => return Err(DaoError::AlreadyRewarded),
+            remaining => remaining,
+        }
+    } else if escrow_challenge_id.is_some() {
+        config.reward_amount
+    } else {
+        config.submitter_reward()
+    };
+    let amount = net_of_fee(reward, &config)
+        .ok_or_else(|| DaoError::InvalidState(format!("A reward of {} does not cover the transfer fee.", reward)))?;
+
+    if let Some(challenge_id) = escrow_challenge_id {
+        reserve_escrow(challenge_id, amount, config.transfer_fee)?;
+    }
+
+    let balance_debit = if submission.reward_credit > 0 { reward } else { 0 };
+    if balance_debit > 0 {
+        debit_balance(&submission.user, balance_debit, BalanceChange::Payout { data_id })?;
+    }
+
+    Ok(Payout {
+        data_id,
+        to: Account {
+            owner: recipient_principal,
+            subaccount: None,
+        },
+        amount,
+        fee: config.transfer_fee,
+        memo: reward_memo(data_id),
+        created_at_time: None,
+        state: PayoutState::Scheduled,
+        attempts: 0,
+        escrow_challenge_id,
+        ledger_canister_id: Some(config.ledger_canister_id),
+        balance_debit,
     })
 }
 
-#[query]
-#[candid_method(query)]
-fn get_user_submission_locations(user_id: String) -> Vec<SubmissionLocationInfo> {
-    SUBMISSIONS.with(|submissions| {
-        let submissions = submissions.borrow();
-        submissions
-            .iter()
-            .filter(|(_, sub)| sub.user == user_id)
-            .map(|(id, sub)| SubmissionLocationInfo {
-                data_id: id,
-                latitude: sub.data.latitude,
-                longitude: sub.data.longitude,
-                status: sub.status.clone(),
-            })
-            .collect()
-    })
+async fn pay_reward(data_id: u64) -> Result<String, DaoError> {
+    let submission = SUBMISSIONS.with(|subs| subs.borrow().get(&data_id))
+        .ok_or_else(|| DaoError::NotFound(format!("Submission {} not found.", data_id)))?;
+
+    if submission.rewarded {
+        return Err(DaoError::AlreadyRewarded);
+    }
+
+    let Some(_in_progress) = PayoutInProgress::start(data_id) else {
+        return Err(DaoError::InvalidState(format!("A payout for submission {} is already in flight.", data_id)));
+    };
+
+    // A stored payout is retried as-is: `InFlight` here means an earlier
+    // attempt trapped after calling the ledger, which deduplicates the resend.
+    let mut payout = match PAYOUTS.with(|p| p.borrow().get(&data_id)) {
+        Some(payout) => match payout.state {
+            PayoutState::Paid { .. } => return Err(DaoError::AlreadyRewarded),
+            _ => payout,
+        },
+        None => schedule_reward_payout(&submission)?,
+    };
+    // A payout the ledger rejected gave its escrow reservation and balance
+    // debit back.
+    if !holds_payout_funds(&payout) {
+        retake_payout_funds(&payout, &submission.user)?;
+    }
+
+    let created_at_time = *payout.created_at_time.get_or_insert_with(time);
+    let ledger = *payout.ledger_canister_id.get_or_insert_with(|| config().ledger_canister_id);
+    payout.state = PayoutState::InFlight;
+    payout.attempts += 1;
+    store_payout(&payout);
+
+    let transfer_arg = TransferArg {
+        to: payout.to.clone(),
+        fee: payout.fee.map(Nat::from),
+        memo: Some(payout.memo.clone()),
+        from_subaccount: payout.escrow_challenge_id.map(challenge_subaccount),
+        created_at_time: Some(created_at_time),
+        amount: Nat::from(payout.amount),
+    };
+
+    let result: Result<(TransferResult,), _> = call(
+        ledger,
+        "icrc1_transfer",
+        (transfer_arg,),
+    )
+    .await;
+
+    payout.state = match result {
+        Ok((TransferResult::Ok(block_idx),)) => PayoutState::Paid { block_index: nat_to_u64(&block_idx) },
+        Ok((TransferResult::Err(TransferError::Duplicate { duplicate_of }),)) => {
+            PayoutState::Paid { block_index: nat_to_u64(&duplicate_of) }
+        }
+        Ok((TransferResult::Err(TransferError::TooOld),)) => PayoutState::Failed {
+            reason: "Transfer is too old to be deduplicated; check the ledger, then resolve the payout.".to_string(),
+        },
+        Ok((TransferResult::Err(err),)) => {
+            // The ledger rejected the transfer, so a fresh attempt is safe.
+            payout.created_at_time = None;
+            PayoutState::Failed { reason: format!("Transfer failed: {:?}", err) }
+        }
+        Err(e) => PayoutState::Failed { reason: format!("Ledger call failed: {:?}", e) },
+    };
+    settle_payout_attempt(&payout, &submission.user);
+    store_payout(&payout);
+
+    match payout.state {
+        PayoutState::Paid { block_index } => {
+            record_reward_paid(data_id, block_index);
+            Ok(format!("Successfully rewarded user {} at block {}", submission.user, block_index))
+        }
+        PayoutState::Failed { reason } => Err(DaoError::LedgerError { message: reason }),
+        PayoutState::Scheduled | PayoutState::InFlight => unreachable!("payout state was just set"),
+    }
+}
+
+/// Records a delivered reward on its submission; accepted posts become `PAID`.
+fn record_reward_paid(data_id: u64, block_index: u64) {
+    update_submission(data_id, |sub| {
+        sub.rewarded = true;
+        sub.payout_block_index = Some(block_index);
+        if sub.status == PostStatus::PENDING {
+            sub.status = PostStatus::PAID;
+        }
+    });
+}
+
+fn nat_to_u64(n: &Nat) -> u64 {
+    u64::try_from(&n.0).unwrap_or(u64::MAX)
 }
 
 #[query]
 #[candid_method(query)]
-fn get_user_submission_summary(user_id: String) -> Vec<UserSubmissionSummary> {
-    SUBMISSIONS.with(|submissions| {
-        let submissions = submissions.borrow();
-        submissions
-            .iter()
-            .filter(|(_, sub)| sub.user == user_id)
-            .map(|(id, sub)| UserSubmissionSummary {
-                data_id: id,
-                city: sub.data.city.clone(),
-                status: sub.status.clone(),
-            })
-            .collect()
-    })
+fn get_payout(data_id: u64) -> Option<Payout> {
+    PAYOUTS.with(|p| p.borrow().get(&data_id))
 }
 
-#[query]
-#[candid_method(query)]
-fn get_submission_map_by_city() -> Vec<(String, Vec<SubmissionInfo>)> {
-    let submissions: Vec<_> = SUBMISSIONS.with(|s| s.borrow().iter().map(|(_, v)| v.clone()).collect());
-    let users: HashMap<_,_> = USERS.with(|u| u.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect());
-    let mut city_map: HashMap<String, Vec<SubmissionInfo>> = HashMap::new();
-
-    for submission in submissions {
-        let user_info = users.get(&submission.user);
-        let submission_info = SubmissionInfo {
-            data_id: submission.data_id,
-            user_id: submission.user.clone(),
-            username: user_info.and_then(|u| u.username.clone()),
-            first_name: user_info.and_then(|u| u.first_name.clone()),
-            last_name: user_info.and_then(|u| u.last_name.clone()),
-            profile_picture_url: user_info.and_then(|u| u.profile_picture_url.clone()),
-            latitude: submission.data.latitude,
-            longitude: submission.data.longitude,
-            city: submission.data.city.clone(),
-            temperature: submission.data.temperature,
-            weather: submission.data.weather.clone(),
-            timestamp: submission.data.timestamp,
-            submission_photo_url: submission.data.submission_photo_url.clone(),
-            rewarded: submission.rewarded,
-            status: submission.status.clone(),
-        };
-        city_map.entry(submission.data.city.clone()).or_default().push(submission_info);
+/// Settles a failed or stuck payout after checking the ledger by hand: with
+/// a block index it is recorded as paid, otherwise the next attempt starts a
+/// fresh transfer.
+#[update]
+#[candid_method(update)]
+fn resolve_payout(data_id: u64, block_index: Option<u64>) -> Result<String, DaoError> {
+    require_controller()?;
+    if PAYOUTS_IN_PROGRESS.with(|p| p.borrow().contains(&data_id)) {
+        return Err(DaoError::InvalidState(format!("A payout for submission {} is in flight.", data_id)));
+    }
+    let mut payout = PAYOUTS.with(|p| p.borrow().get(&data_id))
+        .ok_or_else(|| DaoError::NotFound(format!("No payout for submission {}.", data_id)))?;
+    if let PayoutState::Paid { .. } = payout.state {
+        return Err(DaoError::AlreadyRewarded);
     }
 
-    city_map.into_iter().collect()
+    match block_index {
+        Some(block_index) => {
+            payout.state = PayoutState::Paid { block_index };
+            record_escrow_payment(&payout);
+            record_reward_paid(data_id, block_index);
+        }
+        None => {
+            if holds_payout_funds(&payout) {
+                let user_id = SUBMISSIONS.with(|s| s.borrow().get(&data_id)).map(|sub| sub.user).unwrap_or_default();
+                release_payout_funds(&payout, &user_id);
+            }
+            payout.created_at_time = None;
+            payout.state = PayoutState::Failed { reason: "Reset by controller.".to_string() };
+        }
+    }
+    store_payout(&payout);
+    Ok(format!("Payout for submission {} resolved as {:?}", data_id, payout.state))
 }
 
-#[query]
-#[candid_method(query)]
-fn get_paid_submission_map_by_city() -> Vec<(String, Vec<SubmissionInfo>)> {
-    let submissions: Vec<_> = SUBMISSIONS.with(|s| s.borrow().iter().map(|(_, v)| v.clone()).collect());
-    let users: HashMap<_,_> = USERS.with(|u| u.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect());
-    let mut city_map: HashMap<String, Vec<SubmissionInfo>> = HashMap::new();
+// -------- Payout runner --------
 
-    for submission in submissions {
-        if submission.rewarded || submission.status == PostStatus::PAID {
-            let user_info = users.get(&submission.user);
-            let submission_info = SubmissionInfo {
-                data_id: submission.data_id,
-                user_id: submission.user.clone(),
-                username: user_info.and_then(|u| u.username.clone()),
-                first_name: user_info.and_then(|u| u.first_name.clone()),
-                last_name: user_info.and_then(|u| u.last_name.clone()),
-                profile_picture_url: user_info.and_then(|u| u.profile_picture_url.clone()),
-                latitude: submission.data.latitude,
-                longitude: submission.data.longitude,
-                city: submission.data.city.clone(),
-                temperature: submission.data.temperature,
-                weather: submission.data.weather.clone(),
-                timestamp: submission.data.timestamp,
-                submission_photo_url: submission.data.submission_photo_url.clone(),
-                rewarded: submission.rewarded,
-                status: submission.status.clone(),
-            };
-            city_map.entry(submission.data.city.clone()).or_default().push(submission_info);
-        }
+/// (Re)arms the payout job from the stored settings, like the finalizer.
+fn schedule_payout_runner() {
+    if let Some(timer_id) = PAYOUT_RUN_TIMER.with(|t| t.borrow_mut().take()) {
+        ic_cdk_timers::clear_timer(timer_id);
     }
 
-    city_map.into_iter().collect()
+    let settings = PAYOUT_RUN_SETTINGS.with(|s| s.borrow().get().clone());
+    if !settings.enabled {
+        return;
+    }
+
+    let interval = Duration::from_secs(settings.interval_secs.max(1));
+    let timer_id = ic_cdk_timers::set_timer_interval(interval, || {
+        ic_cdk::spawn(async {
+            if let Some(report) = run_payouts().await {
+                if !report.paid.is_empty() || !report.failed.is_empty() {
+                    ic_cdk::println!("INFO: Payout run: {} paid, {} failed", report.paid.len(), report.failed.len());
+                }
+            }
+        });
+    });
+    PAYOUT_RUN_TIMER.with(|t| *t.borrow_mut() = Some(timer_id));
 }
 
-// -------- Post status functions --------
-#[query]
-#[candid_method(query)]
-fn get_post_status(data_id: u64) -> String {
-    SUBMISSIONS.with(|subs| {
-        let subs = subs.borrow();
-        match subs.get(&data_id) {
-            Some(sub) => format!("Post status: {:?}", sub.status),
-            None => format!("Submission {} not found.", data_id),
+/// Clears `PAYOUT_RUN_RUNNING` when a run ends or its future is dropped.
+struct PayoutRunGuard;
+
+impl Drop for PayoutRunGuard {
+    fn drop(&mut self) {
+        PAYOUT_RUN_RUNNING.with(|r| r.set(false));
+    }
+}
+
+/// Why a `PENDING` post cannot be paid right now, if it cannot.
+fn payout_skip_reason(data_id: u64) -> Option<String> {
+    if PAYOUTS_IN_PROGRESS.with(|p| p.borrow().contains(&data_id)) {
+        return Some("A payout is already in flight.".to_string());
+    }
+    let sub = SUBMISSIONS.with(|s| s.borrow().get(&data_id))?;
+    if sub.rewarded {
+        return Some("Already rewarded.".to_string());
+    }
+    // Past the ledger's deduplication window a resend could pay twice.
+    if let Some(Payout { state: PayoutState::Failed { reason }, created_at_time: Some(created_at), .. }) =
+        PAYOUTS.with(|p| p.borrow().get(&data_id))
+    {
+        if time().saturating_sub(created_at) >= DAY_NS {
+            return Some(format!("Earlier attempt needs to be resolved: {}", reason));
         }
-    })
+    }
+    let has_wallet = USERS.with(|u| u.borrow().get(&sub.user)).is_some_and(|user| user.wallet_address.is_some());
+    if !has_wallet {
+        return Some("User has no wallet; the reward stays claimable from the balance.".to_string());
+    }
+    None
 }
 
-#[query]
-#[candid_method(query)]
-fn get_expiration_time(data_id: u64) -> Result<u64, String> {
-    SUBMISSIONS.with(|subs| {
-        let subs = subs.borrow();
-        match subs.get(&data_id) {
-            Some(sub) => Ok(sub.expiration_timestamp),
-            None => Err(format!("Submission {} not found.", data_id)),
+/// Pays `PENDING` posts in id order, `max_concurrency` ledger calls at a
+/// time, until the per-run payout or cycle budget is used up. Returns `None`
+/// when a run is already going.
+async fn run_payouts() -> Option<PayoutRunReport> {
+    if PAYOUT_RUN_RUNNING.with(|r| r.replace(true)) {
+        return None;
+    }
+    let _running = PayoutRunGuard;
+
+    let settings = PAYOUT_RUN_SETTINGS.with(|s| s.borrow().get().clone());
+    let cycles_at_start = ic_cdk::api::canister_balance();
+    let mut report = PayoutRunReport { started_at: time(), ..PayoutRunReport::default() };
+
+    let mut to_pay = Vec::new();
+    for data_id in submission_ids_with_status(&PostStatus::PENDING) {
+        match payout_skip_reason(data_id) {
+            Some(reason) => report.skip(data_id, reason),
+            None => to_pay.push(data_id),
         }
-    })
+    }
+    let max_payouts = settings.max_payouts_per_run as usize;
+    if to_pay.len() > max_payouts {
+        for data_id in to_pay.split_off(max_payouts) {
+            report.skip(data_id, "Left for the next run.".to_string());
+        }
+        report.stopped_early = Some(format!("At most {} payouts per run.", max_payouts));
+    }
+
+    let mut batches = to_pay.chunks(settings.max_concurrency.max(1) as usize);
+    for batch in batches.by_ref() {
+        let balance = ic_cdk::api::canister_balance();
+        let spent = cycles_at_start.saturating_sub(balance);
+        let stop = if balance < settings.min_cycles_balance {
+            Some(format!("Cycle balance {} is below the reserve of {}.", balance, settings.min_cycles_balance))
+        } else if spent >= settings.max_cycles_per_run {
+            Some(format!("Spent {} cycles, the limit per run is {}.", spent, settings.max_cycles_per_run))
+        } else {
+            None
+        };
+        if let Some(reason) = stop {
+            for &data_id in batch {
+                report.skip(data_id, "Left for the next run.".to_string());
+            }
+            report.stopped_early = Some(reason);
+            break;
+        }
+
+        let results = futures::future::join_all(batch.iter().map(|&data_id| pay_reward(data_id))).await;
+        for (&data_id, result) in batch.iter().zip(results) {
+            match result {
+                Ok(_) => {
+                    let block_index = SUBMISSIONS.with(|s| s.borrow().get(&data_id))
+                        .and_then(|sub| sub.payout_block_index)
+                        .unwrap_or_default();
+                    report.paid.push(PaidSubmission { data_id, block_index });
+                }
+                Err(e) => report.failed.push(SubmissionIssue { data_id, reason: format!("{:?}", e) }),
+            }
+        }
+    }
+    for &data_id in batches.flatten() {
+        report.skip(data_id, "Left for the next run.".to_string());
+    }
+
+    report.cycles_spent = cycles_at_start.saturating_sub(ic_cdk::api::canister_balance());
+    report.finished_at = time();
+    LAST_PAYOUT_RUN.with(|r| r.borrow_mut().set(report.clone()))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to store payout run report: {:?}", e)));
+    Some(report)
+}
+
+#[update]
+#[candid_method(update)]
+async fn run_payouts_now() -> Result<PayoutRunReport, DaoError> {
+    require_privileged()?;
+    run_payouts().await
+        .ok_or_else(|| DaoError::InvalidState("A payout run is already in progress.".to_string()))
 }
 
 #[query]
 #[candid_method(query)]
-fn get_all_expiration_times() -> Vec<ExpirationInfo> {
-    SUBMISSIONS.with(|subs| {
-        subs.borrow()
-            .iter()
-            .map(|(id, sub)| ExpirationInfo {
-                data_id: id,
-                expiration_timestamp: sub.expiration_timestamp,
-            })
-            .collect()
-    })
+fn get_last_payout_run() -> PayoutRunReport {
+    LAST_PAYOUT_RUN.with(|r| r.borrow().get().clone())
 }
 
 #[update]
 #[candid_method(update)]
-fn finalize_post_status(data_id: u64) -> String {
-    use PostStatus::*;
+fn set_payout_run_settings(settings: PayoutRunSettings) -> Result<String, DaoError> {
+    require_controller()?;
+    if settings.max_payouts_per_run == 0 || settings.max_concurrency == 0 {
+        return Err(DaoError::InvalidInput("max_payouts_per_run and max_concurrency must be positive.".to_string()));
+    }
+    PAYOUT_RUN_SETTINGS.with(|s| s.borrow_mut().set(settings))
+        .map_err(|e| DaoError::InvalidInput(format!("Failed to store payout run settings: {:?}", e)))?;
+    schedule_payout_runner();
+    Ok("Payout run settings updated".to_string())
+}
 
-    let result = SUBMISSIONS.with(|subs| {
-        let mut subs = subs.borrow_mut();
-        match subs.get(&data_id) {
-            Some(sub) => {
-                let now = time();
-                if now < sub.expiration_timestamp {
-                    return Some(("Post is still open. Not finalized.".to_string(), sub.status.clone()));
-                }
+#[query]
+#[candid_method(query)]
+fn get_payout_run_settings() -> PayoutRunSettings {
+    PAYOUT_RUN_SETTINGS.with(|s| s.borrow().get().clone())
+}
 
-                if sub.status != OPEN {
-                    return Some((format!("Post already finalized with status {:?}", sub.status), sub.status.clone()));
-                }
+// -------- Internal balances --------
 
-                let mut updated = sub.clone();
-                let votes = VOTES.with(|v| v.borrow().get(&data_id).cloned().unwrap_or_default());
+/// Amount actually transferred for `amount`, after the fee when fees are
+/// included in rewards.
+fn net_of_fee(amount: u64, config: &DaoConfig) -> Option<u64> {
+    match (config.fee_included_in_reward, config.transfer_fee) {
+        (true, Some(fee)) => amount.checked_sub(fee).filter(|&net| net > 0),
+        _ => Some(amount),
+    }
+}
 
-                let valid = votes.iter().filter(|v| v.vote_value).count();
-                let invalid = votes.len().saturating_sub(valid);
+fn record_balance_change(user_id: &str, change: BalanceChange, amount: u64, balance_after: u64) {
+    let id = next_balance_entry_id();
+    let entry = BalanceEntry { id, user: user_id.to_string(), change, amount, balance_after, at: time() };
+    BALANCE_HISTORY.with(|h| h.borrow_mut().insert(UserDataKey { user: user_id.to_string(), data_id: id }, entry));
+}
 
-                if valid > invalid {
-                    updated.status = PENDING;
-                } else {
-                    updated.status = EXPIRED;
-                }
+fn credit_balance(user_id: &str, amount: u64, change: BalanceChange) -> Result<u64, DaoError> {
+    let balance = USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        let mut user = users.get(&user_id.to_string())
+            .ok_or_else(|| DaoError::NotFound(format!("User {} not found.", user_id)))?;
+        user.balance = user.balance.saturating_add(amount);
+        users.insert(user_id.to_string(), user.clone());
+        Ok(user.balance)
+    })?;
+    record_balance_change(user_id, change, amount, balance);
+    Ok(balance)
+}
 
-                subs.insert(data_id, updated.clone());
-                Some((format!("Post finalized as {:?}", updated.status), updated.status))
-            },
-            None => None
+fn debit_balance(user_id: &str, amount: u64, change: BalanceChange) -> Result<u64, DaoError> {
+    let balance = USERS.with(|u| {
+        let mut users = u.borrow_mut();
+        let mut user = users.get(&user_id.to_string())
+            .ok_or_else(|| DaoError::NotFound(format!("User {} not found.", user_id)))?;
+        if user.balance < amount {
+            return Err(DaoError::InvalidState(format!("Balance of {} is {}, {} is needed.", user_id, user.balance, amount)));
         }
-    });
+        user.balance -= amount;
+        users.insert(user_id.to_string(), user.clone());
+        Ok(user.balance)
+    })?;
+    record_balance_change(user_id, change, amount, balance);
+    Ok(balance)
+}
 
-    match result {
-        Some((msg, _)) => msg,
-        None => format!("Submission {} not found.", data_id),
+/// Credits the reward of a post that was just accepted. Posts of a
+/// sponsored challenge are paid from its escrow instead, and posts paid
+/// before they were finalized are not credited again.
+fn credit_submission_reward(sub: &mut UserSubmission, config: &DaoConfig) {
+    let paid = sub.rewarded || PAYOUTS.with(|p| p.borrow().contains_key(&sub.data_id));
+    if paid || sub.reward_credit > 0 || sub.challenge_id.and_then(escrow_of).is_some() {
+        return;
+    }
+    let reward = config.submitter_reward();
+    match credit_balance(&sub.user, reward, BalanceChange::SubmissionReward { data_id: sub.data_id }) {
+        Ok(_) => sub.reward_credit = reward,
+        Err(e) => ic_cdk::println!("WARNING: Could not credit the reward of {}: {:?}", sub.data_id, e),
     }
 }
 
-// -------- Vote functions --------
+/// Splits the voter pool of a decided post equally among the voters who
+/// sided with the outcome and credits their balances. Returns how many were
+/// credited and the amount each got.
+fn credit_voter_rewards(data_id: u64, votes: &[Vote], accepted: bool, config: &DaoConfig) -> (u32, u64) {
+    let winners: Vec<&Vote> = votes.iter().filter(|vote| vote.vote_value == accepted).collect();
+    if winners.is_empty() {
+        return (0, 0);
+    }
+    let each = config.voter_pool() / winners.len() as u64;
+    if each == 0 {
+        return (0, 0);
+    }
+    let mut credited = 0;
+    for vote in winners {
+        if let Err(e) = credit_balance(&vote.user, each, BalanceChange::VoterReward { data_id }) {
+            ic_cdk::println!("WARNING: Could not credit the voter reward of {} on {}: {:?}", vote.user, data_id, e);
+            continue;
+        }
+        let mut reputation = reputation_of(&vote.user);
+        reputation.voter_rewards = reputation.voter_rewards.saturating_add(each);
+        REPUTATIONS.with(|r| r.borrow_mut().insert(vote.user.clone(), reputation));
+        credited += 1;
+    }
+    (credited, each)
+}
 
-#[update]
-#[candid_method(update)]
-fn vote_on_data(user_id: String, data_id: u64, vote_value: bool) -> String {
-    ic_cdk::println!("DEBUG: vote_on_data called with user_id: {}, data_id: {}, vote_value: {}", user_id, data_id, vote_value);
+/// Attributes a claim to the user's oldest credited posts without a payout;
+/// the posts it covers entirely become `PAID`.
+fn allocate_claim(user_id: &str, mut amount: u64, block_index: u64) {
+    for data_id in submission_ids_of_user(user_id) {
+        if amount == 0 {
+            break;
+        }
+        if PAYOUTS.with(|p| p.borrow().contains_key(&data_id)) {
+            continue;
+        }
+        let allocated = update_submission(data_id, |sub| {
+            let remaining = sub.reward_credit.saturating_sub(sub.claimed);
+            if sub.status != PostStatus::PENDING || remaining == 0 {
+                return 0;
+            }
+            let allocated = remaining.min(amount);
+            sub.claimed += allocated;
+            if sub.claimed == sub.reward_credit {
+                sub.rewarded = true;
+                sub.status = PostStatus::PAID;
+                sub.payout_block_index = Some(block_index);
+            }
+            allocated
+        });
+        amount -= allocated.unwrap_or(0);
+    }
+}
+
+fn claim_memo(claim_id: u64) -> Vec<u8> {
+    let mut memo = b"ecoproof:claim:".to_vec();
+    memo.extend_from_slice(&claim_id.to_be_bytes());
+    memo
+}
 
-    let submission_exists = SUBMISSIONS.with(|subs| subs.borrow().contains_key(&data_id));
+fn store_claim(claim: &RewardClaim) {
+    CLAIMS.with(|c| c.borrow_mut().insert(claim.id, claim.clone()));
+}
 
-    if !submission_exists {
-        return "Submission not found.".to_string();
+/// Transfers `amount` of the caller's balance to `to`. The caller must be
+/// linked to a user; wallets do not need to be registered.
+#[update]
+#[candid_method(update)]
+async fn claim_rewards(amount: u64, to: Account) -> Result<RewardClaim, DaoError> {
+    let caller = caller_principal()?;
+    let user_id = bound_user_id(&caller)
+        .ok_or_else(|| DaoError::Unauthorized("Caller principal is not linked to any user.".to_string()))?;
+    if to.owner == Principal::anonymous() || to.subaccount.as_ref().is_some_and(|s| s.len() != 32) {
+        return Err(DaoError::InvalidInput("to is not a valid account.".to_string()));
     }
+    let config = config();
+    let transfer_amount = net_of_fee(amount, &config)
+        .ok_or_else(|| DaoError::InvalidInput("amount must be positive and cover the transfer fee.".to_string()))?;
 
-    let new_vote = Vote {
+    let id = next_claim_id();
+    debit_balance(&user_id, amount, BalanceChange::Claim { claim_id: id })?;
+    let mut claim = RewardClaim {
+        id,
         user: user_id.clone(),
-        data_id,
-        vote_value,
-        submission_id: data_id,
+        to,
+        amount,
+        transfer_amount,
+        fee: config.transfer_fee,
+        memo: claim_memo(id),
+        created_at: time(),
+        state: PayoutState::InFlight,
     };
-    ic_cdk::println!("DEBUG: New vote created: {:?}", new_vote);
+    store_claim(&claim);
 
-    VOTES.with(|votes_map| {
-        let mut votes_map = votes_map.borrow_mut();
-        let entry = votes_map.entry(data_id).or_insert(Vec::new());
-
-        if entry.iter().any(|vote| vote.user == user_id) {
-            ic_cdk::println!("DEBUG: User {} already voted on submission {}", user_id, data_id);
-            return "User has already voted on this submission.".to_string();
+    let transfer_arg = TransferArg {
+        to: claim.to.clone(),
+        fee: claim.fee.map(Nat::from),
+        memo: Some(claim.memo.clone()),
+        from_subaccount: None,
+        created_at_time: Some(claim.created_at),
+        amount: Nat::from(transfer_amount),
+    };
+    let result: Result<(TransferResult,), _> = call(config.ledger_canister_id, "icrc1_transfer", (transfer_arg,)).await;
+
+    // A rejected call or transfer left the ledger untouched, so the amount
+    // goes back to the balance.
+    claim.state = match result {
+        Ok((TransferResult::Ok(block_index),)) => PayoutState::Paid { block_index: nat_to_u64(&block_index) },
+        Ok((TransferResult::Err(TransferError::Duplicate { duplicate_of }),)) => {
+            PayoutState::Paid { block_index: nat_to_u64(&duplicate_of) }
         }
+        Ok((TransferResult::Err(err),)) => PayoutState::Failed { reason: format!("Transfer failed: {:?}", err) },
+        Err(e) => PayoutState::Failed { reason: format!("Ledger call failed: {:?}", e) },
+    };
+    store_claim(&claim);
 
-        entry.push(new_vote);
-        ic_cdk::println!("DEBUG: Updated vote list for data_id {}: {:?}", data_id, entry);
-        format!("User {} successfully voted on data {}.", user_id, data_id)
-    })
+    match &claim.state {
+        PayoutState::Paid { block_index } => {
+            allocate_claim(&user_id, amount, *block_index);
+            Ok(claim)
+        }
+        PayoutState::Failed { reason } => {
+            credit_balance(&user_id, amount, BalanceChange::ClaimReverted { claim_id: id })?;
+            Err(DaoError::LedgerError { message: reason.clone() })
+        }
+        PayoutState::Scheduled | PayoutState::InFlight => unreachable!("claim state was just set"),
+    }
 }
 
 #[query]
 #[candid_method(query)]
-fn get_vote_summary(data_id: u64) -> VoteSummary {
-    VOTES.with(|votes_map| {
-        let votes_map = votes_map.borrow();
-        let votes = votes_map.get(&data_id).cloned().unwrap_or_default();
-
-        let upvotes = votes.iter().filter(|v| v.vote_value).count() as u32;
-        let downvotes = votes.len() as u32 - upvotes;
-
-        VoteSummary {
-            data_id,
-            upvotes,
-            downvotes,
-        }
-    })
+fn get_claim(claim_id: u64) -> Option<RewardClaim> {
+    CLAIMS.with(|c| c.borrow().get(&claim_id))
 }
 
+/// Balance changes of a user, oldest first, after the entry `start_after`.
 #[query]
 #[candid_method(query)]
-fn get_votes_by_user(user_id: String) -> Vec<Vote> {
-    VOTES.with(|votes_map| {
-        let votes_map = votes_map.borrow();
-        let mut user_votes = Vec::new();
-
-        for votes in votes_map.values() {
-            for vote in votes {
-                if vote.user == user_id {
-                    user_votes.push(vote.clone());
-                }
-            }
-        }
+fn get_balance_history(user_id: UserId, start_after: Option<u64>, limit: u32) -> BalanceHistoryPage {
+    let size = page_size(limit);
+    let first = start_after.map_or(0, |id| id.saturating_add(1));
+    let mut items: Vec<BalanceEntry> = BALANCE_HISTORY.with(|h| {
+        h.borrow()
+            .range(UserDataKey { user: user_id.clone(), data_id: first }..)
+            .take_while(|(key, _)| key.user == user_id)
+            .take(size + 1)
+            .map(|(_, entry)| entry)
+            .collect()
+    });
+    let next_cursor = if items.len() > size {
+        items.truncate(size);
+        items.last().map(|entry| entry.id)
+    } else {
+        None
+    };
+    BalanceHistoryPage { items, next_cursor }
+}
 
-        ic_cdk::println!(
-            "DEBUG: Found {} votes for user {}",
-            user_votes.len(),
-            user_id
-        );
+// -------- Challenge escrow --------
 
-        user_votes
-    })
+/// Subaccount of this canister holding the escrow of a challenge.
+fn challenge_subaccount(challenge_id: u64) -> Vec<u8> {
+    let mut subaccount = vec![0u8; 32];
+    subaccount[..9].copy_from_slice(b"challenge");
+    subaccount[24..].copy_from_slice(&challenge_id.to_be_bytes());
+    subaccount
 }
 
-#[update]
-#[candid_method(update)]
-fn update_vote(user_id: String, data_id: u64, new_vote_value: bool) -> String {
-    VOTES.with(|votes_map| {
-        let mut votes_map = votes_map.borrow_mut();
-
-        if let Some(votes) = votes_map.get_mut(&data_id) {
-            for vote in votes.iter_mut() {
-                if vote.user == user_id {
-                    vote.vote_value = new_vote_value;
-                    ic_cdk::println!(
-                        "DEBUG: Updated vote for user {} on data {} to {}",
-                        user_id, data_id, new_vote_value
-                    );
-                    return format!("Vote updated successfully for user {}", user_id);
-                }
-            }
-            ic_cdk::println!("DEBUG: User {} has not voted yet on data {}", user_id, data_id);
-            "Vote not found for user on this data.".to_string()
-        } else {
-            ic_cdk::println!("DEBUG: No votes found for data {}", data_id);
-            "No votes found for this submission.".to_string()
-        }
-    })
+fn escrow_account(challenge_id: u64) -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(challenge_subaccount(challenge_id)),
+    }
 }
 
-#[update]
-#[candid_method(update)]
-fn delete_vote(user_id: String, data_id: u64) -> String {
-    VOTES.with(|votes_map| {
-        let mut votes_map = votes_map.borrow_mut();
-
-        if let Some(votes) = votes_map.get_mut(&data_id) {
-            let original_len = votes.len();
-            votes.retain(|vote| vote.user != user_id);
-
-            if votes.len() < original_len {
-                ic_cdk::println!(
-                    "DEBUG: Deleted vote of user {} for data {}",
-                    user_id,
-                    data_id
-                );
-                "Vote deleted successfully.".to_string()
-            } else {
-                ic_cdk::println!(
-                    "DEBUG: Vote of user {} not found for data {}",
-                    user_id,
-                    data_id
-                );
-                "Vote not found for this user on this submission.".to_string()
-            }
-        } else {
-            ic_cdk::println!(
-                "DEBUG: No votes found for data {} when trying to delete vote of user {}",
-                data_id,
-                user_id
-            );
-            "No votes found for this submission.".to_string()
-        }
-    })
+fn escrow_memo(kind: &[u8], challenge_id: u64) -> Vec<u8> {
+    let mut memo = b"ecoproof:".to_vec();
+    memo.extend_from_slice(kind);
+    memo.push(b':');
+    memo.extend_from_slice(&challenge_id.to_be_bytes());
+    memo
 }
 
-#[query]
-#[candid_method(query)]
-fn get_leaderboard_by_total_votes() -> Vec<VoteSummary> {
-    VOTES.with(|votes_map| {
-        let votes_map = votes_map.borrow();
-        
-        let mut summaries: Vec<VoteSummary> = votes_map.iter().map(|(data_id, votes)| {
-            let upvotes = votes.iter().filter(|v| v.vote_value).count() as u32;
-            let downvotes = votes.len() as u32 - upvotes;
-            VoteSummary {
-                data_id: *data_id,
-                upvotes,
-                downvotes,
-            }
-        }).collect();
-
-        summaries.sort_by(|a, b| {
-            let total_a = a.upvotes + a.downvotes;
-            let total_b = b.upvotes + b.downvotes;
-            total_b.cmp(&total_a)  
-                .then_with(|| a.data_id.cmp(&b.data_id)) 
-        });
-
-        summaries.into_iter().take(10).collect()
-    })
+fn escrow_of(challenge_id: u64) -> Option<ChallengeEscrow> {
+    CHALLENGE_ESCROWS.with(|e| e.borrow().get(&challenge_id))
 }
 
-#[query]
-#[candid_method(query)]
-fn get_leaderboard_by_upvotes() -> Vec<VoteSummary> {
-    VOTES.with(|votes_map| {
-        let votes_map = votes_map.borrow();
-        
-        let mut summaries: Vec<VoteSummary> = votes_map.iter().map(|(data_id, votes)| {
-            let upvotes = votes.iter().filter(|v| v.vote_value).count() as u32;
-            let downvotes = votes.len() as u32 - upvotes;
-            VoteSummary {
-                data_id: *data_id,
-                upvotes,
-                downvotes,
-            }
-        }).collect();
+fn store_escrow(escrow: &ChallengeEscrow) {
+    CHALLENGE_ESCROWS.with(|e| e.borrow_mut().insert(escrow.challenge_id, escrow.clone()));
+}
 
-        summaries.sort_by(|a, b| {
-            b.upvotes.cmp(&a.upvotes) 
-                .then_with(|| a.data_id.cmp(&b.data_id)) 
-        });
+/// Sets the cost of a payout aside in the escrow of `challenge_id`.
+fn reserve_escrow(challenge_id: u64, amount: u64, fee: Option<u64>) -> Result<(), DaoError> {
+    let mut escrow = escrow_of(challenge_id)
+        .ok_or_else(|| DaoError::NotFound(format!("Challenge {} has no escrow.", challenge_id)))?;
+    let cost = escrow.payout_cost(amount, fee);
+    if escrow.available() < cost {
+        return Err(DaoError::InvalidState(format!(
+            "The escrow of challenge {} has {} left, {} is needed.",
+            challenge_id, escrow.available(), cost
+        )));
+    }
+    escrow.reserved += cost;
+    store_escrow(&escrow);
+    Ok(())
+}
 
-        summaries.into_iter().take(10).collect()
-    })
+/// Whether a payout still holds its escrow reservation and balance debit:
+/// until it is paid or the ledger definitely did not execute it.
+fn holds_payout_funds(payout: &Payout) -> bool {
+    match payout.state {
+        PayoutState::Scheduled | PayoutState::InFlight => true,
+        PayoutState::Paid { .. } => false,
+        // Without `created_at_time` no earlier attempt can still land.
+        PayoutState::Failed { .. } => payout.created_at_time.is_some(),
+    }
 }
 
-// -------- Challenge functions --------
-#[update]
-#[candid_method(update)]
-fn create_challenge(title: String, latitude: f64, longitude: f64, radius_m: f64, expiration_duration: u64, picture_url: String) -> u64 {
-    let now = time();
-    let expiration = now + expiration_duration;
-    let id = CHALLENGES.with(|c| c.borrow().len() as u64 + 1);
-    let challenge = Challenge {
-        id,
-        title,
-        latitude,
-        longitude,
-        radius_m,
-        expiration,
-        picture_url,
+/// Gives the cost of a payout that will not be executed back to its escrow.
+fn release_escrow_reservation(payout: &Payout) {
+    let Some(mut escrow) = payout.escrow_challenge_id.and_then(escrow_of) else {
+        return;
     };
-    CHALLENGES.with(|c| {
-        c.borrow_mut().insert(id, challenge.clone());
-    });
-    ic_cdk::println!("Created challenge: {} (id: {})", challenge.title, challenge.id);
-    id
+    let cost = escrow.payout_cost(payout.amount, payout.fee);
+    escrow.reserved = escrow.reserved.saturating_sub(cost);
+    store_escrow(&escrow);
 }
 
-#[query]
-#[candid_method(query)]
-fn get_active_challenges(lat: f64, lon: f64) -> Vec<Challenge> {
-    let now = time();
-    CHALLENGES.with(|c| {
-        ic_cdk::println!("Starting get_active_challenges for location: ({}, {})", lat, lon);
-        let challenges: Vec<Challenge> = c.borrow()
-            .iter()
-            .filter(|(_, ch)| {
-                ic_cdk::println!("Checking challenge: lat={}, lon={}, radius={}, expiration={}", 
-                    ch.latitude, ch.longitude, ch.radius_m, ch.expiration);
-                ch.expiration > now && is_within_geofence(lat, lon, ch.latitude, ch.longitude, ch.radius_m)
-            })
-            .map(|(_, ch)| ch.clone())
-            .collect();
-        ic_cdk::println!("Found {} active challenges", challenges.len());
-        challenges
-    })
+/// Moves the cost of a paid escrow payout from reserved to paid.
+fn record_escrow_payment(payout: &Payout) {
+    let Some(mut escrow) = payout.escrow_challenge_id.and_then(escrow_of) else {
+        return;
+    };
+    let cost = escrow.payout_cost(payout.amount, payout.fee);
+    escrow.reserved = escrow.reserved.saturating_sub(cost);
+    escrow.paid += cost;
+    store_escrow(&escrow);
 }
 
-fn is_submission_within_challenge(challenge_id: u64, lat: f64, lon: f64) -> Result<(), String> {
-    CHALLENGES.with(|c| {
-        match c.borrow().get(&challenge_id) {
-            Some(ch) => {
-                if ch.expiration < time() {
-                    return Err("Challenge expired".to_string());
-                }
-                if !is_within_geofence(lat, lon, ch.latitude, ch.longitude, ch.radius_m) {
-                    return Err("Location outside challenge geofence".to_string());
-                }
-                Ok(())
-            }
-            None => Err("Challenge not found".to_string()),
+/// Gives the funds of a payout that will not be executed back: the cost to
+/// its escrow and the balance debit to the user.
+fn release_payout_funds(payout: &Payout, user_id: &str) {
+    release_escrow_reservation(payout);
+    if payout.balance_debit > 0 {
+        let change = BalanceChange::PayoutReverted { data_id: payout.data_id };
+        if let Err(e) = credit_balance(user_id, payout.balance_debit, change) {
+            ic_cdk::println!("WARNING: Could not give back the balance of payout {}: {:?}", payout.data_id, e);
         }
-    })
+    }
 }
 
-fn is_within_geofence(
-    lat1: f64,
-    lon1: f64,
-    lat2: f64,
-    lon2: f64,
-    radius_m: f64,
-) -> bool {
-    let distance = haversine_distance(lat1, lon1, lat2, lon2);
-    ic_cdk::println!("distance: {}, radius: {}", distance, radius_m);
-    distance <= radius_m
+/// Takes the funds released by a rejected attempt again before a fresh one.
+fn retake_payout_funds(payout: &Payout, user_id: &str) -> Result<(), DaoError> {
+    if let Some(challenge_id) = payout.escrow_challenge_id {
+        reserve_escrow(challenge_id, payout.amount, payout.fee)?;
+    }
+    if payout.balance_debit > 0 {
+        if let Err(e) = debit_balance(user_id, payout.balance_debit, BalanceChange::Payout { data_id: payout.data_id }) {
+            release_escrow_reservation(payout);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Settles the funds of a transfer attempt: a paid escrow cost moves from
+/// reserved to paid, the funds of a rejected one are given back.
+fn settle_payout_attempt(payout: &Payout, user_id: &str) {
+    match payout.state {
+        PayoutState::Paid { .. } => record_escrow_payment(payout),
+        PayoutState::Failed { .. } if !holds_payout_funds(payout) => release_payout_funds(payout, user_id),
+        _ => {}
+    }
 }
 
-fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
-    let r = 6371e3_f64; // meters
-    let phi1 = lat1.to_radians();
-    let phi2 = lat2.to_radians();
-    let delta_phi = (lat2 - lat1).to_radians();
-    let delta_lambda = (lon2 - lon1).to_radians();
+/// Marks an escrow as being funded or refunded for as long as it is alive.
+struct EscrowInProgress(u64);
 
-    let a = (delta_phi / 2.0).sin().powi(2)
-        + phi1.cos() * phi2.cos() * (delta_lambda / 2.0).sin().powi(2);
-    let c = 2.0 * a.sqrt().atan2((1.0 - a).sqrt());
+impl EscrowInProgress {
+    fn start(challenge_id: u64) -> Result<Self, DaoError> {
+        ESCROWS_IN_PROGRESS
+            .with(|e| e.borrow_mut().insert(challenge_id))
+            .then_some(EscrowInProgress(challenge_id))
+            .ok_or_else(|| DaoError::InvalidState(format!("The escrow of challenge {} is being updated.", challenge_id)))
+    }
+}
 
-    r * c
+impl Drop for EscrowInProgress {
+    fn drop(&mut self) {
+        ESCROWS_IN_PROGRESS.with(|e| e.borrow_mut().remove(&self.0));
+    }
+}
+
+async fn ledger_fee() -> Result<u64, DaoError> {
+    let result: Result<(Nat,), _> = call(config().ledger_canister_id, "icrc1_fee", ()).await;
+    result
+        .map(|(fee,)| nat_to_u64(&fee))
+        .map_err(|e| DaoError::LedgerError { message: format!("Ledger call failed: {:?}", e) })
+}
+
+async fn ledger_balance(account: Account) -> Result<u64, DaoError> {
+    let result: Result<(Nat,), _> = call(config().ledger_canister_id, "icrc1_balance_of", (account,)).await;
+    result
+        .map(|(balance,)| nat_to_u64(&balance))
+        .map_err(|e| DaoError::LedgerError { message: format!("Ledger call failed: {:?}", e) })
 }
 
+/// Pulls `amount` from the caller's account, which must have approved this
+/// canister through `icrc2_approve` for `amount` plus the ledger fee, into
+/// the challenge's escrow. The first funder becomes the challenge's sponsor;
+/// only the same account can add to it.
 #[update]
 #[candid_method(update)]
-fn submit_weather_data_with_challenge(
-    telegram_id: String,
-    latitude: f64,
-    longitude: f64,
-    city: String,
-    temperature: f64,
-    weather: String,
-    submission_photo_url: String,
-    challenge_id: u64,
-) -> Result<u64, String> {
-    const SECOND: u64 = 1_000_000_000;
-    let timestamp = time();
-    let expiration_timestamp = timestamp + 300 * SECOND;
-
-    is_submission_within_challenge(challenge_id, latitude, longitude)?;
+async fn fund_challenge(challenge_id: u64, amount: u64, from_subaccount: Option<Vec<u8>>) -> Result<ChallengeEscrow, DaoError> {
+    let caller = caller_principal()?;
+    if amount == 0 {
+        return Err(DaoError::InvalidInput("amount must be positive.".to_string()));
+    }
+    let challenge = load_challenge(challenge_id)?;
+    let state = challenge_state(&challenge, time());
+    if !matches!(state, ChallengeState::Draft | ChallengeState::Active) {
+        return Err(DaoError::InvalidState(format!("Challenge {} is {:?} and can no longer be funded.", challenge_id, state)));
+    }
 
-    let data_id = SUBMISSIONS.with(|s| s.borrow().len() as u64 + 1);
+    let sponsor = Account { owner: caller, subaccount: from_subaccount };
+    let _in_progress = EscrowInProgress::start(challenge_id)?;
+    let transfer_fee = match escrow_of(challenge_id) {
+        Some(escrow) if escrow.sponsor != sponsor => {
+            return Err(DaoError::Unauthorized(format!("Challenge {} is sponsored by another account.", challenge_id)));
+        }
+        Some(escrow) => escrow.transfer_fee,
+        None => ledger_fee().await?,
+    };
 
-    let new_data = UserSubmission {
-        data_id,
-        user: telegram_id.clone(),
-        data: WeatherData {
-            latitude,
-            longitude,
-            city,
-            temperature,
-            weather,
-            timestamp,
-            submission_photo_url,
-        },
-        rewarded: false,
-        status: PostStatus::OPEN,
-        expiration_timestamp,
+    let now = time();
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: sponsor.clone(),
+        to: escrow_account(challenge_id),
+        amount: Nat::from(amount),
+        fee: None,
+        memo: Some(escrow_memo(b"fund", challenge_id)),
+        created_at_time: Some(now),
+    };
+    let result: Result<(TransferFromResult,), _> = call(config().ledger_canister_id, "icrc2_transfer_from", (args,)).await;
+    let block_index = match result {
+        Ok((TransferFromResult::Ok(block_index),)) => nat_to_u64(&block_index),
+        Ok((TransferFromResult::Err(err),)) => {
+            return Err(DaoError::LedgerError { message: format!("Transfer from sponsor failed: {:?}", err) });
+        }
+        Err(e) => return Err(DaoError::LedgerError { message: format!("Ledger call failed: {:?}", e) }),
     };
 
-    SUBMISSIONS.with(|s| {
-        s.borrow_mut().insert(data_id, new_data.clone());
+    // Payouts may have reserved budget while the ledger was called.
+    let mut escrow = escrow_of(challenge_id).unwrap_or_else(|| ChallengeEscrow {
+        challenge_id,
+        sponsor,
+        transfer_fee,
+        deposited: 0,
+        reserved: 0,
+        paid: 0,
+        deposits: Vec::new(),
+        refund: None,
     });
-
-    Ok(data_id)
+    escrow.deposited += amount;
+    escrow.deposits.push(EscrowTransfer { amount, block_index, at: now });
+    store_escrow(&escrow);
+    ic_cdk::println!("INFO: Challenge {} funded with {} at block {}", challenge_id, amount, block_index);
+    Ok(escrow)
 }
 
-#[query]
-#[candid_method(query)]
-fn get_challenge(challenge_id: u64) -> Result<Challenge, String> {
-    CHALLENGES.with(|c| {
-        c.borrow().get(&challenge_id)
-        .map(|ch| ch.clone()) 
-        .ok_or("Challenge not found.".to_string())
-    })
-}
+/// Sends what is left in the escrow of a settled or cancelled challenge back
+/// to its sponsor. Every accepted post must be paid first.
+#[update]
+#[candid_method(update)]
+async fn refund_challenge_escrow(challenge_id: u64) -> Result<ChallengeEscrow, DaoError> {
+    let caller = caller_principal()?;
+    let challenge = load_challenge(challenge_id)?;
+    let escrow = escrow_of(challenge_id)
+        .ok_or_else(|| DaoError::NotFound(format!("Challenge {} has no escrow.", challenge_id)))?;
+    if caller != escrow.sponsor.owner && !has_role(&caller, &[Role::Admin, Role::Moderator]) {
+        return Err(DaoError::Unauthorized("Only the sponsor, admins and moderators can refund an escrow.".to_string()));
+    }
+    if !matches!(challenge.state, ChallengeState::Settled | ChallengeState::Cancelled) {
+        return Err(DaoError::InvalidState(format!(
+            "Challenge {} is {:?}; escrows are refunded once settled or cancelled.", challenge_id, challenge.state
+        )));
+    }
+    if escrow.refund.is_some() {
+        return Err(DaoError::InvalidState(format!("The escrow of challenge {} was already refunded.", challenge_id)));
+    }
+    let submissions = submissions_by_ids(submission_ids_of_challenge(challenge_id));
+    // Open posts may still be accepted and paid from the escrow.
+    let open = submissions.iter().filter(|sub| sub.status == PostStatus::OPEN).count();
+    if open > 0 {
+        return Err(DaoError::InvalidState(format!("Challenge {} has {} posts still being voted on.", challenge_id, open)));
+    }
+    let unpaid = submissions.iter()
+        .filter(|sub| sub.status == PostStatus::PENDING && !sub.rewarded)
+        .count();
+    if unpaid > 0 {
+        return Err(DaoError::InvalidState(format!("Challenge {} has {} accepted posts not paid yet.", challenge_id, unpaid)));
+    }
+    if escrow.reserved > 0 {
+        return Err(DaoError::InvalidState(format!("Challenge {} has payouts in flight.", challenge_id)));
+    }
 
-#[query]
-#[candid_method(query)]
-fn get_all_challenges() -> Vec<Challenge> {
-    CHALLENGES.with(|c| {
-        c.borrow().iter().map(|(_, ch)| ch.clone()).collect()
-    })
-}
+    let _in_progress = EscrowInProgress::start(challenge_id)?;
+    // The balance also covers tokens sent to the subaccount directly.
+    let balance = ledger_balance(escrow_account(challenge_id)).await?;
+    if balance <= escrow.transfer_fee {
+        return Err(DaoError::InvalidState(format!("The escrow of challenge {} holds {}, not enough to refund.", challenge_id, balance)));
+    }
+    let amount = balance - escrow.transfer_fee;
 
-#[query]
-#[candid_method(query)]
-fn get_user_submissions_by_challenge(user_id: String, challenge_id: u64) -> Vec<UserSubmission> {
-    SUBMISSIONS.with(|subs| {
-        subs.borrow().iter()
-            .filter(|(_, sub)| sub.user == user_id 
-                && is_submission_in_challenge(sub, challenge_id))
-            .map(|(_, sub)| sub.clone())
-            .collect()
-    })
-}
+    let now = time();
+    let transfer_arg = TransferArg {
+        to: escrow.sponsor.clone(),
+        fee: Some(Nat::from(escrow.transfer_fee)),
+        memo: Some(escrow_memo(b"refund", challenge_id)),
+        from_subaccount: Some(challenge_subaccount(challenge_id)),
+        created_at_time: Some(now),
+        amount: Nat::from(amount),
+    };
+    let result: Result<(TransferResult,), _> = call(config().ledger_canister_id, "icrc1_transfer", (transfer_arg,)).await;
+    let block_index = match result {
+        Ok((TransferResult::Ok(block_index),)) => nat_to_u64(&block_index),
+        Ok((TransferResult::Err(err),)) => {
+            return Err(DaoError::LedgerError { message: format!("Refund failed: {:?}", err) });
+        }
+        Err(e) => return Err(DaoError::LedgerError { message: format!("Ledger call failed: {:?}", e) }),
+    };
 
-fn is_submission_in_challenge(sub: &UserSubmission, challenge_id: u64) -> bool {
-    CHALLENGES.with(|c| {
-        c.borrow().get(&challenge_id).map_or(false, |ch| {
-            is_within_geofence(
-                sub.data.latitude,
-                sub.data.longitude,
-                ch.latitude,
-                ch.longitude,
-                ch.radius_m,
-            )
-        })
-    })
+    let mut escrow = escrow_of(challenge_id).unwrap_or(escrow);
+    escrow.refund = Some(EscrowTransfer { amount, block_index, at: now });
+    store_escrow(&escrow);
+    ic_cdk::println!("INFO: Refunded {} from the escrow of challenge {} at block {}", amount, challenge_id, block_index);
+    Ok(escrow)
 }
 
 #[query]
 #[candid_method(query)]
-fn get_challenges_expiring_soon(cutoff_seconds: u64) -> Vec<Challenge> {
-    let now = time();
-    CHALLENGES.with(|c| {
-        c.borrow().iter()
-            .filter(|(_, ch)| {
-                let remaining = ch.expiration.saturating_sub(now);
-                remaining <= cutoff_seconds * 1_000_000_000
-            })
-            .map(|(_, ch)| ch.clone())
-            .collect()
-    })
+fn get_challenge_escrow(challenge_id: u64) -> Option<ChallengeEscrow> {
+    escrow_of(challenge_id)
 }
 
-#[query]
-#[candid_method(query)]
-fn get_challenges_by_radius(lat: f64, lon: f64, radius_m: f64) -> Vec<Challenge> {
-    let now = time();
-    CHALLENGES.with(|c| {
-        c.borrow().iter()
-            .filter(|(_, ch)| {
-                ch.expiration > now &&
-                haversine_distance(lat, lon, ch.latitude, ch.longitude) <= radius_m
-            })
-            .map(|(_, ch)| ch.clone())
-            .collect()
-    })
+// -------- Ledger reconciliation --------
+
+const RECONCILIATION_BATCH: u64 = 2_000;
+
+/// Local records checked per `run_reconciliation` call.
+const RECONCILIATION_CHECKS: usize = 2_000;
+
+/// Clears `RECONCILING` when a scan ends or its future is dropped.
+struct ReconciliationGuard;
+
+impl Drop for ReconciliationGuard {
+    fn drop(&mut self) {
+        RECONCILING.with(|r| r.set(false));
+    }
 }
 
-#[query]
-#[candid_method(query)]
-fn get_submissions_by_challenge(challenge_id: u64) -> Vec<UserSubmission> {
-    CHALLENGES.with(|c| {
-        let challenge = match c.borrow().get(&challenge_id) {
-            Some(ch) => ch.clone(),
-            None => return vec![],
-        };
+fn parse_payment_memo(memo: &[u8]) -> Option<PaymentRef> {
+    let id_of = |rest: &[u8]| rest.try_into().ok().map(u64::from_be_bytes);
+    if let Some(rest) = memo.strip_prefix(b"ecoproof:reward:".as_slice()) {
+        return id_of(rest).map(|data_id| PaymentRef::Reward { data_id });
+    }
+    if let Some(rest) = memo.strip_prefix(b"ecoproof:claim:".as_slice()) {
+        return id_of(rest).map(|claim_id| PaymentRef::Claim { claim_id });
+    }
+    None
+}
 
-        SUBMISSIONS.with(|subs| {
-            subs.borrow().iter()
-                .filter(|(_, sub)| {
-                    is_within_geofence(
-                        sub.data.latitude,
-                        sub.data.longitude,
-                        challenge.latitude,
-                        challenge.longitude,
-                        challenge.radius_m,
-                    )
-                })
-                .map(|(_, sub)| sub.clone())
-                .collect()
-        })
+/// Reads a payment of ours out of a block. Transfers count only when they
+/// leave this canister; if it is the minting account they show up as mints.
+fn ledger_payment(block_index: u64, tx: &LedgerTransaction) -> Option<LedgerPayment> {
+    let (to, amount, memo) = match (&tx.transfer, &tx.mint) {
+        (Some(transfer), _) if transfer.from.owner == ic_cdk::id() => (&transfer.to, &transfer.amount, &transfer.memo),
+        (None, Some(mint)) => (&mint.to, &mint.amount, &mint.memo),
+        _ => return None,
+    };
+    Some(LedgerPayment {
+        block_index,
+        payment: parse_payment_memo(memo.as_deref()?)?,
+        to: to.clone(),
+        amount: nat_to_u64(amount),
+        timestamp: tx.timestamp,
     })
 }
 
-// -------- Reward functions --------
-
-#[update]
-#[candid_method(update)]
-async fn reward_user(data_id: u64) -> Result<String, String> {
-    let submission = SUBMISSIONS.with(|subs| subs.borrow().get(&data_id))
-        .ok_or_else(|| format!("Submission {} not found.", data_id))?
-        .clone();
+/// Fetches up to `length` blocks from `start`, following archive callbacks.
+/// Blocks come back by index and may stop short of the requested range.
+async fn fetch_ledger_blocks(start: u64, length: u64) -> Result<(u64, BTreeMap<u64, LedgerTransaction>), DaoError> {
+    let request = GetTransactionsRequest { start: Nat::from(start), length: Nat::from(length) };
+    let (response,): (GetTransactionsResponse,) = call(config().ledger_canister_id, "get_transactions", (request,))
+        .await
+        .map_err(|e| DaoError::LedgerError { message: format!("get_transactions failed: {:?}", e) })?;
+
+    let mut blocks = BTreeMap::new();
+    for archived in response.archived_transactions {
+        let mut next = nat_to_u64(&archived.start);
+        let end = next.saturating_add(nat_to_u64(&archived.length));
+        while next < end {
+            let request = GetTransactionsRequest { start: Nat::from(next), length: Nat::from(end - next) };
+            let callback = &archived.callback.0;
+            let (range,): (TransactionRange,) = call(callback.principal, &callback.method, (request,))
+                .await
+                .map_err(|e| DaoError::LedgerError { message: format!("Archive call failed: {:?}", e) })?;
+            if range.transactions.is_empty() {
+                break;
+            }
+            for tx in range.transactions {
+                blocks.insert(next, tx);
+                next += 1;
+            }
+        }
+    }
+    let first_index = nat_to_u64(&response.first_index);
+    for (offset, tx) in (0u64..).zip(response.transactions) {
+        blocks.insert(first_index + offset, tx);
+    }
+    Ok((nat_to_u64(&response.log_length), blocks))
+}
 
-    if submission.rewarded {
-        return Err("Already rewarded.".to_string());
+/// Adds a discrepancy unless the list is full, in which case the report is
+/// marked as truncated.
+fn push_discrepancy(list: &mut Vec<Discrepancy>, truncated: &mut bool, entry: Discrepancy) {
+    if list.len() < MAX_REPORTED_DISCREPANCIES {
+        list.push(entry);
+    } else {
+        *truncated = true;
     }
+}
 
-    let vote_list = VOTES.with(|vm| vm.borrow().get(&data_id).cloned().unwrap_or_default());
-    let valid_votes = vote_list.iter().filter(|v| v.vote_value).count();
-    let invalid_votes = vote_list.len() - valid_votes;
+/// Ledger side: every payment must be a local payout or claim paid in that
+/// block, for the same amount.
+fn check_ledger_payment(report: &mut ReconciliationReport, payment: &LedgerPayment) {
+    let recorded = match payment.payment {
+        PaymentRef::Reward { data_id } => PAYOUTS.with(|p| p.borrow().get(&data_id))
+            .map(|payout| (payout.state, payout.amount)),
+        PaymentRef::Claim { claim_id } => CLAIMS.with(|c| c.borrow().get(&claim_id))
+            .map(|claim| (claim.state, claim.transfer_amount)),
+    };
+    let discrepancy = |reason: String, expected_amount: Option<u64>| Discrepancy {
+        payment: payment.payment.clone(),
+        block_index: Some(payment.block_index),
+        expected_amount,
+        ledger_amount: Some(payment.amount),
+        reason,
+    };
+    let (list, entry) = match recorded {
+        Some((PayoutState::Paid { block_index }, amount)) if block_index == payment.block_index => {
+            if amount == payment.amount {
+                report.matched += 1;
+                return;
+            }
+            (&mut report.amount_mismatches, discrepancy("Ledger amount differs from the recorded one.".to_string(), Some(amount)))
+        }
+        Some((PayoutState::Paid { block_index }, amount)) => (
+            &mut report.orphaned_payments,
+            discrepancy(format!("Recorded as paid in block {} instead.", block_index), Some(amount)),
+        ),
+        Some((state, amount)) => (&mut report.orphaned_payments, discrepancy(format!("Recorded as {:?}.", state), Some(amount))),
+        None => (&mut report.orphaned_payments, discrepancy("No local record.".to_string(), None)),
+    };
+    push_discrepancy(list, &mut report.truncated, entry);
+}
 
-    if valid_votes <= invalid_votes {
-        return Err("Majority voted invalid, no reward.".to_string());
+/// Local side: every paid block must hold the matching ledger payment.
+fn check_paid(report: &mut ReconciliationReport, payment: PaymentRef, block_index: Option<u64>, expected_amount: Option<u64>) {
+    let Some(block_index) = block_index else {
+        push_discrepancy(&mut report.missing_payments, &mut report.truncated, Discrepancy {
+            payment,
+            block_index: None,
+            expected_amount,
+            ledger_amount: None,
+            reason: "Marked as paid without a ledger transfer.".to_string(),
+        });
+        return;
+    };
+    if block_index >= report.scanned_to {
+        report.unverified += 1;
+        return;
     }
+    let found = LEDGER_PAYMENTS.with(|p| p.borrow().get(&block_index));
+    let matches = match (&payment, &found) {
+        (PaymentRef::Reward { data_id }, Some(LedgerPayment { payment: PaymentRef::Claim { claim_id }, .. })) => {
+            // Claimed rewards are paid by the claim's transfer.
+            CLAIMS.with(|c| c.borrow().get(claim_id)).is_some_and(|claim| {
+                SUBMISSIONS.with(|s| s.borrow().get(data_id)).is_some_and(|sub| sub.user == claim.user)
+            })
+        }
+        (_, Some(found)) => found.payment == payment,
+        (_, None) => false,
+    };
+    if !matches {
+        push_discrepancy(&mut report.missing_payments, &mut report.truncated, Discrepancy {
+            payment,
+            block_index: Some(block_index),
+            expected_amount,
+            ledger_amount: None,
+            reason: "No transfer with this memo in the recorded block.".to_string(),
+        });
+    }
+}
 
-    let user_id = submission.user.clone();
-    let recipient_address = USERS.with(|u| {
-        u.borrow().get(&user_id).and_then(|u| u.wallet_address.clone())
-    }).ok_or("User has no wallet address. Please connect your wallet first.")?;
-
-    let recipient_principal = Principal::from_text(&recipient_address)
-        .map_err(|_| "Recipient address is not a valid principal".to_string())?;
+fn check_rewarded_submission(report: &mut ReconciliationReport, data_id: u64, sub: &UserSubmission) {
+    if !sub.rewarded && sub.status != PostStatus::PAID {
+        return;
+    }
+    let payout = PAYOUTS.with(|p| p.borrow().get(&data_id));
+    let block_index = sub.payout_block_index.or(match payout {
+        Some(Payout { state: PayoutState::Paid { block_index }, .. }) => Some(block_index),
+        _ => None,
+    });
+    let expected_amount = payout.map(|payout| payout.amount);
+    check_paid(report, PaymentRef::Reward { data_id }, block_index, expected_amount);
+}
 
-    let amount_to_send = Nat::from(10_000u64);
+fn check_paid_claim(report: &mut ReconciliationReport, claim_id: u64, claim: &RewardClaim) {
+    if let PayoutState::Paid { block_index } = claim.state {
+        check_paid(report, PaymentRef::Claim { claim_id }, Some(block_index), Some(claim.transfer_amount));
+    }
+}
 
-    let transfer_arg = TransferArg {
-        to: Account {
-            owner: recipient_principal,
-            subaccount: None,
-        },
-        fee: None,
-        memo: None,
-        from_subaccount: None,
-        created_at_time: None,
-        amount: amount_to_send,
+/// Calls `check` on up to `budget` entries after `checked_to`. Returns how
+/// many were visited and the last key, or `None` once the map is done.
+fn check_entries<V: Storable>(
+    map: &StableBTreeMap<u64, V, VirtualMemory<DefaultMemoryImpl>>,
+    checked_to: Option<u64>,
+    budget: usize,
+    mut check: impl FnMut(u64, V),
+) -> (usize, Option<u64>) {
+    use std::ops::Bound as RangeBound;
+
+    let start = match checked_to {
+        Some(key) => RangeBound::Excluded(key),
+        None => RangeBound::Unbounded,
     };
+    let mut visited = 0;
+    let mut last_key = None;
+    let mut entries = map.range((start, RangeBound::Unbounded));
+    for (key, value) in entries.by_ref().take(budget) {
+        check(key, value);
+        last_key = Some(key);
+        visited += 1;
+    }
+    let next = if visited == budget && entries.next().is_some() { last_key } else { None };
+    (visited, next)
+}
 
-    let ledger_canister_id = Principal::from_text("br5f7-7uaaa-aaaaa-qaaca-cai")
-        .map_err(|_| "Invalid ledger canister ID".to_string())?;
+/// Checks up to `RECONCILIATION_CHECKS` records against the ledger payments
+/// read so far. Returns true once the ledger payments, submissions and
+/// claims have all been checked.
+fn advance_reconciliation_check(check: &mut ReconciliationCheck) -> bool {
+    let mut budget = RECONCILIATION_CHECKS;
+    while budget > 0 {
+        let report = &mut check.report;
+        let (visited, next) = match check.stage {
+            // Payments read after the check started belong to the next one.
+            ReconciliationStage::LedgerPayments => LEDGER_PAYMENTS.with(|p| {
+                check_entries(&p.borrow(), check.checked_to, budget, |block_index, payment| {
+                    if block_index < report.scanned_to {
+                        check_ledger_payment(report, &payment);
+                    }
+                })
+            }),
+            ReconciliationStage::Submissions => SUBMISSIONS.with(|s| {
+                check_entries(&s.borrow(), check.checked_to, budget, |data_id, sub| {
+                    check_rewarded_submission(report, data_id, &sub)
+                })
+            }),
+            ReconciliationStage::Claims => CLAIMS.with(|c| {
+                check_entries(&c.borrow(), check.checked_to, budget, |claim_id, claim| {
+                    check_paid_claim(report, claim_id, &claim)
+                })
+            }),
+        };
+        budget -= visited;
+        check.checked_to = next;
+        if next.is_none() {
+            match check.stage {
+                ReconciliationStage::LedgerPayments => check.stage = ReconciliationStage::Submissions,
+                ReconciliationStage::Submissions => check.stage = ReconciliationStage::Claims,
+                ReconciliationStage::Claims => return true,
+            }
+        }
+    }
+    false
+}
 
-    let (transfer_result,): (TransferResult,) = call(
-        ledger_canister_id,
-        "icrc1_transfer",
-        (transfer_arg,),
-    )
-    .await
-    .map_err(|e| format!("Ledger call failed: {:?}", e))?;
-
-    match transfer_result {
-        TransferResult::Ok(block_idx) => {
-            SUBMISSIONS.with(|subs| {
-                let mut subs = subs.borrow_mut();
-                if let Some(existing_sub) = subs.get(&data_id) {
-                    let mut sub = existing_sub.clone();
-                    sub.rewarded = true;
-                    subs.insert(data_id, sub);
-                }
-            });
-            Ok(format!("Successfully rewarded user {} at block {}", user_id, block_idx))
+/// Reads the next `max_blocks` ledger blocks (all new ones up to
+/// `RECONCILIATION_BATCH` by default) and advances the local check by up to
+/// `RECONCILIATION_CHECKS` records. A check covers the blocks read when it
+/// started; once it has walked every record its report replaces the one
+/// returned by `get_reconciliation_report` and the next call starts over.
+#[update]
+#[candid_method(update)]
+async fn run_reconciliation(max_blocks: Option<u64>) -> Result<ReconciliationProgress, DaoError> {
+    require_privileged()?;
+    if RECONCILING.with(|r| r.replace(true)) {
+        return Err(DaoError::InvalidState("A reconciliation is already in progress.".to_string()));
+    }
+    let _running = ReconciliationGuard;
+
+    let start = RECONCILIATION_PROGRESS.with(|p| p.borrow().get().scanned_to);
+    let length = max_blocks.unwrap_or(RECONCILIATION_BATCH).clamp(1, RECONCILIATION_BATCH);
+    let (log_length, blocks) = fetch_ledger_blocks(start, length).await?;
+
+    // Only a gapless prefix counts as read; the rest is fetched again.
+    let mut progress = RECONCILIATION_PROGRESS.with(|p| p.borrow().get().clone());
+    let mut scanned_to = progress.scanned_to;
+    for (block_index, tx) in blocks.range(scanned_to..) {
+        if *block_index != scanned_to {
+            break;
+        }
+        if let Some(payment) = ledger_payment(*block_index, tx) {
+            LEDGER_PAYMENTS.with(|p| p.borrow_mut().insert(*block_index, payment));
+        }
+        scanned_to += 1;
+    }
+    progress.scanned_to = scanned_to;
+    progress.log_length = log_length;
+
+    let mut check = progress.check.take().unwrap_or_else(|| ReconciliationCheck {
+        stage: ReconciliationStage::LedgerPayments,
+        checked_to: None,
+        report: ReconciliationReport {
+            generated_at: time(),
+            scanned_to,
+            log_length,
+            ..ReconciliationReport::default()
         },
-        TransferResult::Err(err) => {
-            Err(format!("Transfer failed: {:?}", err))
+    });
+    if advance_reconciliation_check(&mut check) {
+        let report = check.report;
+        RECONCILIATION_REPORT.with(|r| r.borrow_mut().set(report.clone()))
+            .map_err(|e| DaoError::InvalidInput(format!("Failed to store reconciliation report: {:?}", e)))?;
+        let issues = report.orphaned_payments.len() + report.missing_payments.len() + report.amount_mismatches.len();
+        if issues > 0 {
+            ic_cdk::println!("WARNING: Reconciliation found {} discrepancy(ies) up to block {}", issues, report.scanned_to);
         }
+    } else {
+        progress.check = Some(check);
     }
+
+    RECONCILIATION_PROGRESS.with(|p| p.borrow_mut().set(progress.clone()))
+        .map_err(|e| DaoError::InvalidInput(format!("Failed to store reconciliation progress: {:?}", e)))?;
+    Ok(progress)
+}
+
+/// Report of the last completed check.
+#[query]
+#[candid_method(query)]
+fn get_reconciliation_report() -> ReconciliationReport {
+    RECONCILIATION_REPORT.with(|r| r.borrow().get().clone())
+}
+
+#[query]
+#[candid_method(query)]
+fn get_reconciliation_progress() -> ReconciliationProgress {
+    RECONCILIATION_PROGRESS.with(|p| p.borrow().get().clone())
+}
+
+#[query]
+#[candid_method(query)]
+fn get_ledger_payment(block_index: u64) -> Option<LedgerPayment> {
+    LEDGER_PAYMENTS.with(|p| p.borrow().get(&block_index))
 }
 
 // -------- Canister init --------
 
 #[init]
-fn init() {
+fn init(config: Option<DaoConfig>) {
+    if let Some(config) = config {
+        store_config(config).unwrap_or_else(|e| ic_cdk::trap(&format!("{:?}", e)));
+    }
+    set_schema_version(schema::CURRENT_SCHEMA_VERSION);
+    sync_id_counters();
+    store_integrity_report(check_integrity(Vec::new()));
+    schedule_finalizer();
+    schedule_payout_runner();
     ic_cdk::println!("Canister initialized with StableBTreeMap storage.");
 }
 
 // Role management functions
-// fn is_admin(user_id: &UserId) -> bool {
-//     USERS.with(|users| {
-//         users.borrow()
-//             .get(user_id)
-//             .map(|user| user.role == Role::ADMIN)
-//             .unwrap_or(false)
-//     })
-// }
-
-// fn is_moderator(user_id: &UserId) -> bool {
-//     USERS.with(|users| {
-//         users.borrow()
-//             .get(user_id)
-//             .map(|user| user.role == Role::MODERATOR || user.role == Role::ADMIN)
-//             .unwrap_or(false)
-//     })
-// }
 
+/// Admins, or controllers (which bootstrap the first admin), can change roles.
 #[update]
 #[candid_method(update)]
 async fn update_user_role(
     caller_id: String,
     target_user_id: String,
     new_role: Role,
-) -> Result<String, String> {
+) -> Result<String, DaoError> {
+    let caller = caller_principal()?;
+    let caller_is_controller = ic_cdk::api::is_controller(&caller);
+    if !caller_is_controller {
+        authorize_user(&caller_id)?;
+    }
+
     // Check if caller is admin and get target user in a single scope
     let target_user = USERS.with(|users| {
         let users = users.borrow();
-        let caller_user = users.get(&caller_id).ok_or("Caller not found")?;
-        let target_user = users.get(&target_user_id).ok_or("Target user not found")?;
-        
-        // Allow creating the first admin
-        let is_first_admin = !users.iter().any(|(_, u)| u.role == Role::Admin);
-        
-        if !is_first_admin && caller_user.role != Role::Admin {
-            return Err("Only admins can update roles".to_string());
+        let target_user = users.get(&target_user_id)
+            .ok_or_else(|| DaoError::NotFound("Target user not found".to_string()))?;
+
+        if !caller_is_controller {
+            let caller_user = users.get(&caller_id)
+                .ok_or_else(|| DaoError::NotFound("Caller not found".to_string()))?;
+            if caller_user.role != Role::Admin {
+                return Err(DaoError::Unauthorized("Only admins can update roles".to_string()));
+            }
         }
         
         Ok(target_user.clone())
@@ -1329,38 +5561,251 @@ async fn update_user_role(
 }
 
 #[query]
-fn get_user_role(user_id: String) -> Result<Role, String> {
+#[candid_method(query)]
+fn get_user_role(user_id: String) -> Result<Role, DaoError> {
     USERS.with(|users| {
         users.borrow()
             .get(&user_id)
             .map(|user| user.role.clone())
-            .ok_or_else(|| "User not found".to_string())
+            .ok_or_else(|| DaoError::NotFound("User not found".to_string()))
     })
 }
 
 #[update]
 #[candid_method(update)]
-fn mark_submission_rewarded(data_id: u64) -> Result<String, String> {
+fn mark_submission_rewarded(data_id: u64) -> Result<String, DaoError> {
+    require_privileged()?;
+    mark_rewarded(data_id)
+}
+
+/// Marks a post as paid outside the canister.
+fn mark_rewarded(data_id: u64) -> Result<String, DaoError> {
     // First check if the submission exists
     let submission = SUBMISSIONS.with(|subs| {
         subs.borrow().get(&data_id).clone()
-    }).ok_or_else(|| format!("Submission {} not found", data_id))?;
+    }).ok_or_else(|| DaoError::NotFound(format!("Submission {} not found", data_id)))?;
 
     // Check if already rewarded
     if submission.rewarded {
-        return Err("Submission already rewarded".to_string());
+        return Err(DaoError::AlreadyRewarded);
+    }
+
+    // A credit still in the balance is settled by this and can no longer be
+    // claimed; a payout holding its funds already took it out.
+    let remaining = submission.reward_credit.saturating_sub(submission.claimed);
+    let held_by_payout = PAYOUTS.with(|p| p.borrow().get(&data_id)).is_some_and(|payout| holds_payout_funds(&payout));
+    if remaining > 0 && !held_by_payout {
+        debit_balance(&submission.user, remaining, BalanceChange::MarkedRewarded { data_id })?;
     }
 
     // Update the submission status
-    SUBMISSIONS.with(|subs| {
-        let mut subs = subs.borrow_mut();
-        if let Some(mut sub) = subs.get(&data_id) {
-            sub.rewarded = true;
-            sub.status = PostStatus::PAID;
-            subs.insert(data_id, sub.clone());
-            Ok(format!("Successfully marked submission {} as rewarded", data_id))
-        } else {
-            Err(format!("Failed to update submission {}", data_id))
-        }
+    update_submission(data_id, |sub| {
+        sub.rewarded = true;
+        sub.status = PostStatus::PAID;
     })
-}
\ No newline at end of file
+    .map(|_| format!("Successfully marked submission {} as rewarded", data_id))
+    .ok_or_else(|| DaoError::NotFound(format!("Failed to update submission {}", data_id)))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn escrow(challenge_id: u64, deposited: u64) -> ChallengeEscrow {
+        ChallengeEscrow {
+            challenge_id,
+            sponsor: Account { owner: Principal::anonymous(), subaccount: None },
+            transfer_fee: 10,
+            deposited,
+            reserved: 0,
+            paid: 0,
+            deposits: Vec::new(),
+            refund: None,
+        }
+    }
+
+    fn escrow_payout(data_id: u64, challenge_id: u64, amount: u64) -> Payout {
+        Payout {
+            data_id,
+            to: Account { owner: Principal::anonymous(), subaccount: None },
+            amount,
+            fee: None,
+            memo: reward_memo(data_id),
+            created_at_time: None,
+            state: PayoutState::Scheduled,
+            attempts: 0,
+            escrow_challenge_id: Some(challenge_id),
+            ledger_canister_id: None,
+            balance_debit: 0,
+        }
+    }
+
+    fn store_user(user_id: &str, balance: u64) {
+        let user = User {
+            user_id: user_id.to_string(),
+            balance,
+            first_name: None,
+            last_name: None,
+            username: None,
+            language_code: None,
+            is_bot: false,
+            profile_picture_url: None,
+            wallet_address: None,
+            role: Role::User,
+        };
+        USERS.with(|u| u.borrow_mut().insert(user_id.to_string(), user));
+    }
+
+    fn balance_of(user_id: &str) -> u64 {
+        USERS.with(|u| u.borrow().get(&user_id.to_string())).unwrap().balance
+    }
+
+    fn store_credited_post(data_id: u64, user_id: &str, reward_credit: u64, claimed: u64) {
+        store_submission(&UserSubmission {
+            data_id,
+            user: user_id.to_string(),
+            data: WeatherData {
+                latitude: 0.0,
+                longitude: 0.0,
+                city: "Lisbon".to_string(),
+                temperature: 20.0,
+                weather: "Sunny".to_string(),
+                timestamp: 0,
+                submission_photo_url: String::new(),
+            },
+            rewarded: false,
+            status: PostStatus::PENDING,
+            expiration_timestamp: 0,
+            finalization: None,
+            duplicate_of: None,
+            challenge_id: None,
+            reward_credit,
+            claimed,
+            payout_block_index: None,
+        });
+    }
+
+    fn balance_payout(data_id: u64, balance_debit: u64) -> Payout {
+        Payout {
+            escrow_challenge_id: None,
+            balance_debit,
+            ..escrow_payout(data_id, 0, balance_debit)
+        }
+    }
+
+    #[test]
+    fn paid_escrow_payout_keeps_other_reservations() {
+        store_escrow(&escrow(1, 1_000));
+        let mut paid = escrow_payout(1, 1, 100);
+        let pending = escrow_payout(2, 1, 200);
+        reserve_escrow(1, paid.amount, paid.fee).unwrap();
+        reserve_escrow(1, pending.amount, pending.fee).unwrap();
+        assert_eq!(escrow_of(1).unwrap().reserved, 110 + 210);
+
+        paid.created_at_time = Some(1);
+        paid.state = PayoutState::Paid { block_index: 7 };
+        settle_payout_attempt(&paid, "");
+
+        let after = escrow_of(1).unwrap();
+        assert_eq!(after.reserved, 210);
+        assert_eq!(after.paid, 110);
+        assert_eq!(after.available(), 1_000 - 210 - 110);
+    }
+
+    #[test]
+    fn rejected_escrow_payout_releases_its_reservation() {
+        store_escrow(&escrow(2, 1_000));
+        let mut rejected = escrow_payout(3, 2, 100);
+        let mut uncertain = escrow_payout(4, 2, 200);
+        reserve_escrow(2, rejected.amount, rejected.fee).unwrap();
+        reserve_escrow(2, uncertain.amount, uncertain.fee).unwrap();
+
+        rejected.state = PayoutState::Failed { reason: "Transfer failed".to_string() };
+        settle_payout_attempt(&rejected, "");
+        // The call may have reached the ledger, so the cost stays set aside.
+        uncertain.created_at_time = Some(1);
+        uncertain.state = PayoutState::Failed { reason: "Ledger call failed".to_string() };
+        settle_payout_attempt(&uncertain, "");
+
+        let after = escrow_of(2).unwrap();
+        assert_eq!(after.reserved, 210);
+        assert_eq!(after.paid, 0);
+    }
+
+    #[test]
+    fn marking_rewarded_debits_the_remaining_credit() {
+        store_user("alice", 500);
+        store_credited_post(10, "alice", 300, 100);
+
+        mark_rewarded(10).unwrap();
+
+        assert_eq!(balance_of("alice"), 300);
+        let sub = SUBMISSIONS.with(|s| s.borrow().get(&10)).unwrap();
+        assert!(sub.rewarded);
+        assert_eq!(sub.status, PostStatus::PAID);
+    }
+
+    #[test]
+    fn marking_rewarded_leaves_a_payout_debit_alone() {
+        store_user("bob", 0);
+        store_credited_post(11, "bob", 200, 0);
+        store_payout(&balance_payout(11, 200));
+
+        mark_rewarded(11).unwrap();
+
+        assert_eq!(balance_of("bob"), 0);
+    }
+
+    #[test]
+    fn rejected_payout_gives_the_balance_back() {
+        store_user("carol", 0);
+        let mut payout = balance_payout(12, 200);
+        payout.state = PayoutState::Failed { reason: "Transfer failed".to_string() };
+        settle_payout_attempt(&payout, "carol");
+        assert_eq!(balance_of("carol"), 200);
+
+        // The next attempt takes it again.
+        retake_payout_funds(&payout, "carol").unwrap();
+        assert_eq!(balance_of("carol"), 0);
+    }
+
+    #[test]
+    fn uncertain_payout_keeps_the_balance_debit() {
+        store_user("dave", 0);
+        let mut payout = balance_payout(13, 200);
+        payout.created_at_time = Some(1);
+        payout.state = PayoutState::Failed { reason: "Ledger call failed".to_string() };
+        settle_payout_attempt(&payout, "dave");
+        assert_eq!(balance_of("dave"), 0);
+    }
+
+    fn challenge(expiration: u64, state: ChallengeState) -> Challenge {
+        Challenge {
+            id: 1,
+            title: "Rain in Lisbon".to_string(),
+            latitude: 38.7,
+            longitude: -9.1,
+            radius_m: 1_000.0,
+            expiration,
+            picture_url: String::new(),
+            has_boundary: false,
+            creator: Principal::anonymous(),
+            start_time: 0,
+            max_accepted: None,
+            required_fields: all_weather_fields(),
+            state,
+        }
+    }
+
+    #[test]
+    fn expiring_soon_excludes_ended_challenges() {
+        let now = time();
+        let window = secs_to_ns(3_600);
+        assert!(expires_within(&challenge(now + HOUR_NS / 2, ChallengeState::Active), now, window));
+        assert!(expires_within(&challenge(now + HOUR_NS, ChallengeState::Active), now, window));
+        assert!(!expires_within(&challenge(now + 2 * HOUR_NS, ChallengeState::Active), now, window));
+        // Ended but not closed yet.
+        assert!(!expires_within(&challenge(now - 1, ChallengeState::Active), now, window));
+        assert!(!expires_within(&challenge(now, ChallengeState::Active), now, window));
+        assert!(!expires_within(&challenge(now + HOUR_NS / 2, ChallengeState::Draft), now, window));
+    }
+}
@@ -0,0 +1,403 @@
+//! Versioned CBOR layouts of the stable maps and the migrations between them.
+//!
+//! The schema version is kept in stable memory. On upgrade, every migration
+//! from the stored version up to `CURRENT_SCHEMA_VERSION` runs in order, before
+//! any typed map is opened. A migration reads the records of a map in their
+//! old layout and rewrites them in the next one; a record that cannot be
+//! decoded traps, which rolls the whole upgrade back.
+//!
+//! New fields marked `#[serde(default)]` decode from the current layout as-is
+//! and do not need a new version.
+
+use std::borrow::Cow;
+
+use candid::{CandidType, Principal};
+use ic_stable_structures::{
+    memory_manager::MemoryId,
+    storable::{Bound, Storable},
+    StableBTreeMap,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{
+    add_index_entries, all_weather_fields, index_challenge, infer_challenge_id, submission_cell, Challenge, ChallengeKey, ChallengeState, FinalizationRecord,
+    PostStatus, Role, User, UserId, UserSubmission, WeatherData, CHALLENGES_MEMORY_ID, MEMORY_MANAGER, SUBMISSIONS_BY_CHALLENGE, SUBMISSIONS_MEMORY_ID,
+    SUBMISSION_CELLS, USERS_MEMORY_ID,
+};
+
+/// Layout version written by this build.
+pub const CURRENT_SCHEMA_VERSION: u32 = 4;
+
+/// Records rewritten or indexed by one migration step.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MigrationStep {
+    pub from_version: u32,
+    pub to_version: u32,
+    pub submissions: u64,
+    pub users: u64,
+    pub challenges: u64,
+}
+
+/// Runs every migration needed to bring `stored_version` to
+/// `CURRENT_SCHEMA_VERSION`. Must run before any typed map is touched.
+pub fn migrate(stored_version: u32) -> Vec<MigrationStep> {
+    if stored_version > CURRENT_SCHEMA_VERSION {
+        ic_cdk::trap(&format!(
+            "Stable memory has schema version {}, this build only knows up to {}.",
+            stored_version, CURRENT_SCHEMA_VERSION
+        ));
+    }
+
+    let mut steps = Vec::new();
+    for from_version in stored_version..CURRENT_SCHEMA_VERSION {
+        let step = match from_version {
+            0 => migrate_v0_to_v1(),
+            1 => migrate_v1_to_v2(),
+            2 => migrate_v2_to_v3(),
+            3 => migrate_v3_to_v4(),
+            _ => unreachable!("no migration from schema version {}", from_version),
+        };
+        ic_cdk::println!("INFO: Applied schema migration {:?}", step);
+        steps.push(step);
+    }
+    steps
+}
+
+// -------- v0: layouts written before the schema version was stored --------
+
+pub mod v0 {
+    use super::*;
+
+    /// Also the candid layout of the legacy `stable_save` snapshot, so it
+    /// must not gain fields that candid cannot default.
+    #[derive(CandidType, Deserialize)]
+    pub struct UserSubmission {
+        pub data_id: u64,
+        pub user: UserId,
+        pub data: WeatherData,
+        pub rewarded: bool,
+        pub status: PostStatus,
+        pub expiration_timestamp: u64,
+        /// Only written by the last unversioned builds.
+        #[serde(default)]
+        pub finalization: Option<FinalizationRecord>,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    pub struct User {
+        pub user_id: UserId,
+        pub balance: u64,
+        pub first_name: Option<String>,
+        pub last_name: Option<String>,
+        pub username: Option<String>,
+        pub language_code: Option<String>,
+        pub is_bot: bool,
+        pub profile_picture_url: Option<String>,
+        pub wallet_address: Option<String>,
+        pub role: Role,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Challenge {
+        pub id: u64,
+        pub title: String,
+        pub latitude: f64,
+        pub longitude: f64,
+        pub radius_m: f64,
+        pub expiration: u64,
+        pub picture_url: String,
+    }
+
+    impl UserSubmission {
+        pub fn into_current(self, data_id: u64) -> super::UserSubmission {
+            super::UserSubmission {
+                data_id,
+                user: self.user,
+                data: self.data,
+                rewarded: self.rewarded,
+                status: self.status,
+                expiration_timestamp: self.expiration_timestamp,
+                finalization: self.finalization,
+                duplicate_of: None,
+                challenge_id: None,
+                reward_credit: 0,
+                claimed: 0,
+                payout_block_index: None,
+            }
+        }
+    }
+
+    impl From<User> for super::User {
+        fn from(old: User) -> Self {
+            super::User {
+                user_id: old.user_id,
+                balance: old.balance,
+                first_name: old.first_name,
+                last_name: old.last_name,
+                username: old.username,
+                language_code: old.language_code,
+                is_bot: old.is_bot,
+                profile_picture_url: old.profile_picture_url,
+                wallet_address: old.wallet_address,
+                role: old.role,
+            }
+        }
+    }
+}
+
+/// Submissions and users written with `stable_save` by the `pre_upgrade` of
+/// unversioned builds. Decoded with the frozen v0 layouts: candid ignores
+/// serde defaults, so fields added since would fail to decode.
+pub type LegacySnapshot = (Vec<(u64, v0::UserSubmission)>, Vec<(UserId, v0::User)>);
+
+/// v1 keeps the v0 fields. Records are rewritten canonically and the ids
+/// embedded in submissions and challenges are aligned with their map keys,
+/// which the legacy `stable_save` restore did not guarantee.
+fn migrate_v0_to_v1() -> MigrationStep {
+    let submissions = rewrite_values(SUBMISSIONS_MEMORY_ID, "UserSubmission", |key: &u64, old: v0::UserSubmission| {
+        if old.data_id != *key {
+            ic_cdk::println!("INFO: Submission {} stored under key {}, renumbering.", old.data_id, key);
+        }
+        old.into_current(*key)
+    });
+    let users = rewrite_values(USERS_MEMORY_ID, "User", |_: &UserId, old: v0::User| User::from(old));
+    let challenges = rewrite_values(CHALLENGES_MEMORY_ID, "Challenge", |key: &u64, old: v0::Challenge| {
+        if old.id != *key {
+            ic_cdk::println!("INFO: Challenge {} stored under key {}, renumbering.", old.id, key);
+        }
+        Challenge {
+            id: *key,
+            title: old.title,
+            latitude: old.latitude,
+            longitude: old.longitude,
+            radius_m: old.radius_m,
+            expiration: old.expiration,
+            picture_url: old.picture_url,
+            has_boundary: false,
+            creator: Principal::anonymous(),
+            start_time: 0,
+            max_accepted: None,
+            required_fields: all_weather_fields(),
+            state: ChallengeState::Active,
+        }
+    });
+
+    MigrationStep { from_version: 0, to_version: 1, submissions, users, challenges }
+}
+
+/// v2 adds the user, city and status indexes of submissions; the record
+/// layouts are unchanged.
+fn migrate_v1_to_v2() -> MigrationStep {
+    let submissions = for_each_value(SUBMISSIONS_MEMORY_ID, "UserSubmission", |_: &u64, sub: UserSubmission| {
+        add_index_entries(&sub)
+    });
+    MigrationStep { from_version: 1, to_version: 2, submissions, ..MigrationStep::default() }
+}
+
+/// v3 adds the spatial indexes of submissions and challenges.
+fn migrate_v2_to_v3() -> MigrationStep {
+    let submissions = for_each_value(SUBMISSIONS_MEMORY_ID, "UserSubmission", |_: &u64, sub: UserSubmission| {
+        SUBMISSION_CELLS.with(|i| i.borrow_mut().insert(submission_cell(&sub), ()));
+    });
+    let challenges = for_each_value(CHALLENGES_MEMORY_ID, "Challenge", |_: &u64, challenge: Challenge| {
+        index_challenge(&challenge)
+    });
+    MigrationStep { from_version: 2, to_version: 3, submissions, challenges, ..MigrationStep::default() }
+}
+
+/// v4 links posts made before submissions recorded their challenge, which
+/// used to be matched by geofence alone, to the challenge they fall in.
+/// Counts the submissions linked.
+fn migrate_v3_to_v4() -> MigrationStep {
+    let mut submissions = 0;
+    rewrite_values(SUBMISSIONS_MEMORY_ID, "UserSubmission", |_: &u64, mut sub: UserSubmission| {
+        if sub.challenge_id.is_none() {
+            if let Some(challenge_id) = infer_challenge_id(&sub) {
+                sub.challenge_id = Some(challenge_id);
+                SUBMISSIONS_BY_CHALLENGE.with(|i| i.borrow_mut().insert(ChallengeKey { challenge_id, data_id: sub.data_id }, ()));
+                submissions += 1;
+            }
+        }
+        sub
+    });
+    MigrationStep { from_version: 3, to_version: 4, submissions, ..MigrationStep::default() }
+}
+
+// -------- Helpers --------
+
+/// A map value as stored, without decoding it.
+struct RawValue(Vec<u8>);
+
+impl Storable for RawValue {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RawValue(bytes.into_owned())
+    }
+}
+
+/// Decodes every value of the map in `memory_id` as `V` and passes it to `f`.
+/// Returns the number of records visited.
+fn for_each_value<K, V>(memory_id: MemoryId, record: &str, mut f: impl FnMut(&K, V)) -> u64
+where
+    K: Storable + Ord + Clone,
+    V: DeserializeOwned,
+{
+    let memory = MEMORY_MANAGER.with(|m| m.borrow().get(memory_id));
+    let map: StableBTreeMap<K, RawValue, _> = StableBTreeMap::init(memory);
+
+    let mut count = 0;
+    for (key, raw) in map.iter() {
+        let value: V = serde_cbor::from_slice(&raw.0)
+            .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to decode {} for migration: {}", record, e)));
+        f(&key, value);
+        count += 1;
+    }
+    count
+}
+
+/// Decodes every value of the map in `memory_id` as `Old` and stores it back
+/// as `New`. Returns the number of records rewritten.
+fn rewrite_values<K, Old, New>(memory_id: MemoryId, record: &str, mut convert: impl FnMut(&K, Old) -> New) -> u64
+where
+    K: Storable + Ord + Clone,
+    Old: DeserializeOwned,
+    New: Serialize,
+{
+    let memory = MEMORY_MANAGER.with(|m| m.borrow().get(memory_id));
+    let mut map: StableBTreeMap<K, RawValue, _> = StableBTreeMap::init(memory);
+
+    let keys: Vec<K> = map.iter().map(|(key, _)| key).collect();
+    for key in &keys {
+        let raw = map.get(key).expect("key was just listed");
+        let old: Old = serde_cbor::from_slice(&raw.0).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!("Failed to decode {} for migration: {}", record, e))
+        });
+        let new = serde_cbor::to_vec(&convert(key, old))
+            .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to encode {} for migration: {}", record, e)));
+        map.insert(key.clone(), RawValue(new));
+    }
+    keys.len() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `UserSubmission` and `User` exactly as the unversioned builds encoded
+    /// them in `pre_upgrade`.
+    mod baseline {
+        use super::super::{PostStatus, Role, UserId, WeatherData};
+        use candid::CandidType;
+        use serde::Serialize;
+
+        #[derive(CandidType, Serialize)]
+        pub struct UserSubmission {
+            pub data_id: u64,
+            pub user: UserId,
+            pub data: WeatherData,
+            pub rewarded: bool,
+            pub status: PostStatus,
+            pub expiration_timestamp: u64,
+        }
+
+        #[derive(CandidType, Serialize)]
+        pub struct User {
+            pub user_id: UserId,
+            pub balance: u64,
+            pub first_name: Option<String>,
+            pub last_name: Option<String>,
+            pub username: Option<String>,
+            pub language_code: Option<String>,
+            pub is_bot: bool,
+            pub profile_picture_url: Option<String>,
+            pub wallet_address: Option<String>,
+            pub role: Role,
+        }
+    }
+
+    fn weather() -> WeatherData {
+        WeatherData {
+            latitude: 52.52,
+            longitude: 13.405,
+            city: "Berlin".to_string(),
+            temperature: 21.5,
+            weather: "Clear".to_string(),
+            timestamp: 1_700_000_000_000_000_000,
+            submission_photo_url: "https://example.com/photo.jpg".to_string(),
+        }
+    }
+
+    #[test]
+    fn decodes_baseline_snapshot() {
+        let submissions = vec![(
+            7u64,
+            baseline::UserSubmission {
+                data_id: 7,
+                user: "42".to_string(),
+                data: weather(),
+                rewarded: true,
+                status: PostStatus::PAID,
+                expiration_timestamp: 1_700_000_900_000_000_000,
+            },
+        )];
+        let users = vec![(
+            "42".to_string(),
+            baseline::User {
+                user_id: "42".to_string(),
+                balance: 10_000,
+                first_name: Some("Ada".to_string()),
+                last_name: None,
+                username: Some("ada".to_string()),
+                language_code: Some("en".to_string()),
+                is_bot: false,
+                profile_picture_url: None,
+                wallet_address: Some("aaaaa-aa".to_string()),
+                role: Role::Moderator,
+            },
+        )];
+        let bytes = candid::encode_args((submissions, users)).unwrap();
+
+        let (submissions, users): LegacySnapshot = candid::decode_args(&bytes).unwrap();
+
+        let (key, old) = submissions.into_iter().next().unwrap();
+        let sub = old.into_current(key);
+        assert_eq!(sub.data_id, 7);
+        assert_eq!(sub.user, "42");
+        assert_eq!(sub.data.city, "Berlin");
+        assert!(sub.rewarded);
+        assert_eq!(sub.status, PostStatus::PAID);
+        assert!(sub.finalization.is_none());
+        assert_eq!((sub.reward_credit, sub.claimed), (0, 0));
+        assert_eq!(sub.challenge_id, None);
+
+        let (user_id, old) = users.into_iter().next().unwrap();
+        let user = User::from(old);
+        assert_eq!(user_id, "42");
+        assert_eq!(user.balance, 10_000);
+        assert_eq!(user.wallet_address.as_deref(), Some("aaaaa-aa"));
+        assert_eq!(user.role, Role::Moderator);
+    }
+
+    #[test]
+    fn current_layout_rejects_baseline_snapshot() {
+        let submissions = vec![(
+            1u64,
+            baseline::UserSubmission {
+                data_id: 1,
+                user: "1".to_string(),
+                data: weather(),
+                rewarded: false,
+                status: PostStatus::OPEN,
+                expiration_timestamp: 0,
+            },
+        )];
+        let bytes = candid::encode_args((submissions, Vec::<(UserId, baseline::User)>::new())).unwrap();
+
+        assert!(candid::decode_args::<(Vec<(u64, UserSubmission)>, Vec<(UserId, User)>)>(&bytes).is_err());
+    }
+}
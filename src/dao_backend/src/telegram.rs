@@ -0,0 +1,152 @@
+//! Verification of Telegram Mini App `initData` strings.
+//!
+//! See <https://core.telegram.org/bots/webapps#validating-data-received-via-the-mini-app>.
+
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Allowed clock skew between Telegram and the IC when checking `auth_date`.
+const MAX_CLOCK_SKEW_SECS: u64 = 60;
+
+/// The `user` field of `initData`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct WebAppUser {
+    pub id: u64,
+    pub first_name: String,
+    pub last_name: Option<String>,
+    pub username: Option<String>,
+    pub language_code: Option<String>,
+    #[serde(default)]
+    pub is_bot: bool,
+    pub photo_url: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct VerifiedInitData {
+    pub user: WebAppUser,
+    pub auth_date: u64,
+    /// The signature, which identifies this `initData` for replay checks.
+    pub hash: [u8; 32],
+}
+
+/// Checks the `hash` of `init_data` against `bot_token` and that `auth_date`
+/// is at most `max_age_secs` older than `now_secs`.
+pub fn verify_init_data(
+    init_data: &str,
+    bot_token: &str,
+    now_secs: u64,
+    max_age_secs: u64,
+) -> Result<VerifiedInitData, String> {
+    let mut hash = None;
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for (key, value) in form_urlencoded::parse(init_data.as_bytes()) {
+        if key == "hash" {
+            if hash.is_some() {
+                return Err("initData contains more than one hash.".to_string());
+            }
+            hash = Some(value.into_owned());
+        } else {
+            fields.push((key.into_owned(), value.into_owned()));
+        }
+    }
+
+    let hash = hash.ok_or("initData has no hash.")?;
+    let hash = hex::decode(hash).map_err(|_| "initData hash is not valid hex.".to_string())?;
+
+    fields.sort_by(|a, b| a.0.cmp(&b.0));
+    let data_check_string = fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut secret = HmacSha256::new_from_slice(b"WebAppData").expect("HMAC accepts any key length");
+    secret.update(bot_token.as_bytes());
+    let secret_key = secret.finalize().into_bytes();
+
+    let mut mac = HmacSha256::new_from_slice(&secret_key).expect("HMAC accepts any key length");
+    mac.update(data_check_string.as_bytes());
+    mac.verify_slice(&hash)
+        .map_err(|_| "initData signature is invalid.".to_string())?;
+    let hash: [u8; 32] = hash.try_into().expect("a verified HMAC-SHA256 is 32 bytes");
+
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+            .ok_or_else(|| format!("initData has no {}.", name))
+    };
+
+    let auth_date: u64 = field("auth_date")?
+        .parse()
+        .map_err(|_| "initData auth_date is not a number.".to_string())?;
+    if auth_date > now_secs + MAX_CLOCK_SKEW_SECS {
+        return Err("initData auth_date is in the future.".to_string());
+    }
+    if now_secs.saturating_sub(auth_date) > max_age_secs {
+        return Err("initData has expired.".to_string());
+    }
+
+    let user: WebAppUser = serde_json::from_str(field("user")?)
+        .map_err(|e| format!("initData user is malformed: {}", e))?;
+
+    Ok(VerifiedInitData { user, auth_date, hash })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOT_TOKEN: &str = "123456789:AAEhBP0av28Fnt4Dn4G5Xe8WLv5QbPEYx3o";
+    const AUTH_DATE: u64 = 1_700_000_000;
+    const MAX_AGE: u64 = 24 * 60 * 60;
+    /// Signed with `BOT_TOKEN` independently of this module.
+    const INIT_DATA: &str = "auth_date=1700000000&query_id=AAHdF6IQAAAAAN0XohDhrOrc\
+        &user=%7B%22id%22%3A279058397%2C%22first_name%22%3A%22Vladislav%22%2C%22last_name%22%3A%22Kibenko%22\
+        %2C%22username%22%3A%22vdkfrost%22%2C%22language_code%22%3A%22ru%22%2C%22is_premium%22%3Atrue%7D\
+        &hash=2f57aef8feb632754ad8620fe51130b3558e8ddd0d52f76b07b73debe565dfd8";
+
+    #[test]
+    fn accepts_valid_init_data() {
+        let verified = verify_init_data(INIT_DATA, BOT_TOKEN, AUTH_DATE + 10, MAX_AGE).unwrap();
+        assert_eq!(verified.auth_date, AUTH_DATE);
+        assert_eq!(verified.user.id, 279_058_397);
+        assert_eq!(verified.user.first_name, "Vladislav");
+        assert_eq!(verified.user.username.as_deref(), Some("vdkfrost"));
+        assert!(!verified.user.is_bot);
+        assert_eq!(hex::encode(verified.hash), "2f57aef8feb632754ad8620fe51130b3558e8ddd0d52f76b07b73debe565dfd8");
+    }
+
+    #[test]
+    fn rejects_tampered_field() {
+        let tampered = INIT_DATA.replace("279058397", "279058398");
+        let err = verify_init_data(&tampered, BOT_TOKEN, AUTH_DATE, MAX_AGE).unwrap_err();
+        assert_eq!(err, "initData signature is invalid.");
+    }
+
+    #[test]
+    fn rejects_wrong_bot_token() {
+        let err = verify_init_data(INIT_DATA, "987654321:OTHER-TOKEN", AUTH_DATE, MAX_AGE).unwrap_err();
+        assert_eq!(err, "initData signature is invalid.");
+    }
+
+    #[test]
+    fn rejects_stale_auth_date() {
+        let err = verify_init_data(INIT_DATA, BOT_TOKEN, AUTH_DATE + MAX_AGE + 1, MAX_AGE).unwrap_err();
+        assert_eq!(err, "initData has expired.");
+        let err = verify_init_data(INIT_DATA, BOT_TOKEN, AUTH_DATE - MAX_CLOCK_SKEW_SECS - 1, MAX_AGE).unwrap_err();
+        assert_eq!(err, "initData auth_date is in the future.");
+    }
+
+    #[test]
+    fn rejects_missing_hash() {
+        let without_hash = INIT_DATA.split("&hash=").next().unwrap();
+        let err = verify_init_data(without_hash, BOT_TOKEN, AUTH_DATE, MAX_AGE).unwrap_err();
+        assert_eq!(err, "initData has no hash.");
+    }
+}
+
@@ -0,0 +1,273 @@
+//! Validation of user-supplied fields on the write paths.
+//!
+//! Every check of a request is run and all failures are returned together,
+//! so clients can flag each offending field at once.
+
+use std::borrow::Cow;
+
+use candid::CandidType;
+use ic_stable_structures::storable::{Bound, Storable};
+use serde::{Deserialize, Serialize};
+
+/// Bounds applied to submissions, challenges and user profiles. Lengths are
+/// in UTF-8 bytes.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ValidationLimits {
+    pub min_latitude: f64,
+    pub max_latitude: f64,
+    pub min_longitude: f64,
+    pub max_longitude: f64,
+    /// Plausible air temperatures in °C.
+    pub min_temperature: f64,
+    pub max_temperature: f64,
+    pub max_challenge_radius_m: f64,
+    /// Cities, weather descriptions and profile names.
+    pub max_name_bytes: u32,
+    pub max_title_bytes: u32,
+    pub max_url_bytes: u32,
+    /// Lowercase schemes accepted in photo and picture URLs, e.g. `https`.
+    pub allowed_url_schemes: Vec<String>,
+    /// Positions, over all rings, of a polygon geofence.
+    #[serde(default = "default_max_boundary_vertices")]
+    pub max_boundary_vertices: u32,
+}
+
+fn default_max_boundary_vertices() -> u32 {
+    10_000
+}
+
+impl Default for ValidationLimits {
+    fn default() -> Self {
+        ValidationLimits {
+            min_latitude: -90.0,
+            max_latitude: 90.0,
+            min_longitude: -180.0,
+            max_longitude: 180.0,
+            min_temperature: -90.0,
+            max_temperature: 60.0,
+            max_challenge_radius_m: 100_000.0,
+            max_name_bytes: 100,
+            max_title_bytes: 120,
+            max_url_bytes: 256,
+            allowed_url_schemes: vec!["https".to_string()],
+            max_boundary_vertices: default_max_boundary_vertices(),
+        }
+    }
+}
+
+impl Storable for ValidationLimits {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("ValidationLimits serialization failed"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("ValidationLimits deserialization failed")
+    }
+}
+
+impl ValidationLimits {
+    /// Checks that the limits themselves are usable.
+    pub fn check(&self) -> Result<(), String> {
+        let ranges = [
+            ("latitude", self.min_latitude, self.max_latitude, -90.0, 90.0),
+            ("longitude", self.min_longitude, self.max_longitude, -180.0, 180.0),
+            ("temperature", self.min_temperature, self.max_temperature, f64::MIN, f64::MAX),
+        ];
+        for (name, min, max, lowest, highest) in ranges {
+            if !(min.is_finite() && max.is_finite() && lowest <= min && min < max && max <= highest) {
+                return Err(format!("Invalid {} range [{}, {}].", name, min, max));
+            }
+        }
+        if !(self.max_challenge_radius_m.is_finite() && self.max_challenge_radius_m > 0.0) {
+            return Err("max_challenge_radius_m must be positive.".to_string());
+        }
+        if self.max_name_bytes == 0 || self.max_title_bytes == 0 || self.max_url_bytes == 0 || self.max_boundary_vertices == 0 {
+            return Err("Length limits must be positive.".to_string());
+        }
+        if self.allowed_url_schemes.is_empty() {
+            return Err("At least one URL scheme must be allowed.".to_string());
+        }
+        if let Some(scheme) = self.allowed_url_schemes.iter().find(|s| !is_scheme(s)) {
+            return Err(format!("{:?} is not a lowercase URL scheme.", scheme));
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ValidationIssue {
+    /// NaN or infinite.
+    NotFinite,
+    OutOfRange { min: f64, max: f64 },
+    NotPositive,
+    Empty,
+    TooLong { max_bytes: u32 },
+    InvalidUrl,
+    SchemeNotAllowed { scheme: String },
+    InvalidGeometry { reason: String },
+    /// A time that must come after `other`.
+    NotAfter { other: String },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub issue: ValidationIssue,
+}
+
+/// Collects the failures of a series of field checks.
+pub struct Validator<'a> {
+    limits: &'a ValidationLimits,
+    errors: Vec<FieldError>,
+}
+
+impl<'a> Validator<'a> {
+    pub fn new(limits: &'a ValidationLimits) -> Self {
+        Validator { limits, errors: Vec::new() }
+    }
+
+    fn fail(&mut self, field: &str, issue: ValidationIssue) {
+        self.errors.push(FieldError { field: field.to_string(), issue });
+    }
+
+    pub fn range(mut self, field: &str, value: f64, min: f64, max: f64) -> Self {
+        if !value.is_finite() {
+            self.fail(field, ValidationIssue::NotFinite);
+        } else if value < min || value > max {
+            self.fail(field, ValidationIssue::OutOfRange { min, max });
+        }
+        self
+    }
+
+    pub fn latitude(self, field: &str, value: f64) -> Self {
+        let (min, max) = (self.limits.min_latitude, self.limits.max_latitude);
+        self.range(field, value, min, max)
+    }
+
+    pub fn longitude(self, field: &str, value: f64) -> Self {
+        let (min, max) = (self.limits.min_longitude, self.limits.max_longitude);
+        self.range(field, value, min, max)
+    }
+
+    pub fn temperature(self, field: &str, value: f64) -> Self {
+        let (min, max) = (self.limits.min_temperature, self.limits.max_temperature);
+        self.range(field, value, min, max)
+    }
+
+    pub fn radius(mut self, field: &str, value: f64) -> Self {
+        if value.is_finite() && value <= 0.0 {
+            self.fail(field, ValidationIssue::NotPositive);
+            return self;
+        }
+        let max = self.limits.max_challenge_radius_m;
+        self.range(field, value, 0.0, max)
+    }
+
+    /// A required string of at most `max_bytes` bytes.
+    pub fn text(mut self, field: &str, value: &str, max_bytes: u32) -> Self {
+        if value.trim().is_empty() {
+            self.fail(field, ValidationIssue::Empty);
+        } else if value.len() > max_bytes as usize {
+            self.fail(field, ValidationIssue::TooLong { max_bytes });
+        }
+        self
+    }
+
+    /// A required city, weather description or name.
+    pub fn name(self, field: &str, value: &str) -> Self {
+        let max_bytes = self.limits.max_name_bytes;
+        self.text(field, value, max_bytes)
+    }
+
+    /// An optional name; empty values are accepted.
+    pub fn optional_name(mut self, field: &str, value: Option<&str>) -> Self {
+        let max_bytes = self.limits.max_name_bytes;
+        if value.is_some_and(|v| v.len() > max_bytes as usize) {
+            self.fail(field, ValidationIssue::TooLong { max_bytes });
+        }
+        self
+    }
+
+    /// `name` when `required`, `optional_name` otherwise.
+    pub fn name_if(self, required: bool, field: &str, value: &str) -> Self {
+        if required {
+            self.name(field, value)
+        } else {
+            self.optional_name(field, Some(value))
+        }
+    }
+
+    pub fn title(self, field: &str, value: &str) -> Self {
+        let max_bytes = self.limits.max_title_bytes;
+        self.text(field, value, max_bytes)
+    }
+
+    /// A required absolute URL with an allowed scheme.
+    pub fn url(mut self, field: &str, value: &str) -> Self {
+        let max_bytes = self.limits.max_url_bytes;
+        if value.is_empty() {
+            self.fail(field, ValidationIssue::Empty);
+        } else if value.len() > max_bytes as usize {
+            self.fail(field, ValidationIssue::TooLong { max_bytes });
+        } else {
+            let parts = value.split_once("://").map(|(scheme, rest)| (scheme.to_ascii_lowercase(), rest));
+            match parts {
+                Some((scheme, rest)) if is_scheme(&scheme) && is_url_body(rest) => {
+                    if !self.limits.allowed_url_schemes.contains(&scheme) {
+                        self.fail(field, ValidationIssue::SchemeNotAllowed { scheme });
+                    }
+                }
+                _ => self.fail(field, ValidationIssue::InvalidUrl),
+            }
+        }
+        self
+    }
+
+    /// An optional URL; empty values are accepted.
+    pub fn optional_url(self, field: &str, value: Option<&str>) -> Self {
+        match value {
+            Some(v) if !v.is_empty() => self.url(field, v),
+            _ => self,
+        }
+    }
+
+    /// `url` when `required`, `optional_url` otherwise.
+    pub fn url_if(self, required: bool, field: &str, value: &str) -> Self {
+        if required {
+            self.url(field, value)
+        } else {
+            self.optional_url(field, Some(value))
+        }
+    }
+
+    /// Records a failure found outside the built-in checks.
+    pub fn check(mut self, field: &str, ok: bool, issue: ValidationIssue) -> Self {
+        if !ok {
+            self.fail(field, issue);
+        }
+        self
+    }
+
+    pub fn finish(self) -> Result<(), Vec<FieldError>> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.errors)
+        }
+    }
+}
+
+fn is_scheme(scheme: &str) -> bool {
+    let mut chars = scheme.chars();
+    chars.next().is_some_and(|c| c.is_ascii_lowercase())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '+' | '-' | '.'))
+}
+
+/// Host and path of a URL: non-empty, no whitespace or control characters.
+fn is_url_body(rest: &str) -> bool {
+    !rest.is_empty()
+        && !rest.starts_with('/')
+        && !rest.chars().any(|c| c.is_whitespace() || c.is_control())
+}
@@ -2,19 +2,285 @@ import type { Principal } from '@dfinity/principal';
 import type { ActorMethod } from '@dfinity/agent';
 import type { IDL } from '@dfinity/candid';
 
+export interface Account {
+  'owner' : Principal,
+  'subaccount' : [] | [Uint8Array | number[]],
+}
+export type BalanceChange = { 'PayoutReverted' : { 'data_id' : bigint } } |
+  { 'Payout' : { 'data_id' : bigint } } |
+  { 'SubmissionReward' : { 'data_id' : bigint } } |
+  { 'VoterReward' : { 'data_id' : bigint } } |
+  { 'MarkedRewarded' : { 'data_id' : bigint } } |
+  { 'Claim' : { 'claim_id' : bigint } } |
+  { 'ClaimReverted' : { 'claim_id' : bigint } };
+export interface BalanceEntry {
+  'at' : bigint,
+  'id' : bigint,
+  'balance_after' : bigint,
+  'user' : string,
+  'change' : BalanceChange,
+  'amount' : bigint,
+}
+export interface BalanceHistoryPage {
+  'next_cursor' : [] | [bigint],
+  'items' : Array<BalanceEntry>,
+}
+export interface Boundary { 'polygons' : Array<Array<Array<[number, number]>>> }
 export interface Challenge {
   'id' : bigint,
+  'max_accepted' : [] | [number],
   'latitude' : number,
   'title' : string,
+  'creator' : Principal,
+  'has_boundary' : boolean,
+  'state' : ChallengeState,
+  'start_time' : bigint,
   'expiration' : bigint,
   'longitude' : number,
   'picture_url' : string,
   'radius_m' : number,
+  'required_fields' : Array<WeatherField>,
+}
+export interface ChallengeEscrow {
+  'deposited' : bigint,
+  'transfer_fee' : bigint,
+  'paid' : bigint,
+  'reserved' : bigint,
+  'sponsor' : Account,
+  'challenge_id' : bigint,
+  'deposits' : Array<EscrowTransfer>,
+  'refund' : [] | [EscrowTransfer],
+}
+export interface ChallengeInput {
+  'max_accepted' : [] | [number],
+  'latitude' : number,
+  'title' : string,
+  'boundary_geojson' : [] | [string],
+  'end_time' : bigint,
+  'start_time' : [] | [bigint],
+  'longitude' : number,
+  'publish' : boolean,
+  'picture_url' : string,
+  'radius_m' : number,
+  'required_fields' : [] | [Array<WeatherField>],
+}
+export interface ChallengePage {
+  'next_cursor' : [] | [bigint],
+  'items' : Array<Challenge>,
+}
+export type ChallengeState = { 'Closed' : null } |
+  { 'Active' : null } |
+  { 'Draft' : null } |
+  { 'Cancelled' : null } |
+  { 'Settled' : null };
+export interface ChallengeStats {
+  'participants' : bigint,
+  'expired' : bigint,
+  'open' : bigint,
+  'paid' : bigint,
+  'submissions' : bigint,
+  'state' : ChallengeState,
+  'remaining_capacity' : [] | [number],
+  'no_quorum' : bigint,
+  'challenge_id' : bigint,
+  'accepted' : bigint,
+}
+export interface ChallengeUpdate {
+  'max_accepted' : [] | [[] | [number]],
+  'title' : [] | [string],
+  'end_time' : [] | [bigint],
+  'start_time' : [] | [bigint],
+  'picture_url' : [] | [string],
+  'required_fields' : [] | [Array<WeatherField>],
+}
+export interface CityMapPage {
+  'next_cursor' : [] | [bigint],
+  'items' : Array<[string, Array<SubmissionInfo>]>,
+}
+export interface DaoConfig {
+  'voting_window_secs' : bigint,
+  'approval_threshold_bps' : number,
+  'fee_included_in_reward' : boolean,
+  'transfer_fee' : [] | [bigint],
+  'challenge_voting_window_secs' : bigint,
+  'reward_amount' : bigint,
+  'min_approvals' : number,
+  'ledger_canister_id' : Principal,
+  'quorum' : number,
+  'voter_reward_bps' : number,
+}
+export type DaoError = { 'AlreadyVoted' : null } |
+  { 'AlreadyRewarded' : null } |
+  { 'SelfVote' : null } |
+  { 'InvalidInput' : string } |
+  { 'VotingClosed' : null } |
+  { 'DuplicateSubmission' : { 'data_id' : bigint } } |
+  { 'NotFound' : string } |
+  { 'LedgerError' : { 'message' : string } } |
+  { 'Unauthorized' : string } |
+  { 'RateLimited' : { 'retry_after_secs' : bigint } } |
+  { 'Validation' : Array<FieldError> } |
+  { 'InvalidState' : string };
+export interface Discrepancy {
+  'ledger_amount' : [] | [bigint],
+  'block_index' : [] | [bigint],
+  'expected_amount' : [] | [bigint],
+  'payment' : PaymentRef,
+  'reason' : string,
 }
+export interface EscrowTransfer {
+  'at' : bigint,
+  'block_index' : bigint,
+  'amount' : bigint,
+}
+export interface ExpirationPage {
+  'next_cursor' : [] | [bigint],
+  'items' : Array<{ 'data_id' : bigint, 'expiration_timestamp' : bigint }>,
+}
+export interface FieldError { 'field' : string, 'issue' : ValidationIssue }
+export interface FinalizationRecord {
+  'tally' : VoteTally,
+  'approval_threshold_bps' : number,
+  'min_approvals' : number,
+  'rewarded_voters' : number,
+  'capacity_reached' : boolean,
+  'voter_reward' : bigint,
+  'quorum' : number,
+  'finalized_at' : bigint,
+}
+export interface FinalizerReport {
+  'expired' : BigUint64Array | bigint[],
+  'pending' : BigUint64Array | bigint[],
+  'paid' : BigUint64Array | bigint[],
+  'closed_challenges' : BigUint64Array | bigint[],
+  'no_quorum' : BigUint64Array | bigint[],
+  'payout_errors' : Array<[bigint, DaoError]>,
+}
+export interface FinalizerSettings {
+  'batch_size' : number,
+  'interval_secs' : bigint,
+  'auto_payout' : boolean,
+  'enabled' : boolean,
+}
+export interface IntegrityReport {
+  'votes' : bigint,
+  'submissions' : bigint,
+  'schema_version' : number,
+  'issues' : Array<string>,
+  'users' : bigint,
+  'migrations' : Array<MigrationStep>,
+  'challenges' : bigint,
+  'checked_at' : bigint,
+  'payouts' : bigint,
+}
+export interface LedgerPayment {
+  'to' : Account,
+  'block_index' : bigint,
+  'timestamp' : bigint,
+  'amount' : bigint,
+  'payment' : PaymentRef,
+}
+export interface MigrationStep {
+  'to_version' : number,
+  'submissions' : bigint,
+  'from_version' : number,
+  'users' : bigint,
+  'challenges' : bigint,
+}
+export interface PaidSubmission { 'data_id' : bigint, 'block_index' : bigint }
+export type PaymentRef = { 'Reward' : { 'data_id' : bigint } } |
+  { 'Claim' : { 'claim_id' : bigint } };
+export interface Payout {
+  'to' : Account,
+  'fee' : [] | [bigint],
+  'data_id' : bigint,
+  'memo' : Uint8Array | number[],
+  'escrow_challenge_id' : [] | [bigint],
+  'attempts' : number,
+  'state' : PayoutState,
+  'ledger_canister_id' : [] | [Principal],
+  'created_at_time' : [] | [bigint],
+  'balance_debit' : bigint,
+  'amount' : bigint,
+}
+export interface PayoutRunReport {
+  'skipped_count' : bigint,
+  'skipped' : Array<SubmissionIssue>,
+  'paid' : Array<PaidSubmission>,
+  'truncated' : boolean,
+  'cycles_spent' : bigint,
+  'stopped_early' : [] | [string],
+  'failed' : Array<SubmissionIssue>,
+  'started_at' : bigint,
+  'finished_at' : bigint,
+}
+export interface PayoutRunSettings {
+  'max_payouts_per_run' : number,
+  'interval_secs' : bigint,
+  'min_cycles_balance' : bigint,
+  'max_cycles_per_run' : bigint,
+  'enabled' : boolean,
+  'max_concurrency' : number,
+}
+export type PayoutState = { 'Failed' : { 'reason' : string } } |
+  { 'Paid' : { 'block_index' : bigint } } |
+  { 'InFlight' : null } |
+  { 'Scheduled' : null };
 export type PostStatus = { 'OPEN' : null } |
   { 'PAID' : null } |
+  { 'NoQuorum' : null } |
   { 'EXPIRED' : null } |
   { 'PENDING' : null };
+export interface RateLimits {
+  'votes_per_hour' : number,
+  'duplicate_radius_m' : number,
+  'reject_duplicates' : boolean,
+  'submissions_per_hour' : number,
+  'submissions_per_day' : number,
+  'duplicate_window_secs' : bigint,
+}
+export interface ReconciliationCheck {
+  'report' : ReconciliationReport,
+  'stage' : ReconciliationStage,
+  'checked_to' : [] | [bigint],
+}
+export interface ReconciliationProgress {
+  'check' : [] | [ReconciliationCheck],
+  'log_length' : bigint,
+  'scanned_to' : bigint,
+}
+export interface ReconciliationReport {
+  'generated_at' : bigint,
+  'amount_mismatches' : Array<Discrepancy>,
+  'truncated' : boolean,
+  'matched' : bigint,
+  'unverified' : bigint,
+  'log_length' : bigint,
+  'orphaned_payments' : Array<Discrepancy>,
+  'missing_payments' : Array<Discrepancy>,
+  'scanned_to' : bigint,
+}
+export type ReconciliationStage = { 'Claims' : null } |
+  { 'Submissions' : null } |
+  { 'LedgerPayments' : null };
+export interface Reputation {
+  'user_id' : string,
+  'score' : number,
+  'incorrect_votes' : number,
+  'voter_rewards' : bigint,
+  'correct_votes' : number,
+}
+export interface RewardClaim {
+  'id' : bigint,
+  'to' : Account,
+  'fee' : [] | [bigint],
+  'memo' : Uint8Array | number[],
+  'user' : string,
+  'transfer_amount' : bigint,
+  'created_at' : bigint,
+  'state' : PayoutState,
+  'amount' : bigint,
+}
 export type Role = { 'User' : null } |
   { 'Admin' : null } |
   { 'Moderator' : null };
@@ -35,9 +301,32 @@ export interface SubmissionInfo {
   'last_name' : [] | [string],
   'weather' : string,
 }
+export interface SubmissionIssue { 'data_id' : bigint, 'reason' : string }
+export interface SubmissionPage {
+  'next_cursor' : [] | [bigint],
+  'items' : Array<UserSubmission>,
+}
+export interface TimeRange { 'end' : bigint, 'start' : bigint }
+export interface User {
+  'username' : [] | [string],
+  'balance' : bigint,
+  'language_code' : [] | [string],
+  'role' : Role,
+  'wallet_address' : [] | [string],
+  'profile_picture_url' : [] | [string],
+  'user_id' : string,
+  'is_bot' : boolean,
+  'first_name' : [] | [string],
+  'last_name' : [] | [string],
+}
+export interface UserPage {
+  'next_cursor' : [] | [string],
+  'items' : Array<User>,
+}
 export interface UserSubmission {
   'status' : PostStatus,
   'data_id' : bigint,
+  'duplicate_of' : [] | [bigint],
   'data' : {
     'latitude' : number,
     'submission_photo_url' : string,
@@ -49,13 +338,41 @@ export interface UserSubmission {
   },
   'user' : string,
   'expiration_timestamp' : bigint,
+  'claimed' : bigint,
+  'payout_block_index' : [] | [bigint],
   'rewarded' : boolean,
+  'challenge_id' : [] | [bigint],
+  'finalization' : [] | [FinalizationRecord],
+  'reward_credit' : bigint,
 }
 export interface UserSubmissionSummary {
   'status' : PostStatus,
   'data_id' : bigint,
   'city' : string,
 }
+export type ValidationIssue = { 'Empty' : null } |
+  { 'TooLong' : { 'max_bytes' : number } } |
+  { 'InvalidUrl' : null } |
+  { 'InvalidGeometry' : { 'reason' : string } } |
+  { 'NotAfter' : { 'other' : string } } |
+  { 'NotFinite' : null } |
+  { 'OutOfRange' : { 'max' : number, 'min' : number } } |
+  { 'SchemeNotAllowed' : { 'scheme' : string } } |
+  { 'NotPositive' : null };
+export interface ValidationLimits {
+  'allowed_url_schemes' : Array<string>,
+  'max_latitude' : number,
+  'max_challenge_radius_m' : number,
+  'max_title_bytes' : number,
+  'max_boundary_vertices' : number,
+  'max_url_bytes' : number,
+  'max_temperature' : number,
+  'min_latitude' : number,
+  'min_temperature' : number,
+  'max_longitude' : number,
+  'max_name_bytes' : number,
+  'min_longitude' : number,
+}
 export interface Vote {
   'data_id' : bigint,
   'user' : string,
@@ -67,18 +384,81 @@ export interface VoteSummary {
   'data_id' : bigint,
   'downvotes' : number,
 }
+export interface VoteTally {
+  'weighted_invalid' : bigint,
+  'valid' : number,
+  'invalid' : number,
+  'weighted_valid' : bigint,
+}
+export type WeatherField = { 'City' : null } |
+  { 'Photo' : null } |
+  { 'Weather' : null };
 export interface _SERVICE {
+  'add_trusted_relayer' : ActorMethod<
+    [Principal],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'bind_principal' : ActorMethod<
+    [string, Principal],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'cancel_challenge' : ActorMethod<
+    [bigint],
+    { 'Ok' : Challenge } |
+      { 'Err' : DaoError }
+  >,
+  'claim_rewards' : ActorMethod<
+    [bigint, Account],
+    { 'Ok' : RewardClaim } |
+      { 'Err' : DaoError }
+  >,
   'create_challenge' : ActorMethod<
-    [string, number, number, number, bigint, string],
-    bigint
+    [ChallengeInput],
+    { 'Ok' : bigint } |
+      { 'Err' : DaoError }
   >,
   'create_tg_user' : ActorMethod<
     [string, string, string, string, string, boolean, string],
-    string
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'create_tg_user_with_init_data' : ActorMethod<
+    [string],
+    {
+        'Ok' : {
+          'username' : [] | [string],
+          'balance' : bigint,
+          'language_code' : [] | [string],
+          'role' : Role,
+          'wallet_address' : [] | [string],
+          'profile_picture_url' : [] | [string],
+          'user_id' : string,
+          'is_bot' : boolean,
+          'first_name' : [] | [string],
+          'last_name' : [] | [string],
+        }
+      } |
+      { 'Err' : DaoError }
+  >,
+  'delete_vote' : ActorMethod<
+    [string, bigint],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'finalize_post_status' : ActorMethod<
+    [bigint],
+    { 'Ok' : PostStatus } |
+      { 'Err' : DaoError }
+  >,
+  'fund_challenge' : ActorMethod<
+    [bigint, bigint, [] | [Uint8Array | number[]]],
+    { 'Ok' : ChallengeEscrow } |
+      { 'Err' : DaoError }
   >,
-  'delete_vote' : ActorMethod<[string, bigint], string>,
-  'finalize_post_status' : ActorMethod<[bigint], string>,
   'get_active_challenges' : ActorMethod<[number, number], Array<Challenge>>,
+  'get_all_challenges' : ActorMethod<[], Array<Challenge>>,
   'get_all_expiration_times' : ActorMethod<
     [],
     Array<{ 'data_id' : bigint, 'expiration_timestamp' : bigint }>
@@ -121,28 +501,66 @@ export interface _SERVICE {
     >
   >,
   'get_balance' : ActorMethod<[string], bigint>,
+  'get_balance_history' : ActorMethod<
+    [string, [] | [bigint], number],
+    BalanceHistoryPage
+  >,
+  'get_caller_user_id' : ActorMethod<[], [] | [string]>,
   'get_challenge' : ActorMethod<
     [bigint],
     { 'Ok' : Challenge } |
-      { 'Err' : string }
+      { 'Err' : DaoError }
+  >,
+  'get_challenge_boundary' : ActorMethod<[bigint], [] | [Boundary]>,
+  'get_challenge_escrow' : ActorMethod<[bigint], [] | [ChallengeEscrow]>,
+  'get_challenge_stats' : ActorMethod<
+    [bigint],
+    { 'Ok' : ChallengeStats } |
+      { 'Err' : DaoError }
   >,
   'get_challenges_by_radius' : ActorMethod<
     [number, number, number],
     Array<Challenge>
   >,
   'get_challenges_expiring_soon' : ActorMethod<[bigint], Array<Challenge>>,
+  'get_challenges_page' : ActorMethod<[[] | [bigint], number], ChallengePage>,
+  'get_claim' : ActorMethod<[bigint], [] | [RewardClaim]>,
+  'get_config' : ActorMethod<[], DaoConfig>,
   'get_expiration_time' : ActorMethod<
     [bigint],
     { 'Ok' : bigint } |
-      { 'Err' : string }
+      { 'Err' : DaoError }
+  >,
+  'get_expiration_times_page' : ActorMethod<
+    [[] | [bigint], number],
+    ExpirationPage
   >,
+  'get_finalizer_settings' : ActorMethod<[], FinalizerSettings>,
+  'get_integrity_report' : ActorMethod<[], IntegrityReport>,
+  'get_last_payout_run' : ActorMethod<[], PayoutRunReport>,
   'get_leaderboard_by_total_votes' : ActorMethod<[], Array<VoteSummary>>,
   'get_leaderboard_by_upvotes' : ActorMethod<[], Array<VoteSummary>>,
+  'get_ledger_payment' : ActorMethod<[bigint], [] | [LedgerPayment]>,
   'get_paid_submission_map_by_city' : ActorMethod<
     [],
     Array<[string, Array<SubmissionInfo>]>
   >,
-  'get_post_status' : ActorMethod<[bigint], string>,
+  'get_paid_submission_map_by_city_page' : ActorMethod<
+    [[] | [bigint], number],
+    CityMapPage
+  >,
+  'get_payout' : ActorMethod<[bigint], [] | [Payout]>,
+  'get_payout_run_settings' : ActorMethod<[], PayoutRunSettings>,
+  'get_post_status' : ActorMethod<
+    [bigint],
+    { 'Ok' : PostStatus } |
+      { 'Err' : DaoError }
+  >,
+  'get_rate_limits' : ActorMethod<[], RateLimits>,
+  'get_reconciliation_progress' : ActorMethod<[], ReconciliationProgress>,
+  'get_reconciliation_report' : ActorMethod<[], ReconciliationReport>,
+  'get_reputation' : ActorMethod<[string], Reputation>,
+  'get_reputation_leaderboard' : ActorMethod<[], Array<Reputation>>,
   'get_rewarded_submissions' : ActorMethod<[string], Array<UserSubmission>>,
   'get_submission' : ActorMethod<
     [bigint],
@@ -163,14 +581,29 @@ export interface _SERVICE {
           'rewarded' : boolean,
         }
       } |
-      { 'Err' : string }
+      { 'Err' : DaoError }
   >,
   'get_submission_map_by_city' : ActorMethod<
     [],
     Array<[string, Array<SubmissionInfo>]>
   >,
+  'get_submission_map_by_city_page' : ActorMethod<
+    [[] | [bigint], number],
+    CityMapPage
+  >,
   'get_submissions_by_challenge' : ActorMethod<[bigint], Array<UserSubmission>>,
   'get_submissions_by_city' : ActorMethod<[string], Array<UserSubmission>>,
+  'get_submissions_in_bbox' : ActorMethod<
+    [number, number, number, number],
+    { 'Ok' : Array<UserSubmission> } |
+      { 'Err' : DaoError }
+  >,
+  'get_submissions_near' : ActorMethod<
+    [number, number, number, [] | [TimeRange]],
+    { 'Ok' : Array<UserSubmission> } |
+      { 'Err' : DaoError }
+  >,
+  'get_submissions_page' : ActorMethod<[[] | [bigint], number], SubmissionPage>,
   'get_tg_user' : ActorMethod<
     [string],
     {
@@ -187,8 +620,9 @@ export interface _SERVICE {
           'last_name' : [] | [string],
         }
       } |
-      { 'Err' : string }
+      { 'Err' : DaoError }
   >,
+  'get_trusted_relayers' : ActorMethod<[], Array<Principal>>,
   'get_user_posts' : ActorMethod<
     [string],
     Array<
@@ -209,15 +643,16 @@ export interface _SERVICE {
       }
     >
   >,
-  'get_user_role' : ActorMethod<[string], { 'Ok' : Role } | { 'Err' : string }>,
+  'get_user_role' : ActorMethod<
+    [string],
+    { 'Ok' : Role } |
+      { 'Err' : DaoError }
+  >,
   'get_user_submission_locations' : ActorMethod<
     [string],
     Array<
       {
-        'status' : { 'OPEN' : null } |
-          { 'PAID' : null } |
-          { 'EXPIRED' : null } |
-          { 'PENDING' : null },
+        'status' : PostStatus,
         'latitude' : number,
         'data_id' : bigint,
         'longitude' : number,
@@ -232,6 +667,8 @@ export interface _SERVICE {
     [string, bigint],
     Array<UserSubmission>
   >,
+  'get_users_page' : ActorMethod<[[] | [string], number], UserPage>,
+  'get_validation_limits' : ActorMethod<[], ValidationLimits>,
   'get_vote_summary' : ActorMethod<
     [bigint],
     { 'upvotes' : number, 'data_id' : bigint, 'downvotes' : number }
@@ -240,26 +677,123 @@ export interface _SERVICE {
   'mark_submission_rewarded' : ActorMethod<
     [bigint],
     { 'Ok' : string } |
-      { 'Err' : string }
+      { 'Err' : DaoError }
+  >,
+  'publish_challenge' : ActorMethod<
+    [bigint],
+    { 'Ok' : Challenge } |
+      { 'Err' : DaoError }
+  >,
+  'refund_challenge_escrow' : ActorMethod<
+    [bigint],
+    { 'Ok' : ChallengeEscrow } |
+      { 'Err' : DaoError }
+  >,
+  'remove_trusted_relayer' : ActorMethod<
+    [Principal],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'resolve_payout' : ActorMethod<
+    [bigint, [] | [bigint]],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'reward_user' : ActorMethod<
+    [bigint],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'run_finalizer_now' : ActorMethod<
+    [],
+    { 'Ok' : FinalizerReport } |
+      { 'Err' : DaoError }
+  >,
+  'run_payouts_now' : ActorMethod<
+    [],
+    { 'Ok' : PayoutRunReport } |
+      { 'Err' : DaoError }
+  >,
+  'run_reconciliation' : ActorMethod<
+    [[] | [bigint]],
+    { 'Ok' : ReconciliationProgress } |
+      { 'Err' : DaoError }
+  >,
+  'set_finalizer_settings' : ActorMethod<
+    [FinalizerSettings],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'set_payout_run_settings' : ActorMethod<
+    [PayoutRunSettings],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'set_rate_limits' : ActorMethod<
+    [RateLimits],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'set_telegram_bot_token' : ActorMethod<
+    [string],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'set_validation_limits' : ActorMethod<
+    [ValidationLimits],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'settle_challenge' : ActorMethod<
+    [bigint],
+    { 'Ok' : Challenge } |
+      { 'Err' : DaoError }
   >,
-  'reward_user' : ActorMethod<[bigint], { 'Ok' : string } | { 'Err' : string }>,
   'submit_weather_data' : ActorMethod<
     [string, number, number, string, number, string, string],
-    bigint
+    { 'Ok' : bigint } |
+      { 'Err' : DaoError }
   >,
   'submit_weather_data_with_challenge' : ActorMethod<
     [string, number, number, string, number, string, string, bigint],
     { 'Ok' : bigint } |
-      { 'Err' : string }
+      { 'Err' : DaoError }
+  >,
+  'unbind_principal' : ActorMethod<
+    [Principal],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'update_challenge' : ActorMethod<
+    [bigint, ChallengeUpdate],
+    { 'Ok' : Challenge } |
+      { 'Err' : DaoError }
+  >,
+  'update_config' : ActorMethod<
+    [DaoConfig],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
   >,
   'update_user_role' : ActorMethod<
     [string, string, Role],
     { 'Ok' : string } |
-      { 'Err' : string }
+      { 'Err' : DaoError }
+  >,
+  'update_vote' : ActorMethod<
+    [string, bigint, boolean],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'update_wallet_address' : ActorMethod<
+    [string, string],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
+  >,
+  'vote_on_data' : ActorMethod<
+    [string, bigint, boolean],
+    { 'Ok' : string } |
+      { 'Err' : DaoError }
   >,
-  'update_vote' : ActorMethod<[string, bigint, boolean], string>,
-  'update_wallet_address' : ActorMethod<[string, string], string>,
-  'vote_on_data' : ActorMethod<[string, bigint, boolean], string>,
 }
 export declare const idlFactory: IDL.InterfaceFactory;
 export declare const init: (args: { IDL: typeof IDL }) => IDL.Type[];
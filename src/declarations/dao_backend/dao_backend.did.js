@@ -1,29 +1,238 @@
 export const idlFactory = ({ IDL }) => {
+  const DaoConfig = IDL.Record({
+    'voting_window_secs' : IDL.Nat64,
+    'approval_threshold_bps' : IDL.Nat32,
+    'fee_included_in_reward' : IDL.Bool,
+    'transfer_fee' : IDL.Opt(IDL.Nat64),
+    'challenge_voting_window_secs' : IDL.Nat64,
+    'reward_amount' : IDL.Nat64,
+    'min_approvals' : IDL.Nat32,
+    'ledger_canister_id' : IDL.Principal,
+    'quorum' : IDL.Nat32,
+    'voter_reward_bps' : IDL.Nat32,
+  });
+  const ValidationIssue = IDL.Variant({
+    'Empty' : IDL.Null,
+    'TooLong' : IDL.Record({ 'max_bytes' : IDL.Nat32 }),
+    'InvalidUrl' : IDL.Null,
+    'InvalidGeometry' : IDL.Record({ 'reason' : IDL.Text }),
+    'NotAfter' : IDL.Record({ 'other' : IDL.Text }),
+    'NotFinite' : IDL.Null,
+    'OutOfRange' : IDL.Record({ 'max' : IDL.Float64, 'min' : IDL.Float64 }),
+    'SchemeNotAllowed' : IDL.Record({ 'scheme' : IDL.Text }),
+    'NotPositive' : IDL.Null,
+  });
+  const FieldError = IDL.Record({
+    'field' : IDL.Text,
+    'issue' : ValidationIssue,
+  });
+  const DaoError = IDL.Variant({
+    'AlreadyVoted' : IDL.Null,
+    'AlreadyRewarded' : IDL.Null,
+    'SelfVote' : IDL.Null,
+    'InvalidInput' : IDL.Text,
+    'VotingClosed' : IDL.Null,
+    'DuplicateSubmission' : IDL.Record({ 'data_id' : IDL.Nat64 }),
+    'NotFound' : IDL.Text,
+    'LedgerError' : IDL.Record({ 'message' : IDL.Text }),
+    'Unauthorized' : IDL.Text,
+    'RateLimited' : IDL.Record({ 'retry_after_secs' : IDL.Nat64 }),
+    'Validation' : IDL.Vec(FieldError),
+    'InvalidState' : IDL.Text,
+  });
+  const ChallengeState = IDL.Variant({
+    'Closed' : IDL.Null,
+    'Active' : IDL.Null,
+    'Draft' : IDL.Null,
+    'Cancelled' : IDL.Null,
+    'Settled' : IDL.Null,
+  });
+  const WeatherField = IDL.Variant({
+    'City' : IDL.Null,
+    'Photo' : IDL.Null,
+    'Weather' : IDL.Null,
+  });
   const Challenge = IDL.Record({
     'id' : IDL.Nat64,
+    'max_accepted' : IDL.Opt(IDL.Nat32),
     'latitude' : IDL.Float64,
     'title' : IDL.Text,
+    'creator' : IDL.Principal,
+    'has_boundary' : IDL.Bool,
+    'state' : ChallengeState,
+    'start_time' : IDL.Nat64,
     'expiration' : IDL.Nat64,
     'longitude' : IDL.Float64,
     'picture_url' : IDL.Text,
     'radius_m' : IDL.Float64,
+    'required_fields' : IDL.Vec(WeatherField),
+  });
+  const Account = IDL.Record({
+    'owner' : IDL.Principal,
+    'subaccount' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+  });
+  const PayoutState = IDL.Variant({
+    'Failed' : IDL.Record({ 'reason' : IDL.Text }),
+    'Paid' : IDL.Record({ 'block_index' : IDL.Nat64 }),
+    'InFlight' : IDL.Null,
+    'Scheduled' : IDL.Null,
+  });
+  const RewardClaim = IDL.Record({
+    'id' : IDL.Nat64,
+    'to' : Account,
+    'fee' : IDL.Opt(IDL.Nat64),
+    'memo' : IDL.Vec(IDL.Nat8),
+    'user' : IDL.Text,
+    'transfer_amount' : IDL.Nat64,
+    'created_at' : IDL.Nat64,
+    'state' : PayoutState,
+    'amount' : IDL.Nat64,
+  });
+  const ChallengeInput = IDL.Record({
+    'max_accepted' : IDL.Opt(IDL.Nat32),
+    'latitude' : IDL.Float64,
+    'title' : IDL.Text,
+    'boundary_geojson' : IDL.Opt(IDL.Text),
+    'end_time' : IDL.Nat64,
+    'start_time' : IDL.Opt(IDL.Nat64),
+    'longitude' : IDL.Float64,
+    'publish' : IDL.Bool,
+    'picture_url' : IDL.Text,
+    'radius_m' : IDL.Float64,
+    'required_fields' : IDL.Opt(IDL.Vec(WeatherField)),
+  });
+  const Role = IDL.Variant({
+    'User' : IDL.Null,
+    'Admin' : IDL.Null,
+    'Moderator' : IDL.Null,
   });
   const PostStatus = IDL.Variant({
     'OPEN' : IDL.Null,
     'PAID' : IDL.Null,
+    'NoQuorum' : IDL.Null,
     'EXPIRED' : IDL.Null,
     'PENDING' : IDL.Null,
   });
-  const Role = IDL.Variant({
-    'User' : IDL.Null,
-    'Admin' : IDL.Null,
-    'Moderator' : IDL.Null,
+  const EscrowTransfer = IDL.Record({
+    'at' : IDL.Nat64,
+    'block_index' : IDL.Nat64,
+    'amount' : IDL.Nat64,
+  });
+  const ChallengeEscrow = IDL.Record({
+    'deposited' : IDL.Nat64,
+    'transfer_fee' : IDL.Nat64,
+    'paid' : IDL.Nat64,
+    'reserved' : IDL.Nat64,
+    'sponsor' : Account,
+    'challenge_id' : IDL.Nat64,
+    'deposits' : IDL.Vec(EscrowTransfer),
+    'refund' : IDL.Opt(EscrowTransfer),
+  });
+  const BalanceChange = IDL.Variant({
+    'PayoutReverted' : IDL.Record({ 'data_id' : IDL.Nat64 }),
+    'Payout' : IDL.Record({ 'data_id' : IDL.Nat64 }),
+    'SubmissionReward' : IDL.Record({ 'data_id' : IDL.Nat64 }),
+    'VoterReward' : IDL.Record({ 'data_id' : IDL.Nat64 }),
+    'MarkedRewarded' : IDL.Record({ 'data_id' : IDL.Nat64 }),
+    'Claim' : IDL.Record({ 'claim_id' : IDL.Nat64 }),
+    'ClaimReverted' : IDL.Record({ 'claim_id' : IDL.Nat64 }),
+  });
+  const BalanceEntry = IDL.Record({
+    'at' : IDL.Nat64,
+    'id' : IDL.Nat64,
+    'balance_after' : IDL.Nat64,
+    'user' : IDL.Text,
+    'change' : BalanceChange,
+    'amount' : IDL.Nat64,
+  });
+  const BalanceHistoryPage = IDL.Record({
+    'next_cursor' : IDL.Opt(IDL.Nat64),
+    'items' : IDL.Vec(BalanceEntry),
+  });
+  const Boundary = IDL.Record({
+    'polygons' : IDL.Vec(IDL.Vec(IDL.Vec(IDL.Tuple(IDL.Float64, IDL.Float64)))),
+  });
+  const ChallengeStats = IDL.Record({
+    'participants' : IDL.Nat64,
+    'expired' : IDL.Nat64,
+    'open' : IDL.Nat64,
+    'paid' : IDL.Nat64,
+    'submissions' : IDL.Nat64,
+    'state' : ChallengeState,
+    'remaining_capacity' : IDL.Opt(IDL.Nat32),
+    'no_quorum' : IDL.Nat64,
+    'challenge_id' : IDL.Nat64,
+    'accepted' : IDL.Nat64,
+  });
+  const ChallengePage = IDL.Record({
+    'next_cursor' : IDL.Opt(IDL.Nat64),
+    'items' : IDL.Vec(Challenge),
+  });
+  const ExpirationPage = IDL.Record({
+    'next_cursor' : IDL.Opt(IDL.Nat64),
+    'items' : IDL.Vec(
+      IDL.Record({ 'data_id' : IDL.Nat64, 'expiration_timestamp' : IDL.Nat64 })
+    ),
+  });
+  const FinalizerSettings = IDL.Record({
+    'batch_size' : IDL.Nat32,
+    'interval_secs' : IDL.Nat64,
+    'auto_payout' : IDL.Bool,
+    'enabled' : IDL.Bool,
+  });
+  const MigrationStep = IDL.Record({
+    'to_version' : IDL.Nat32,
+    'submissions' : IDL.Nat64,
+    'from_version' : IDL.Nat32,
+    'users' : IDL.Nat64,
+    'challenges' : IDL.Nat64,
+  });
+  const IntegrityReport = IDL.Record({
+    'votes' : IDL.Nat64,
+    'submissions' : IDL.Nat64,
+    'schema_version' : IDL.Nat32,
+    'issues' : IDL.Vec(IDL.Text),
+    'users' : IDL.Nat64,
+    'migrations' : IDL.Vec(MigrationStep),
+    'challenges' : IDL.Nat64,
+    'checked_at' : IDL.Nat64,
+    'payouts' : IDL.Nat64,
+  });
+  const SubmissionIssue = IDL.Record({
+    'data_id' : IDL.Nat64,
+    'reason' : IDL.Text,
+  });
+  const PaidSubmission = IDL.Record({
+    'data_id' : IDL.Nat64,
+    'block_index' : IDL.Nat64,
+  });
+  const PayoutRunReport = IDL.Record({
+    'skipped_count' : IDL.Nat64,
+    'skipped' : IDL.Vec(SubmissionIssue),
+    'paid' : IDL.Vec(PaidSubmission),
+    'truncated' : IDL.Bool,
+    'cycles_spent' : IDL.Nat64,
+    'stopped_early' : IDL.Opt(IDL.Text),
+    'failed' : IDL.Vec(SubmissionIssue),
+    'started_at' : IDL.Nat64,
+    'finished_at' : IDL.Nat64,
   });
   const VoteSummary = IDL.Record({
     'upvotes' : IDL.Nat32,
     'data_id' : IDL.Nat64,
     'downvotes' : IDL.Nat32,
   });
+  const PaymentRef = IDL.Variant({
+    'Reward' : IDL.Record({ 'data_id' : IDL.Nat64 }),
+    'Claim' : IDL.Record({ 'claim_id' : IDL.Nat64 }),
+  });
+  const LedgerPayment = IDL.Record({
+    'to' : Account,
+    'block_index' : IDL.Nat64,
+    'timestamp' : IDL.Nat64,
+    'amount' : IDL.Nat64,
+    'payment' : PaymentRef,
+  });
   const SubmissionInfo = IDL.Record({
     'status' : PostStatus,
     'latitude' : IDL.Float64,
@@ -41,9 +250,99 @@ export const idlFactory = ({ IDL }) => {
     'last_name' : IDL.Opt(IDL.Text),
     'weather' : IDL.Text,
   });
+  const CityMapPage = IDL.Record({
+    'next_cursor' : IDL.Opt(IDL.Nat64),
+    'items' : IDL.Vec(IDL.Tuple(IDL.Text, IDL.Vec(SubmissionInfo))),
+  });
+  const Payout = IDL.Record({
+    'to' : Account,
+    'fee' : IDL.Opt(IDL.Nat64),
+    'data_id' : IDL.Nat64,
+    'memo' : IDL.Vec(IDL.Nat8),
+    'escrow_challenge_id' : IDL.Opt(IDL.Nat64),
+    'attempts' : IDL.Nat32,
+    'state' : PayoutState,
+    'ledger_canister_id' : IDL.Opt(IDL.Principal),
+    'created_at_time' : IDL.Opt(IDL.Nat64),
+    'balance_debit' : IDL.Nat64,
+    'amount' : IDL.Nat64,
+  });
+  const PayoutRunSettings = IDL.Record({
+    'max_payouts_per_run' : IDL.Nat32,
+    'interval_secs' : IDL.Nat64,
+    'min_cycles_balance' : IDL.Nat64,
+    'max_cycles_per_run' : IDL.Nat64,
+    'enabled' : IDL.Bool,
+    'max_concurrency' : IDL.Nat32,
+  });
+  const RateLimits = IDL.Record({
+    'votes_per_hour' : IDL.Nat32,
+    'duplicate_radius_m' : IDL.Float64,
+    'reject_duplicates' : IDL.Bool,
+    'submissions_per_hour' : IDL.Nat32,
+    'submissions_per_day' : IDL.Nat32,
+    'duplicate_window_secs' : IDL.Nat64,
+  });
+  const Discrepancy = IDL.Record({
+    'ledger_amount' : IDL.Opt(IDL.Nat64),
+    'block_index' : IDL.Opt(IDL.Nat64),
+    'expected_amount' : IDL.Opt(IDL.Nat64),
+    'payment' : PaymentRef,
+    'reason' : IDL.Text,
+  });
+  const ReconciliationReport = IDL.Record({
+    'generated_at' : IDL.Nat64,
+    'amount_mismatches' : IDL.Vec(Discrepancy),
+    'truncated' : IDL.Bool,
+    'matched' : IDL.Nat64,
+    'unverified' : IDL.Nat64,
+    'log_length' : IDL.Nat64,
+    'orphaned_payments' : IDL.Vec(Discrepancy),
+    'missing_payments' : IDL.Vec(Discrepancy),
+    'scanned_to' : IDL.Nat64,
+  });
+  const ReconciliationStage = IDL.Variant({
+    'Claims' : IDL.Null,
+    'Submissions' : IDL.Null,
+    'LedgerPayments' : IDL.Null,
+  });
+  const ReconciliationCheck = IDL.Record({
+    'report' : ReconciliationReport,
+    'stage' : ReconciliationStage,
+    'checked_to' : IDL.Opt(IDL.Nat64),
+  });
+  const ReconciliationProgress = IDL.Record({
+    'check' : IDL.Opt(ReconciliationCheck),
+    'log_length' : IDL.Nat64,
+    'scanned_to' : IDL.Nat64,
+  });
+  const Reputation = IDL.Record({
+    'user_id' : IDL.Text,
+    'score' : IDL.Nat32,
+    'incorrect_votes' : IDL.Nat32,
+    'voter_rewards' : IDL.Nat64,
+    'correct_votes' : IDL.Nat32,
+  });
+  const VoteTally = IDL.Record({
+    'weighted_invalid' : IDL.Nat64,
+    'valid' : IDL.Nat32,
+    'invalid' : IDL.Nat32,
+    'weighted_valid' : IDL.Nat64,
+  });
+  const FinalizationRecord = IDL.Record({
+    'tally' : VoteTally,
+    'approval_threshold_bps' : IDL.Nat32,
+    'min_approvals' : IDL.Nat32,
+    'rewarded_voters' : IDL.Nat32,
+    'capacity_reached' : IDL.Bool,
+    'voter_reward' : IDL.Nat64,
+    'quorum' : IDL.Nat32,
+    'finalized_at' : IDL.Nat64,
+  });
   const UserSubmission = IDL.Record({
     'status' : PostStatus,
     'data_id' : IDL.Nat64,
+    'duplicate_of' : IDL.Opt(IDL.Nat64),
     'data' : IDL.Record({
       'latitude' : IDL.Float64,
       'submission_photo_url' : IDL.Text,
@@ -55,37 +354,148 @@ export const idlFactory = ({ IDL }) => {
     }),
     'user' : IDL.Text,
     'expiration_timestamp' : IDL.Nat64,
+    'claimed' : IDL.Nat64,
+    'payout_block_index' : IDL.Opt(IDL.Nat64),
     'rewarded' : IDL.Bool,
+    'challenge_id' : IDL.Opt(IDL.Nat64),
+    'finalization' : IDL.Opt(FinalizationRecord),
+    'reward_credit' : IDL.Nat64,
+  });
+  const TimeRange = IDL.Record({ 'end' : IDL.Nat64, 'start' : IDL.Nat64 });
+  const SubmissionPage = IDL.Record({
+    'next_cursor' : IDL.Opt(IDL.Nat64),
+    'items' : IDL.Vec(UserSubmission),
   });
   const UserSubmissionSummary = IDL.Record({
     'status' : PostStatus,
     'data_id' : IDL.Nat64,
     'city' : IDL.Text,
   });
+  const User = IDL.Record({
+    'username' : IDL.Opt(IDL.Text),
+    'balance' : IDL.Nat64,
+    'language_code' : IDL.Opt(IDL.Text),
+    'role' : Role,
+    'wallet_address' : IDL.Opt(IDL.Text),
+    'profile_picture_url' : IDL.Opt(IDL.Text),
+    'user_id' : IDL.Text,
+    'is_bot' : IDL.Bool,
+    'first_name' : IDL.Opt(IDL.Text),
+    'last_name' : IDL.Opt(IDL.Text),
+  });
+  const UserPage = IDL.Record({
+    'next_cursor' : IDL.Opt(IDL.Text),
+    'items' : IDL.Vec(User),
+  });
+  const ValidationLimits = IDL.Record({
+    'allowed_url_schemes' : IDL.Vec(IDL.Text),
+    'max_latitude' : IDL.Float64,
+    'max_challenge_radius_m' : IDL.Float64,
+    'max_title_bytes' : IDL.Nat32,
+    'max_boundary_vertices' : IDL.Nat32,
+    'max_url_bytes' : IDL.Nat32,
+    'max_temperature' : IDL.Float64,
+    'min_latitude' : IDL.Float64,
+    'min_temperature' : IDL.Float64,
+    'max_longitude' : IDL.Float64,
+    'max_name_bytes' : IDL.Nat32,
+    'min_longitude' : IDL.Float64,
+  });
   const Vote = IDL.Record({
     'data_id' : IDL.Nat64,
     'user' : IDL.Text,
     'vote_value' : IDL.Bool,
     'submission_id' : IDL.Nat64,
   });
+  const FinalizerReport = IDL.Record({
+    'expired' : IDL.Vec(IDL.Nat64),
+    'pending' : IDL.Vec(IDL.Nat64),
+    'paid' : IDL.Vec(IDL.Nat64),
+    'closed_challenges' : IDL.Vec(IDL.Nat64),
+    'no_quorum' : IDL.Vec(IDL.Nat64),
+    'payout_errors' : IDL.Vec(IDL.Tuple(IDL.Nat64, DaoError)),
+  });
+  const ChallengeUpdate = IDL.Record({
+    'max_accepted' : IDL.Opt(IDL.Opt(IDL.Nat32)),
+    'title' : IDL.Opt(IDL.Text),
+    'end_time' : IDL.Opt(IDL.Nat64),
+    'start_time' : IDL.Opt(IDL.Nat64),
+    'picture_url' : IDL.Opt(IDL.Text),
+    'required_fields' : IDL.Opt(IDL.Vec(WeatherField)),
+  });
   return IDL.Service({
-    'create_challenge' : IDL.Func(
-        [IDL.Text, IDL.Float64, IDL.Float64, IDL.Float64, IDL.Nat64, IDL.Text],
+    'add_trusted_relayer' : IDL.Func(
+        [IDL.Principal],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'bind_principal' : IDL.Func(
+        [IDL.Text, IDL.Principal],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'cancel_challenge' : IDL.Func(
         [IDL.Nat64],
+        [IDL.Variant({ 'Ok' : Challenge, 'Err' : DaoError })],
+        [],
+      ),
+    'claim_rewards' : IDL.Func(
+        [IDL.Nat64, Account],
+        [IDL.Variant({ 'Ok' : RewardClaim, 'Err' : DaoError })],
+        [],
+      ),
+    'create_challenge' : IDL.Func(
+        [ChallengeInput],
+        [IDL.Variant({ 'Ok' : IDL.Nat64, 'Err' : DaoError })],
         [],
       ),
     'create_tg_user' : IDL.Func(
         [IDL.Text, IDL.Text, IDL.Text, IDL.Text, IDL.Text, IDL.Bool, IDL.Text],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'create_tg_user_with_init_data' : IDL.Func(
         [IDL.Text],
+        [
+          IDL.Variant({
+            'Ok' : IDL.Record({
+              'username' : IDL.Opt(IDL.Text),
+              'balance' : IDL.Nat64,
+              'language_code' : IDL.Opt(IDL.Text),
+              'role' : Role,
+              'wallet_address' : IDL.Opt(IDL.Text),
+              'profile_picture_url' : IDL.Opt(IDL.Text),
+              'user_id' : IDL.Text,
+              'is_bot' : IDL.Bool,
+              'first_name' : IDL.Opt(IDL.Text),
+              'last_name' : IDL.Opt(IDL.Text),
+            }),
+            'Err' : DaoError,
+          }),
+        ],
+        [],
+      ),
+    'delete_vote' : IDL.Func(
+        [IDL.Text, IDL.Nat64],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'finalize_post_status' : IDL.Func(
+        [IDL.Nat64],
+        [IDL.Variant({ 'Ok' : PostStatus, 'Err' : DaoError })],
+        [],
+      ),
+    'fund_challenge' : IDL.Func(
+        [IDL.Nat64, IDL.Nat64, IDL.Opt(IDL.Vec(IDL.Nat8))],
+        [IDL.Variant({ 'Ok' : ChallengeEscrow, 'Err' : DaoError })],
         [],
       ),
-    'delete_vote' : IDL.Func([IDL.Text, IDL.Nat64], [IDL.Text], []),
-    'finalize_post_status' : IDL.Func([IDL.Nat64], [IDL.Text], []),
     'get_active_challenges' : IDL.Func(
         [IDL.Float64, IDL.Float64],
         [IDL.Vec(Challenge)],
         ['query'],
       ),
+    'get_all_challenges' : IDL.Func([], [IDL.Vec(Challenge)], ['query']),
     'get_all_expiration_times' : IDL.Func(
         [],
         [
@@ -142,9 +552,30 @@ export const idlFactory = ({ IDL }) => {
         [],
       ),
     'get_balance' : IDL.Func([IDL.Text], [IDL.Nat64], []),
+    'get_balance_history' : IDL.Func(
+        [IDL.Text, IDL.Opt(IDL.Nat64), IDL.Nat32],
+        [BalanceHistoryPage],
+        ['query'],
+      ),
+    'get_caller_user_id' : IDL.Func([], [IDL.Opt(IDL.Text)], ['query']),
     'get_challenge' : IDL.Func(
         [IDL.Nat64],
-        [IDL.Variant({ 'Ok' : Challenge, 'Err' : IDL.Text })],
+        [IDL.Variant({ 'Ok' : Challenge, 'Err' : DaoError })],
+        ['query'],
+      ),
+    'get_challenge_boundary' : IDL.Func(
+        [IDL.Nat64],
+        [IDL.Opt(Boundary)],
+        ['query'],
+      ),
+    'get_challenge_escrow' : IDL.Func(
+        [IDL.Nat64],
+        [IDL.Opt(ChallengeEscrow)],
+        ['query'],
+      ),
+    'get_challenge_stats' : IDL.Func(
+        [IDL.Nat64],
+        [IDL.Variant({ 'Ok' : ChallengeStats, 'Err' : DaoError })],
         ['query'],
       ),
     'get_challenges_by_radius' : IDL.Func(
@@ -157,11 +588,26 @@ export const idlFactory = ({ IDL }) => {
         [IDL.Vec(Challenge)],
         ['query'],
       ),
+    'get_challenges_page' : IDL.Func(
+        [IDL.Opt(IDL.Nat64), IDL.Nat32],
+        [ChallengePage],
+        ['query'],
+      ),
+    'get_claim' : IDL.Func([IDL.Nat64], [IDL.Opt(RewardClaim)], ['query']),
+    'get_config' : IDL.Func([], [DaoConfig], ['query']),
     'get_expiration_time' : IDL.Func(
         [IDL.Nat64],
-        [IDL.Variant({ 'Ok' : IDL.Nat64, 'Err' : IDL.Text })],
+        [IDL.Variant({ 'Ok' : IDL.Nat64, 'Err' : DaoError })],
         [],
       ),
+    'get_expiration_times_page' : IDL.Func(
+        [IDL.Opt(IDL.Nat64), IDL.Nat32],
+        [ExpirationPage],
+        ['query'],
+      ),
+    'get_finalizer_settings' : IDL.Func([], [FinalizerSettings], ['query']),
+    'get_integrity_report' : IDL.Func([], [IntegrityReport], ['query']),
+    'get_last_payout_run' : IDL.Func([], [PayoutRunReport], ['query']),
     'get_leaderboard_by_total_votes' : IDL.Func(
         [],
         [IDL.Vec(VoteSummary)],
@@ -172,12 +618,45 @@ export const idlFactory = ({ IDL }) => {
         [IDL.Vec(VoteSummary)],
         ['query'],
       ),
+    'get_ledger_payment' : IDL.Func(
+        [IDL.Nat64],
+        [IDL.Opt(LedgerPayment)],
+        ['query'],
+      ),
     'get_paid_submission_map_by_city' : IDL.Func(
         [],
         [IDL.Vec(IDL.Tuple(IDL.Text, IDL.Vec(SubmissionInfo)))],
         ['query'],
       ),
-    'get_post_status' : IDL.Func([IDL.Nat64], [IDL.Text], []),
+    'get_paid_submission_map_by_city_page' : IDL.Func(
+        [IDL.Opt(IDL.Nat64), IDL.Nat32],
+        [CityMapPage],
+        ['query'],
+      ),
+    'get_payout' : IDL.Func([IDL.Nat64], [IDL.Opt(Payout)], ['query']),
+    'get_payout_run_settings' : IDL.Func([], [PayoutRunSettings], ['query']),
+    'get_post_status' : IDL.Func(
+        [IDL.Nat64],
+        [IDL.Variant({ 'Ok' : PostStatus, 'Err' : DaoError })],
+        [],
+      ),
+    'get_rate_limits' : IDL.Func([], [RateLimits], ['query']),
+    'get_reconciliation_progress' : IDL.Func(
+        [],
+        [ReconciliationProgress],
+        ['query'],
+      ),
+    'get_reconciliation_report' : IDL.Func(
+        [],
+        [ReconciliationReport],
+        ['query'],
+      ),
+    'get_reputation' : IDL.Func([IDL.Text], [Reputation], ['query']),
+    'get_reputation_leaderboard' : IDL.Func(
+        [],
+        [IDL.Vec(Reputation)],
+        ['query'],
+      ),
     'get_rewarded_submissions' : IDL.Func(
         [IDL.Text],
         [IDL.Vec(UserSubmission)],
@@ -202,7 +681,7 @@ export const idlFactory = ({ IDL }) => {
               'user' : IDL.Text,
               'rewarded' : IDL.Bool,
             }),
-            'Err' : IDL.Text,
+            'Err' : DaoError,
           }),
         ],
         [],
@@ -212,6 +691,11 @@ export const idlFactory = ({ IDL }) => {
         [IDL.Vec(IDL.Tuple(IDL.Text, IDL.Vec(SubmissionInfo)))],
         ['query'],
       ),
+    'get_submission_map_by_city_page' : IDL.Func(
+        [IDL.Opt(IDL.Nat64), IDL.Nat32],
+        [CityMapPage],
+        ['query'],
+      ),
     'get_submissions_by_challenge' : IDL.Func(
         [IDL.Nat64],
         [IDL.Vec(UserSubmission)],
@@ -222,6 +706,21 @@ export const idlFactory = ({ IDL }) => {
         [IDL.Vec(UserSubmission)],
         ['query'],
       ),
+    'get_submissions_in_bbox' : IDL.Func(
+        [IDL.Float64, IDL.Float64, IDL.Float64, IDL.Float64],
+        [IDL.Variant({ 'Ok' : IDL.Vec(UserSubmission), 'Err' : DaoError })],
+        ['query'],
+      ),
+    'get_submissions_near' : IDL.Func(
+        [IDL.Float64, IDL.Float64, IDL.Float64, IDL.Opt(TimeRange)],
+        [IDL.Variant({ 'Ok' : IDL.Vec(UserSubmission), 'Err' : DaoError })],
+        ['query'],
+      ),
+    'get_submissions_page' : IDL.Func(
+        [IDL.Opt(IDL.Nat64), IDL.Nat32],
+        [SubmissionPage],
+        ['query'],
+      ),
     'get_tg_user' : IDL.Func(
         [IDL.Text],
         [
@@ -238,11 +737,12 @@ export const idlFactory = ({ IDL }) => {
               'first_name' : IDL.Opt(IDL.Text),
               'last_name' : IDL.Opt(IDL.Text),
             }),
-            'Err' : IDL.Text,
+            'Err' : DaoError,
           }),
         ],
         [],
       ),
+    'get_trusted_relayers' : IDL.Func([], [IDL.Vec(IDL.Principal)], ['query']),
     'get_user_posts' : IDL.Func(
         [IDL.Text],
         [
@@ -268,7 +768,7 @@ export const idlFactory = ({ IDL }) => {
       ),
     'get_user_role' : IDL.Func(
         [IDL.Text],
-        [IDL.Variant({ 'Ok' : Role, 'Err' : IDL.Text })],
+        [IDL.Variant({ 'Ok' : Role, 'Err' : DaoError })],
         [],
       ),
     'get_user_submission_locations' : IDL.Func(
@@ -276,12 +776,7 @@ export const idlFactory = ({ IDL }) => {
         [
           IDL.Vec(
             IDL.Record({
-              'status' : IDL.Variant({
-                'OPEN' : IDL.Null,
-                'PAID' : IDL.Null,
-                'EXPIRED' : IDL.Null,
-                'PENDING' : IDL.Null,
-              }),
+              'status' : PostStatus,
               'latitude' : IDL.Float64,
               'data_id' : IDL.Nat64,
               'longitude' : IDL.Float64,
@@ -300,6 +795,12 @@ export const idlFactory = ({ IDL }) => {
         [IDL.Vec(UserSubmission)],
         ['query'],
       ),
+    'get_users_page' : IDL.Func(
+        [IDL.Opt(IDL.Text), IDL.Nat32],
+        [UserPage],
+        ['query'],
+      ),
+    'get_validation_limits' : IDL.Func([], [ValidationLimits], ['query']),
     'get_vote_summary' : IDL.Func(
         [IDL.Nat64],
         [
@@ -314,12 +815,77 @@ export const idlFactory = ({ IDL }) => {
     'get_votes_by_user' : IDL.Func([IDL.Text], [IDL.Vec(Vote)], ['query']),
     'mark_submission_rewarded' : IDL.Func(
         [IDL.Nat64],
-        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : IDL.Text })],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'publish_challenge' : IDL.Func(
+        [IDL.Nat64],
+        [IDL.Variant({ 'Ok' : Challenge, 'Err' : DaoError })],
+        [],
+      ),
+    'refund_challenge_escrow' : IDL.Func(
+        [IDL.Nat64],
+        [IDL.Variant({ 'Ok' : ChallengeEscrow, 'Err' : DaoError })],
+        [],
+      ),
+    'remove_trusted_relayer' : IDL.Func(
+        [IDL.Principal],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'resolve_payout' : IDL.Func(
+        [IDL.Nat64, IDL.Opt(IDL.Nat64)],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
         [],
       ),
     'reward_user' : IDL.Func(
         [IDL.Nat64],
-        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : IDL.Text })],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'run_finalizer_now' : IDL.Func(
+        [],
+        [IDL.Variant({ 'Ok' : FinalizerReport, 'Err' : DaoError })],
+        [],
+      ),
+    'run_payouts_now' : IDL.Func(
+        [],
+        [IDL.Variant({ 'Ok' : PayoutRunReport, 'Err' : DaoError })],
+        [],
+      ),
+    'run_reconciliation' : IDL.Func(
+        [IDL.Opt(IDL.Nat64)],
+        [IDL.Variant({ 'Ok' : ReconciliationProgress, 'Err' : DaoError })],
+        [],
+      ),
+    'set_finalizer_settings' : IDL.Func(
+        [FinalizerSettings],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'set_payout_run_settings' : IDL.Func(
+        [PayoutRunSettings],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'set_rate_limits' : IDL.Func(
+        [RateLimits],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'set_telegram_bot_token' : IDL.Func(
+        [IDL.Text],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'set_validation_limits' : IDL.Func(
+        [ValidationLimits],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'settle_challenge' : IDL.Func(
+        [IDL.Nat64],
+        [IDL.Variant({ 'Ok' : Challenge, 'Err' : DaoError })],
         [],
       ),
     'submit_weather_data' : IDL.Func(
@@ -332,7 +898,7 @@ export const idlFactory = ({ IDL }) => {
           IDL.Text,
           IDL.Text,
         ],
-        [IDL.Nat64],
+        [IDL.Variant({ 'Ok' : IDL.Nat64, 'Err' : DaoError })],
         [],
       ),
     'submit_weather_data_with_challenge' : IDL.Func(
@@ -346,17 +912,58 @@ export const idlFactory = ({ IDL }) => {
           IDL.Text,
           IDL.Nat64,
         ],
-        [IDL.Variant({ 'Ok' : IDL.Nat64, 'Err' : IDL.Text })],
+        [IDL.Variant({ 'Ok' : IDL.Nat64, 'Err' : DaoError })],
+        [],
+      ),
+    'unbind_principal' : IDL.Func(
+        [IDL.Principal],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'update_challenge' : IDL.Func(
+        [IDL.Nat64, ChallengeUpdate],
+        [IDL.Variant({ 'Ok' : Challenge, 'Err' : DaoError })],
+        [],
+      ),
+    'update_config' : IDL.Func(
+        [DaoConfig],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
         [],
       ),
     'update_user_role' : IDL.Func(
         [IDL.Text, IDL.Text, Role],
-        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : IDL.Text })],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'update_vote' : IDL.Func(
+        [IDL.Text, IDL.Nat64, IDL.Bool],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
         [],
       ),
-    'update_vote' : IDL.Func([IDL.Text, IDL.Nat64, IDL.Bool], [IDL.Text], []),
-    'update_wallet_address' : IDL.Func([IDL.Text, IDL.Text], [IDL.Text], []),
-    'vote_on_data' : IDL.Func([IDL.Text, IDL.Nat64, IDL.Bool], [IDL.Text], []),
+    'update_wallet_address' : IDL.Func(
+        [IDL.Text, IDL.Text],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+    'vote_on_data' : IDL.Func(
+        [IDL.Text, IDL.Nat64, IDL.Bool],
+        [IDL.Variant({ 'Ok' : IDL.Text, 'Err' : DaoError })],
+        [],
+      ),
+  });
+};
+export const init = ({ IDL }) => {
+  const DaoConfig = IDL.Record({
+    'voting_window_secs' : IDL.Nat64,
+    'approval_threshold_bps' : IDL.Nat32,
+    'fee_included_in_reward' : IDL.Bool,
+    'transfer_fee' : IDL.Opt(IDL.Nat64),
+    'challenge_voting_window_secs' : IDL.Nat64,
+    'reward_amount' : IDL.Nat64,
+    'min_approvals' : IDL.Nat32,
+    'ledger_canister_id' : IDL.Principal,
+    'quorum' : IDL.Nat32,
+    'voter_reward_bps' : IDL.Nat32,
   });
+  return [IDL.Opt(DaoConfig)];
 };
-export const init = ({ IDL }) => { return []; };
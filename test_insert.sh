@@ -1,5 +1,8 @@
 #!/bin/bash
 
+# Let the current dfx identity act on behalf of the test users
+dfx canister call dao_backend add_trusted_relayer "(principal \"$(dfx identity get-principal)\")"
+
 # Create 10 test users
 dfx canister call dao_backend create_tg_user '("user1_id", "Alice", "Smith", "alice_smith", "en", false, "https://profile_picture_url")'
 dfx canister call dao_backend create_tg_user '("user2_id", "Bob", "Johnson", "bobby_j", "en", false, "https://profile_picture_url")'